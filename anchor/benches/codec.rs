@@ -0,0 +1,73 @@
+//! Benchmarks for the protocol hot paths
+//!
+//! Run with `cargo bench -p anchor`.
+
+use anchor::bench::crc16;
+use anchor::encoding::{Readable, Writable};
+use anchor::{FifoBuffer, ScratchOutput};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// Values covering each VLQ encoded length, from one to five bytes
+const VLQ_VALUES: [u32; 5] = [5, 1000, 100_000, 10_000_000, 0x7FFF_FFFF];
+
+fn vlq(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vlq");
+    for v in VLQ_VALUES {
+        let mut encoded = ScratchOutput::<8>::new();
+        v.write(&mut encoded);
+        let encoded = encoded.result().to_vec();
+
+        group.bench_with_input(BenchmarkId::new("encode", encoded.len()), &v, |b, v| {
+            let mut out = ScratchOutput::<8>::new();
+            b.iter(|| {
+                out.reset();
+                black_box(v).write(&mut out);
+            })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("parse", encoded.len()),
+            &encoded[..],
+            |b, encoded| {
+                b.iter(|| {
+                    let mut data = black_box(encoded);
+                    <u32 as Readable>::read(&mut data).ok()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn crc(c: &mut Criterion) {
+    let mut group = c.benchmark_group("crc16");
+    // An ACK, a typical command block, and a full frame without the trailer
+    for len in [2usize, 16, 61] {
+        let data: Vec<u8> = (0..len as u8).collect();
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &data[..], |b, data| {
+            b.iter(|| crc16(black_box(data)))
+        });
+    }
+    group.finish();
+}
+
+fn fifo_pop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fifo_pop");
+    // Popping one frame at a time from a full buffer
+    for len in [5usize, 64] {
+        let data = [0x7Eu8; 256];
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, len| {
+            let mut fifo = FifoBuffer::<256>::new();
+            b.iter(|| {
+                if fifo.len() < *len {
+                    fifo.extend(&data[..256 - fifo.len()]);
+                }
+                fifo.pop(black_box(*len));
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, vlq, crc, fifo_pop);
+criterion_main!(benches);
@@ -0,0 +1,191 @@
+use crate::fifo_buffer::FifoBuffer;
+use crate::output_buffer::{FrameRingOutput, ScratchOutput};
+use crate::transport_output::TransportOutput;
+
+/// Trait abstracting a blocking mutex
+///
+/// This allows [`BufferedTransportOutput`] to be used with whatever mutex is appropriate for the
+/// platform. Implementations are provided for `std::sync::Mutex` with the `std` feature, and for
+/// `critical_section::Mutex<RefCell<T>>` with the `critical-section` feature.
+pub trait BufferMutex<T> {
+    /// Lock the mutex and call `f` with the protected data
+    fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+}
+
+#[cfg(feature = "std")]
+impl<T> BufferMutex<T> for std::sync::Mutex<T> {
+    fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut std::sync::Mutex::lock(self).unwrap())
+    }
+}
+
+#[cfg(feature = "critical-section")]
+impl<T> BufferMutex<T> for critical_section::Mutex<core::cell::RefCell<T>> {
+    fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.borrow_ref_mut(cs)))
+    }
+}
+
+/// A `TransportOutput` writing to a mutex protected `FifoBuffer`
+///
+/// Messages are serialized to a `ScratchOutput` and then appended to the buffer with the mutex
+/// held. The buffer must be emptied regularly, usually from the main loop, by using `drain` or
+/// `with_buffer`. Messages that don't fit in the buffer are dropped.
+///
+/// This is intended to be placed in a `static` and passed as the `transport` option to
+/// `klipper_config_generate`:
+/// ```ignore
+/// pub type UsbOutput = BufferedTransportOutput<Mutex<RefCell<FifoBuffer<128>>>, 128>;
+/// pub static TRANSPORT_OUTPUT: UsbOutput =
+///     BufferedTransportOutput::new(Mutex::new(RefCell::new(FifoBuffer::new())));
+/// ```
+pub struct BufferedTransportOutput<M, const BUF_SIZE: usize> {
+    buffer: M,
+}
+
+impl<M, const BUF_SIZE: usize> BufferedTransportOutput<M, BUF_SIZE> {
+    /// Creates a new output from a mutex holding the buffer
+    pub const fn new(buffer: M) -> Self {
+        BufferedTransportOutput { buffer }
+    }
+}
+
+impl<M: BufferMutex<FifoBuffer<BUF_SIZE>>, const BUF_SIZE: usize>
+    BufferedTransportOutput<M, BUF_SIZE>
+{
+    /// Lock the buffer and call `f` with it
+    pub fn with_buffer<R>(&self, f: impl FnOnce(&mut FifoBuffer<BUF_SIZE>) -> R) -> R {
+        self.buffer.lock(f)
+    }
+
+    /// Pass the buffered data to `f`, removing the number of bytes it returns from the buffer
+    pub fn drain(&self, f: impl FnOnce(&[u8]) -> usize) {
+        self.buffer.lock(|buffer| {
+            if !buffer.is_empty() {
+                let n = f(buffer.data());
+                buffer.pop(n);
+            }
+        })
+    }
+}
+
+impl<M: BufferMutex<FifoBuffer<BUF_SIZE>>, const BUF_SIZE: usize> TransportOutput
+    for BufferedTransportOutput<M, BUF_SIZE>
+{
+    type Output = ScratchOutput;
+    fn output(&self, f: impl FnOnce(&mut Self::Output)) {
+        let mut scratch = ScratchOutput::new();
+        f(&mut scratch);
+        let output = scratch.result();
+        self.buffer.lock(|buffer| buffer.extend(output));
+    }
+}
+
+/// A `TransportOutput` writing frames in place to a mutex protected `FrameRingOutput`
+///
+/// Unlike [`BufferedTransportOutput`], frames are serialized directly into the buffer with the
+/// mutex held, avoiding the copy from a scratch buffer. Each frame is committed once complete,
+/// so `drain` and `with_buffer` only ever see whole frames. Frames that don't fit in the buffer
+/// are dropped.
+/// ```ignore
+/// pub type UsbOutput = FrameRingTransportOutput<Mutex<RefCell<FrameRingOutput<128>>>, 128>;
+/// pub static TRANSPORT_OUTPUT: UsbOutput =
+///     FrameRingTransportOutput::new(Mutex::new(RefCell::new(FrameRingOutput::new())));
+/// ```
+pub struct FrameRingTransportOutput<M, const BUF_SIZE: usize> {
+    buffer: M,
+}
+
+impl<M, const BUF_SIZE: usize> FrameRingTransportOutput<M, BUF_SIZE> {
+    /// Creates a new output from a mutex holding the buffer
+    pub const fn new(buffer: M) -> Self {
+        FrameRingTransportOutput { buffer }
+    }
+}
+
+impl<M: BufferMutex<FrameRingOutput<BUF_SIZE>>, const BUF_SIZE: usize>
+    FrameRingTransportOutput<M, BUF_SIZE>
+{
+    /// Lock the buffer and call `f` with it
+    pub fn with_buffer<R>(&self, f: impl FnOnce(&mut FrameRingOutput<BUF_SIZE>) -> R) -> R {
+        self.buffer.lock(f)
+    }
+
+    /// Pass the buffered data to `f`, removing the number of bytes it returns from the buffer
+    pub fn drain(&self, f: impl FnOnce(&[u8]) -> usize) {
+        self.buffer.lock(|buffer| {
+            if !buffer.is_empty() {
+                let n = f(buffer.data());
+                buffer.pop(n);
+            }
+        })
+    }
+}
+
+impl<M: BufferMutex<FrameRingOutput<BUF_SIZE>>, const BUF_SIZE: usize> TransportOutput
+    for FrameRingTransportOutput<M, BUF_SIZE>
+{
+    type Output = FrameRingOutput<BUF_SIZE>;
+    fn output(&self, f: impl FnOnce(&mut Self::Output)) {
+        self.buffer.lock(|buffer| {
+            buffer.discard();
+            f(buffer);
+            buffer.commit();
+        })
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::encoding::ReadError;
+    use crate::output_buffer::OutputBuffer;
+    use crate::transport::{Config, Transport};
+    use std::sync::Mutex;
+
+    type Output = BufferedTransportOutput<Mutex<FifoBuffer<1024>>, 1024>;
+
+    static OUTPUT: Output = BufferedTransportOutput::new(Mutex::new(FifoBuffer::new()));
+
+    struct SendOnly;
+
+    impl Config for SendOnly {
+        type TransportOutput = &'static Output;
+        type Context<'c> = ();
+
+        fn dispatch<'c>(_cmd: u16, _frame: &mut &[u8], _context: &mut ()) -> Result<(), ReadError> {
+            Err(ReadError::UnknownCommand)
+        }
+    }
+
+    #[test]
+    fn std_mutex_output_keeps_frames_whole() {
+        let transport = Transport::<SendOnly>::with_output(&OUTPUT);
+        std::thread::scope(|s| {
+            for thread in 0..4u8 {
+                let transport = &transport;
+                s.spawn(move || {
+                    for i in 0..10u8 {
+                        let payload = [thread, i, 0x7e, 0x10];
+                        transport.encode_frame(|out| out.output(&payload)).unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut payloads = std::vec::Vec::new();
+        OUTPUT.with_buffer(|buffer| {
+            assert_eq!(buffer.len(), 40 * 9);
+            buffer.drain_frames(|frame| {
+                assert_eq!(frame[..2], [9, 0x10]);
+                payloads.push([frame[2], frame[3], frame[4], frame[5]]);
+            });
+            assert!(buffer.is_empty());
+        });
+        payloads.sort();
+        let expected: std::vec::Vec<_> = (0..4u8)
+            .flat_map(|t| (0..10u8).map(move |i| [t, i, 0x7e, 0x10]))
+            .collect();
+        assert_eq!(payloads, expected);
+    }
+}
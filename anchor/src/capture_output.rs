@@ -0,0 +1,178 @@
+use crate::output_buffer::{OutputBuffer, ScratchOutput};
+use crate::transport_output::TransportOutput;
+use std::{
+    sync::{Mutex, PoisonError},
+    thread::{self, ThreadId},
+    vec::Vec,
+};
+
+/// A `TransportOutput` wrapper that can capture the frames sent by a piece of code
+///
+/// Frames are passed to the inner output, except while `capture` runs, which collects the frames
+/// sent from its thread instead. This allows testing a command's request and reply in a single
+/// call, without a host on the other end:
+/// ```ignore
+/// klipper_config_generate!(transport = crate::TRANSPORT_OUTPUT: ::anchor::CaptureOutput<crate::UartOutput>);
+///
+/// pub(crate) static TRANSPORT_OUTPUT: CaptureOutput<UartOutput> = CaptureOutput::new(UartOutput);
+///
+/// #[test]
+/// fn replies_to_get_clock() {
+///     let (result, frames) =
+///         TRANSPORT_OUTPUT.capture(|| _anchor_config::invoke_command("get_clock", &[], &mut ()));
+///     assert_eq!(result, Ok(()));
+///     assert_eq!(frames.len(), 1);
+/// }
+/// ```
+/// Frames sent from other threads, e.g. a concurrently running main loop, still go to the inner
+/// output. Tests running in parallel can each capture at the same time, every capture only
+/// collects the frames of its own thread. Captures can't be nested.
+pub struct CaptureOutput<T> {
+    inner: T,
+    captures: Captures,
+}
+
+/// The frames collected by the running captures, by capturing thread
+type Captures = Mutex<Vec<(ThreadId, Vec<Vec<u8>>)>>;
+
+impl<T> CaptureOutput<T> {
+    /// Wraps `inner`, passing it all frames until a capture is started
+    pub const fn new(inner: T) -> Self {
+        CaptureOutput {
+            inner,
+            captures: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the wrapped output
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Runs `f`, returning its result and the complete frames it sent, in order
+    ///
+    /// If `f` panics, the capture ends and the frames sent so far are dropped.
+    pub fn capture<R>(&self, f: impl FnOnce() -> R) -> (R, Vec<Vec<u8>>) {
+        let thread = thread::current().id();
+        {
+            let mut captures = self.captures.lock().unwrap();
+            if captures.iter().any(|(t, _)| *t == thread) {
+                panic!("Captures can't be nested");
+            }
+            captures.push((thread, Vec::new()));
+        }
+        let guard = CaptureGuard {
+            captures: &self.captures,
+            thread,
+        };
+        let result = f();
+        (result, guard.take())
+    }
+}
+
+/// Ends a capture when dropped, even if the captured code panicked
+struct CaptureGuard<'a> {
+    captures: &'a Captures,
+    thread: ThreadId,
+}
+
+impl CaptureGuard<'_> {
+    fn take(&self) -> Vec<Vec<u8>> {
+        let mut captures = self.captures.lock().unwrap_or_else(PoisonError::into_inner);
+        match captures.iter().position(|(t, _)| *t == self.thread) {
+            Some(idx) => captures.swap_remove(idx).1,
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for CaptureGuard<'_> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+impl<T: TransportOutput> TransportOutput for CaptureOutput<T> {
+    type Output = ScratchOutput;
+    fn output(&self, f: impl FnOnce(&mut Self::Output)) {
+        let mut scratch = ScratchOutput::new();
+        f(&mut scratch);
+        let frame = scratch.result();
+        if frame.is_empty() {
+            return;
+        }
+        let thread = thread::current().id();
+        let mut captures = self.captures.lock().unwrap();
+        if let Some((_, frames)) = captures.iter_mut().find(|(t, _)| *t == thread) {
+            frames.push(frame.to_vec());
+            return;
+        }
+        drop(captures);
+        self.inner.output(|out| out.output(frame));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::{panic, sync::Barrier, vec};
+
+    std::thread_local! {
+        static SENT: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Collects the frames passed on to it, per thread
+    struct ThreadOutput;
+
+    impl TransportOutput for ThreadOutput {
+        type Output = ScratchOutput;
+        fn output(&self, f: impl FnOnce(&mut Self::Output)) {
+            let mut scratch = ScratchOutput::new();
+            f(&mut scratch);
+            SENT.with(|sent| sent.borrow_mut().push(scratch.result().to_vec()));
+        }
+    }
+
+    fn send(output: &CaptureOutput<ThreadOutput>, frame: &[u8]) {
+        output.output(|out| out.output(frame));
+    }
+
+    #[test]
+    fn concurrent_captures_keep_their_frames() {
+        let output = CaptureOutput::new(ThreadOutput);
+        let barrier = Barrier::new(2);
+        thread::scope(|s| {
+            for id in [1u8, 2] {
+                let (output, barrier) = (&output, &barrier);
+                s.spawn(move || {
+                    let ((), frames) = output.capture(|| {
+                        send(output, &[id, 0]);
+                        // Both captures are running now
+                        barrier.wait();
+                        send(output, &[id, 1]);
+                    });
+                    assert_eq!(frames, vec![vec![id, 0], vec![id, 1]]);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn panic_ends_capture() {
+        let output = CaptureOutput::new(ThreadOutput);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            output.capture(|| {
+                send(&output, &[1]);
+                panic!("handler failed");
+            })
+        }));
+        assert!(result.is_err());
+
+        // Frames go to the inner output again, and a new capture can start
+        send(&output, &[2]);
+        SENT.with(|sent| assert_eq!(*sent.borrow(), vec![vec![2]]));
+        let ((), frames) = output.capture(|| send(&output, &[3]));
+        assert_eq!(frames, vec![vec![3]]);
+    }
+}
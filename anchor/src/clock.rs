@@ -0,0 +1,243 @@
+/// A 64 bit clock value
+///
+/// Klipper transfers 64 bit clocks as two 32 bit halves, e.g. in the `uptime` reply. This type
+/// provides the correctly split halves:
+/// ```ignore
+/// let c = Clock64::from(full_clock);
+/// klipper_reply!(uptime, high: u32 = c.high(), clock: u32 = c.low());
+/// ```
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Clock64(pub u64);
+
+impl Clock64 {
+    /// Creates a clock value from its two 32 bit halves
+    pub const fn from_parts(high: u32, low: u32) -> Self {
+        Clock64((high as u64) << 32 | low as u64)
+    }
+
+    /// Returns the upper 32 bits of the clock
+    pub const fn high(&self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    /// Returns the lower 32 bits of the clock
+    pub const fn low(&self) -> u32 {
+        (self.0 & 0xFFFF_FFFF) as u32
+    }
+}
+
+impl From<u64> for Clock64 {
+    fn from(c: u64) -> Self {
+        Clock64(c)
+    }
+}
+
+impl From<Clock64> for u64 {
+    fn from(c: Clock64) -> Self {
+        c.0
+    }
+}
+
+/// A 32 bit clock value, e.g. the low half of a free running timer
+///
+/// Klipper schedules with 32 bit clocks, which wrap around. Two instants are compared by the
+/// signed difference of their values, so the comparisons are correct as long as the instants are
+/// less than 2^31 ticks apart, whether or not the counter wrapped between them:
+/// ```ignore
+/// let deadline = clock.low() + REPORT_TICKS;
+/// ...
+/// if clock.low().after(deadline) {
+///     report(clock.low().elapsed_since(deadline));
+/// }
+/// ```
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct InstantShort(pub u32);
+
+impl InstantShort {
+    /// Creates an instant from a raw clock value
+    pub const fn new(t: u32) -> InstantShort {
+        InstantShort(t)
+    }
+
+    /// Returns true if this instant is later than `other`
+    pub fn after(&self, other: impl AsRef<Self>) -> bool {
+        other.as_ref().0.wrapping_sub(self.0) & 0x8000_0000 != 0
+    }
+
+    /// Returns true if this instant is earlier than `other`
+    pub fn before(&self, other: impl AsRef<Self>) -> bool {
+        other.as_ref().after(self)
+    }
+
+    /// Returns the number of ticks from `earlier` to this instant
+    ///
+    /// The result wraps if `earlier` is actually later, use `saturating_duration_since` to get 0
+    /// instead.
+    pub fn elapsed_since(&self, earlier: impl AsRef<Self>) -> u32 {
+        self.0.wrapping_sub(earlier.as_ref().0)
+    }
+
+    /// Returns the number of ticks from `earlier` to this instant, or 0 if `earlier` is later
+    pub fn saturating_duration_since(&self, earlier: impl AsRef<Self>) -> u32 {
+        let earlier = earlier.as_ref();
+        if self.before(earlier) {
+            0
+        } else {
+            self.elapsed_since(earlier)
+        }
+    }
+}
+
+impl core::ops::AddAssign<u32> for InstantShort {
+    fn add_assign(&mut self, rhs: u32) {
+        self.0 = self.0.wrapping_add(rhs);
+    }
+}
+
+impl core::ops::Add<u32> for InstantShort {
+    type Output = Self;
+    fn add(self, rhs: u32) -> Self::Output {
+        InstantShort(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AsRef<InstantShort> for InstantShort {
+    fn as_ref(&self) -> &InstantShort {
+        self
+    }
+}
+
+impl From<u32> for InstantShort {
+    fn from(t: u32) -> Self {
+        InstantShort(t)
+    }
+}
+
+impl From<InstantShort> for u32 {
+    fn from(t: InstantShort) -> Self {
+        t.0
+    }
+}
+
+/// A clock frequency, for converting durations to clock ticks
+///
+/// The conversions use 64 bit intermediates and return `None` if the tick count doesn't fit in
+/// 32 bits, so they can't silently wrap. All conversions are `const`:
+/// ```ignore
+/// const CLOCK: ClockFreq = ClockFreq(CLOCK_FREQ);
+/// const REPORT_TICKS: u32 = match CLOCK.ticks_from_ms(500) {
+///     Some(t) => t,
+///     None => panic!("report interval too long"),
+/// };
+/// ```
+/// Partial ticks are truncated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClockFreq(pub u32);
+
+impl ClockFreq {
+    /// Converts microseconds to clock ticks
+    pub const fn ticks_from_us(&self, us: u32) -> Option<u32> {
+        Self::narrow(us as u64 * self.0 as u64 / 1_000_000)
+    }
+
+    /// Converts milliseconds to clock ticks
+    pub const fn ticks_from_ms(&self, ms: u32) -> Option<u32> {
+        Self::narrow(ms as u64 * self.0 as u64 / 1_000)
+    }
+
+    const fn narrow(ticks: u64) -> Option<u32> {
+        if ticks > u32::MAX as u64 {
+            None
+        } else {
+            Some(ticks as u32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock64_halves_around_2_pow_32() {
+        let cases = [
+            (0xFFFF_FFFF, 0, 0xFFFF_FFFF),
+            (0x1_0000_0000, 1, 0),
+            (0x1_0000_0001, 1, 1),
+            (0x1_FFFF_FFFF, 1, 0xFFFF_FFFF),
+            (0x2_0000_0000, 2, 0),
+            (u64::MAX, u32::MAX, u32::MAX),
+        ];
+        for (clock, high, low) in cases {
+            let c = Clock64::from(clock);
+            assert_eq!((c.high(), c.low()), (high, low), "{:#x}", clock);
+            assert_eq!(Clock64::from_parts(high, low), c);
+            assert_eq!(u64::from(c), clock);
+        }
+    }
+
+    #[test]
+    fn clock64_orders_across_the_low_wrap() {
+        let before = Clock64::from_parts(0, 0xFFFF_FFFF);
+        let after = Clock64::from_parts(1, 0);
+        assert!(before < after);
+        assert_eq!(u64::from(after) - u64::from(before), 1);
+    }
+
+    #[test]
+    fn instant_short_across_the_wrap() {
+        let early = InstantShort(u32::MAX - 1);
+        let late = InstantShort(2);
+        assert_eq!(early + 4, late);
+
+        assert!(late.after(early));
+        assert!(!early.after(late));
+        assert!(early.before(late));
+        assert!(!late.before(early));
+        assert!(!late.before(late) && !late.after(late));
+
+        assert_eq!(late.elapsed_since(early), 4);
+        assert_eq!(early.elapsed_since(late), u32::MAX - 3);
+        assert_eq!(late.elapsed_since(late), 0);
+
+        assert_eq!(late.saturating_duration_since(early), 4);
+        assert_eq!(early.saturating_duration_since(late), 0);
+        assert_eq!(late.saturating_duration_since(late), 0);
+    }
+
+    #[test]
+    fn instant_short_at_half_range() {
+        // Instants 2^31 - 1 ticks apart still compare correctly across the wrap
+        let early = InstantShort(u32::MAX - 1);
+        let late = early + 0x7FFF_FFFF;
+        assert!(late.after(early) && early.before(late));
+        assert_eq!(late.saturating_duration_since(early), 0x7FFF_FFFF);
+        assert_eq!(early.saturating_duration_since(late), 0);
+    }
+
+    #[test]
+    fn clock_freq_near_u32_max_ticks() {
+        let freq = ClockFreq(2_000_000);
+        assert_eq!(freq.ticks_from_us(2_147_483_647), Some(u32::MAX - 1));
+        assert_eq!(freq.ticks_from_us(2_147_483_648), None);
+        assert_eq!(freq.ticks_from_ms(2_147_483), Some(4_294_966_000));
+        assert_eq!(freq.ticks_from_ms(2_147_484), None);
+
+        // A fast clock leaves little room, and large inputs don't wrap the intermediate
+        let freq = ClockFreq(400_000_000);
+        assert_eq!(freq.ticks_from_us(10_737_418), Some(4_294_967_200));
+        assert_eq!(freq.ticks_from_us(10_737_419), None);
+        assert_eq!(freq.ticks_from_ms(10_737), Some(4_294_800_000));
+        assert_eq!(freq.ticks_from_ms(10_738), None);
+        assert_eq!(freq.ticks_from_us(u32::MAX), None);
+        assert_eq!(freq.ticks_from_ms(u32::MAX), None);
+    }
+
+    #[test]
+    fn clock_freq_truncates_partial_ticks() {
+        let freq = ClockFreq(12_000_001);
+        assert_eq!(freq.ticks_from_us(1), Some(12));
+        assert_eq!(freq.ticks_from_ms(1), Some(12_000));
+        assert_eq!(ClockFreq(999_999).ticks_from_us(1), Some(0));
+    }
+}
@@ -0,0 +1,204 @@
+/// Phase of the configuration handshake with the host
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConfigPhase {
+    /// No configuration has been received, `allocate_oids` is expected
+    Unconfigured,
+    /// Oids have been allocated, `config_*` commands and `finalize_config` are expected
+    Configuring,
+    /// `finalize_config` has been received
+    Configured,
+    /// The MCU is shut down, only `config_reset` leaves this phase
+    Shutdown,
+}
+
+/// Error returned for a handshake command that is not allowed in the current phase
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConfigPhaseError {
+    /// The phase the command was received in
+    pub phase: ConfigPhase,
+}
+
+/// Tracks the configuration handshake with the host
+///
+/// Klippy configures the MCU by sending `allocate_oids`, followed by the `config_*` commands of
+/// the MCU objects, and finally `finalize_config` with a CRC of the configuration. The handlers
+/// of these commands call the matching methods, which reject commands received out of order:
+/// ```ignore
+/// #[klipper_command]
+/// fn allocate_oids(context: &mut State, count: u8) -> Result<(), Shutdown> {
+///     context.config.allocate_oids().map_err(|_| {
+///         Shutdown::new(klipper_static_string!("oids already allocated"), context.clock())
+///     })?;
+///     ...
+/// }
+/// ```
+/// `get_config` reports `is_configured`, `crc` and `is_shutdown` to the host.
+///
+/// The state is independent of the `Transport`. Firmware using the transport level `phase` and
+/// `shutdown` command options should still call `set_configured` and `set_shutdown` on it.
+#[derive(Debug, Clone)]
+pub struct ConfigState {
+    phase: ConfigPhase,
+    crc: Option<u32>,
+}
+
+impl ConfigState {
+    /// Creates a new, unconfigured state
+    pub const fn new() -> Self {
+        ConfigState {
+            phase: ConfigPhase::Unconfigured,
+            crc: None,
+        }
+    }
+
+    /// Returns the current phase
+    pub fn phase(&self) -> ConfigPhase {
+        self.phase
+    }
+
+    /// Starts the configuration, for `allocate_oids`
+    ///
+    /// Only allowed while unconfigured. The oid count isn't tracked here. A count of zero, as
+    /// Klippy sends for a configuration without MCU objects, is valid and starts the
+    /// configuration like any other count. Handlers sizing storage from the count must accept it.
+    pub fn allocate_oids(&mut self) -> Result<(), ConfigPhaseError> {
+        self.expect(ConfigPhase::Unconfigured)?;
+        self.phase = ConfigPhase::Configuring;
+        Ok(())
+    }
+
+    /// Checks that a `config_*` command may be processed
+    ///
+    /// Only allowed between `allocate_oids` and `finalize_config`.
+    pub fn config_command(&self) -> Result<(), ConfigPhaseError> {
+        self.expect(ConfigPhase::Configuring)
+    }
+
+    /// Finishes the configuration, for `finalize_config`
+    ///
+    /// Only allowed after `allocate_oids`, and only once.
+    pub fn finalize_config(&mut self, crc: u32) -> Result<(), ConfigPhaseError> {
+        self.expect(ConfigPhase::Configuring)?;
+        self.phase = ConfigPhase::Configured;
+        self.crc = Some(crc);
+        Ok(())
+    }
+
+    /// Enters the shutdown phase
+    ///
+    /// Always allowed. The configuration is kept, and still reported by `is_configured` and
+    /// `crc`, as Klippy expects.
+    pub fn shutdown(&mut self) {
+        self.phase = ConfigPhase::Shutdown;
+    }
+
+    /// Clears the configuration, for `config_reset`
+    ///
+    /// Only allowed while shut down, as in Klipper.
+    pub fn config_reset(&mut self) -> Result<(), ConfigPhaseError> {
+        self.expect(ConfigPhase::Shutdown)?;
+        *self = Self::new();
+        Ok(())
+    }
+
+    /// Returns true if `finalize_config` has been received
+    pub fn is_configured(&self) -> bool {
+        self.crc.is_some()
+    }
+
+    /// Returns the CRC given with `finalize_config`
+    pub fn crc(&self) -> Option<u32> {
+        self.crc
+    }
+
+    /// Returns true if in the shutdown phase
+    pub fn is_shutdown(&self) -> bool {
+        self.phase == ConfigPhase::Shutdown
+    }
+
+    fn expect(&self, phase: ConfigPhase) -> Result<(), ConfigPhaseError> {
+        if self.phase == phase {
+            Ok(())
+        } else {
+            Err(ConfigPhaseError { phase: self.phase })
+        }
+    }
+}
+
+impl Default for ConfigState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ConfigPhase::*;
+
+    fn err(phase: ConfigPhase) -> Result<(), ConfigPhaseError> {
+        Err(ConfigPhaseError { phase })
+    }
+
+    #[test]
+    fn legal_handshake_and_reset() {
+        let mut state = ConfigState::new();
+        assert_eq!(state.phase(), Unconfigured);
+        assert_eq!(state.allocate_oids(), Ok(()));
+        assert_eq!(state.phase(), Configuring);
+        assert_eq!(state.config_command(), Ok(()));
+        assert!(!state.is_configured());
+        assert_eq!(state.finalize_config(0x1234), Ok(()));
+        assert_eq!(state.phase(), Configured);
+        assert_eq!(state.crc(), Some(0x1234));
+
+        state.shutdown();
+        assert!(state.is_shutdown());
+        // The configuration is still reported while shut down
+        assert!(state.is_configured());
+        assert_eq!(state.crc(), Some(0x1234));
+
+        assert_eq!(state.config_reset(), Ok(()));
+        assert_eq!(state.phase(), Unconfigured);
+        assert_eq!(state.crc(), None);
+        assert_eq!(state.allocate_oids(), Ok(()));
+    }
+
+    #[test]
+    fn illegal_transitions() {
+        let mut state = ConfigState::new();
+        // Nothing but `allocate_oids` while unconfigured
+        assert_eq!(state.config_command(), err(Unconfigured));
+        assert_eq!(state.finalize_config(1), err(Unconfigured));
+        assert_eq!(state.config_reset(), err(Unconfigured));
+
+        state.allocate_oids().unwrap();
+        assert_eq!(state.allocate_oids(), err(Configuring));
+        assert_eq!(state.config_reset(), err(Configuring));
+
+        state.finalize_config(1).unwrap();
+        assert_eq!(state.allocate_oids(), err(Configured));
+        assert_eq!(state.config_command(), err(Configured));
+        assert_eq!(state.finalize_config(2), err(Configured));
+        assert_eq!(state.config_reset(), err(Configured));
+        assert_eq!(state.crc(), Some(1));
+
+        state.shutdown();
+        assert_eq!(state.allocate_oids(), err(Shutdown));
+        assert_eq!(state.config_command(), err(Shutdown));
+        assert_eq!(state.finalize_config(2), err(Shutdown));
+        // Failed commands leave the phase alone
+        assert_eq!(state.phase(), Shutdown);
+    }
+
+    #[test]
+    fn shutdown_during_configuration() {
+        let mut state = ConfigState::new();
+        state.allocate_oids().unwrap();
+        state.shutdown();
+        assert!(!state.is_configured());
+        assert_eq!(state.finalize_config(1), err(Shutdown));
+        assert_eq!(state.config_reset(), Ok(()));
+        assert_eq!(state.phase(), Unconfigured);
+    }
+}
@@ -0,0 +1,21 @@
+/// Feeds a single byte through the Klipper CRC16
+pub(crate) fn crc16_step(crc: u16, b: u8) -> u16 {
+    let b = b ^ ((crc & 0xFF) as u8);
+    let b = b ^ (b << 4);
+    let b16 = b as u16;
+    (b16 << 8 | crc >> 8) ^ (b16 >> 4) ^ (b16 << 3)
+}
+
+/// Calculates the Klipper CRC16 over a buffer
+pub fn crc16(buf: &[u8]) -> u16 {
+    buf.iter().fold(0xFFFF, |crc, b| crc16_step(crc, *b))
+}
+
+/// Calculates the change in the final CRC when a byte followed by `trailing` bytes is XORed with
+/// `diff`
+///
+/// The CRC step is linear, so the effect of changing a byte can be propagated through the rest of
+/// the message without knowing its contents.
+pub(crate) fn crc16_delta(diff: u8, trailing: usize) -> u16 {
+    (0..trailing).fold(crc16_step(0, diff), |crc, _| crc16_step(crc, 0))
+}
@@ -1,12 +1,39 @@
 use crate::output_buffer::OutputBuffer;
 
 /// Error type for representing a failed read
-pub struct ReadError;
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadError {
+    /// The data ended before the value was complete
+    Truncated,
+    /// The data does not hold a valid value of the type
+    Invalid,
+    /// A value outside the variants of a `klipper_enumeration!`
+    InvalidEnumValue {
+        /// The dictionary name of the enumeration
+        name: &'static str,
+        /// The received value
+        value: u32,
+    },
+    /// The command id is unknown
+    UnknownCommand,
+    /// The command is known, but not allowed in the current state
+    Rejected,
+}
 
 /// Trait implemented for types that can be read from an input message
 ///
 /// The `'de` lifetime allows the implementation to return references to the original data buffer.
 /// This permits zero-copy reading of variable length data like byte arrays.
+///
+/// Commands in a frame are not delimited, so every argument must consume exactly the bytes of its
+/// encoding, and no argument type takes the rest of the frame. A trailing blob is sent as a
+/// length prefixed byte array, which leaves any following commands intact. Command arguments of a
+/// custom `Readable` type are checked against the encoding of the type they map to, and reading
+/// fails if they consume more or less.
+///
+/// Only commands declared with `#[klipper_command(raw)]` take the rest of the frame, bypassing
+/// `Readable` entirely. Their handler receives everything after the command id undecoded,
+/// including any commands following it in the frame, so a raw command must be sent last.
 pub trait Readable<'de>: Sized {
     /// Attempt to read a `Self` from the input buffer, advancing the buffer if successful.
     ///
@@ -17,7 +44,7 @@ pub trait Readable<'de>: Sized {
 
 pub(crate) fn next_byte(data: &mut &[u8]) -> Result<u8, ReadError> {
     if data.is_empty() {
-        Err(ReadError)
+        Err(ReadError::Truncated)
     } else {
         let v = data[0];
         *data = &data[1..];
@@ -88,6 +115,7 @@ int_readwrite!(u16);
 int_readwrite!(i16);
 int_readwrite!(u8);
 
+// Booleans are `%c` on the wire, like `u8`. Any non-zero value is true.
 impl Readable<'_> for bool {
     fn read(data: &mut &[u8]) -> Result<Self, ReadError> {
         parse_vlq_int(data).map(|v| v != 0)
@@ -100,11 +128,37 @@ impl Writable for bool {
     }
 }
 
+// Characters are `%c` on the wire, like `u8`, and only ASCII can be represented. `write` can't
+// fail, so any other character is sent as `?`. Reading a value above 0x7F is an error.
+impl Readable<'_> for char {
+    fn read(data: &mut &[u8]) -> Result<Self, ReadError> {
+        let mut peek = *data;
+        match parse_vlq_int(&mut peek)? {
+            v @ 0..=0x7F => {
+                *data = peek;
+                Ok(char::from(v as u8))
+            }
+            _ => Err(ReadError::Invalid),
+        }
+    }
+}
+
+impl Writable for char {
+    fn write(&self, output: &mut impl OutputBuffer) {
+        let c = if self.is_ascii() { *self as u8 } else { b'?' };
+        encode_vlq_int(output, u32::from(c))
+    }
+}
+
+// Byte arrays (`%*s`) are a VLQ encoded length followed by the raw bytes. Unlike integers, the
+// bytes themselves are not VLQ encoded, so values above 0x7F take a single byte each. Reading
+// returns exactly those bytes, borrowed from the frame. Typed slices like `&[u16]` are carried
+// the same way, see `SliceElement`.
 impl<'de> Readable<'de> for &'de [u8] {
     fn read(data: &mut &'de [u8]) -> Result<&'de [u8], ReadError> {
         let len = parse_vlq_int(data)? as usize;
         if data.len() < len {
-            Err(ReadError)
+            Err(ReadError::Truncated)
         } else {
             let ret = &data[..len];
             *data = &data[len..];
@@ -113,6 +167,177 @@ impl<'de> Readable<'de> for &'de [u8] {
     }
 }
 
+/// Returns the entry of `table` at an index received from the host
+///
+/// Handlers indexing a table, e.g. of pins or oids, with a value from the host should not index
+/// directly, as a bad value would panic. This returns `ReadError::Invalid` instead, which a
+/// handler returning `Result<(), ReadError>` or `Result<(), CommandError>` can pass on with `?`.
+/// The command is then rejected like a malformed one:
+/// ```ignore
+/// #[klipper_command]
+/// fn set_pin(context: &mut State, oid: u8, value: u8) -> Result<(), ReadError> {
+///     let pin = checked_index(&context.pins, oid)?;
+///     ...
+///     Ok(())
+/// }
+/// ```
+///
+/// [`CommandError`]: crate::CommandError
+pub fn checked_index<T, I: TryInto<usize>>(table: &[T], index: I) -> Result<&T, ReadError> {
+    index
+        .try_into()
+        .ok()
+        .and_then(|i| table.get(i))
+        .ok_or(ReadError::Invalid)
+}
+
+/// Returns the entry of `table` at an index received from the host, see [`checked_index`]
+pub fn checked_index_mut<T, I: TryInto<usize>>(
+    table: &mut [T],
+    index: I,
+) -> Result<&mut T, ReadError> {
+    index
+        .try_into()
+        .ok()
+        .and_then(|i| table.get_mut(i))
+        .ok_or(ReadError::Invalid)
+}
+
+/// Reads a length prefixed byte array from `data` into `buffer`
+///
+/// This allows handlers and `Readable` implementations to copy variable length data into owned
+/// storage that outlives the input buffer. Returns the number of bytes written to `buffer`. If the
+/// array does not fit in `buffer`, or `data` is truncated, `ReadError` is returned and `data` is
+/// not advanced.
+pub fn read_into(data: &mut &[u8], buffer: &mut [u8]) -> Result<usize, ReadError> {
+    let mut cursor = *data;
+    let bytes = <&[u8] as Readable>::read(&mut cursor)?;
+    let target = buffer.get_mut(..bytes.len()).ok_or(ReadError::Invalid)?;
+    target.copy_from_slice(bytes);
+    *data = cursor;
+    Ok(bytes.len())
+}
+
+/// Trait implemented for integer types that can be transferred as a typed slice
+///
+/// Klipper has no array type, so a `&[u16]` argument is transferred as a length prefixed byte
+/// array (`%*s` in the dictionary) holding the values in little endian byte order. The remote end
+/// must pack the values accordingly.
+pub trait SliceElement: Copy + Default {
+    /// Size of one encoded value in bytes
+    const SIZE: usize;
+
+    /// Decodes a value from exactly `SIZE` little endian bytes
+    fn from_le(bytes: &[u8]) -> Self;
+
+    /// Outputs the value as `SIZE` little endian bytes
+    fn write_le(&self, output: &mut impl OutputBuffer);
+}
+
+macro_rules! slice_element {
+    ( $type:tt ) => {
+        impl SliceElement for $type {
+            const SIZE: usize = core::mem::size_of::<$type>();
+
+            fn from_le(bytes: &[u8]) -> Self {
+                let mut b = [0u8; core::mem::size_of::<$type>()];
+                b.copy_from_slice(bytes);
+                $type::from_le_bytes(b)
+            }
+
+            fn write_le(&self, output: &mut impl OutputBuffer) {
+                output.output(&self.to_le_bytes());
+            }
+        }
+    };
+}
+
+slice_element!(u32);
+slice_element!(i32);
+slice_element!(u16);
+slice_element!(i16);
+
+/// Reads a typed slice from `data` into `buffer`
+///
+/// This is the typed counterpart of `read_into`, see `SliceElement` for the wire format. Returns
+/// the number of values written to `buffer`. If the values do not fit in `buffer`, the byte
+/// length isn't a multiple of the value size, or `data` is truncated, `ReadError` is returned and
+/// `data` is not advanced.
+pub fn read_slice_into<T: SliceElement>(
+    data: &mut &[u8],
+    buffer: &mut [T],
+) -> Result<usize, ReadError> {
+    let mut cursor = *data;
+    let bytes = <&[u8] as Readable>::read(&mut cursor)?;
+    if bytes.len() % T::SIZE != 0 {
+        return Err(ReadError::Invalid);
+    }
+    let target = buffer
+        .get_mut(..bytes.len() / T::SIZE)
+        .ok_or(ReadError::Invalid)?;
+    for (v, b) in target.iter_mut().zip(bytes.chunks_exact(T::SIZE)) {
+        *v = T::from_le(b);
+    }
+    *data = cursor;
+    Ok(target.len())
+}
+
+impl<T: SliceElement> Writable for &[T] {
+    fn write(&self, output: &mut impl OutputBuffer) {
+        encode_vlq_int(output, (self.len() * T::SIZE) as u32);
+        for v in self.iter() {
+            v.write_le(output);
+        }
+    }
+}
+
+/// A `u32` transferred as 4 little endian bytes instead of a VLQ integer
+///
+/// Useful for bridging binary sub-protocols with fixed layouts. Klipper has no fixed width
+/// integer type, so the value is transferred as a length prefixed byte array holding exactly 4
+/// bytes, and appears as `%*s` in the dictionary. A byte array of any other length is rejected.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Le32(pub u32);
+
+/// A `u32` transferred as 4 big endian bytes instead of a VLQ integer
+///
+/// See `Le32` for the representation.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Be32(pub u32);
+
+fn read_fixed4(data: &mut &[u8]) -> Result<[u8; 4], ReadError> {
+    let mut cursor = *data;
+    let bytes = <&[u8] as Readable>::read(&mut cursor)?;
+    let bytes = bytes.try_into().map_err(|_| ReadError::Invalid)?;
+    *data = cursor;
+    Ok(bytes)
+}
+
+impl Readable<'_> for Le32 {
+    fn read(data: &mut &[u8]) -> Result<Self, ReadError> {
+        read_fixed4(data).map(|b| Le32(u32::from_le_bytes(b)))
+    }
+}
+
+impl Writable for Le32 {
+    fn write(&self, output: &mut impl OutputBuffer) {
+        <&[u8] as Writable>::write(&&self.0.to_le_bytes()[..], output);
+    }
+}
+
+impl Readable<'_> for Be32 {
+    fn read(data: &mut &[u8]) -> Result<Self, ReadError> {
+        read_fixed4(data).map(|b| Be32(u32::from_be_bytes(b)))
+    }
+}
+
+impl Writable for Be32 {
+    fn write(&self, output: &mut impl OutputBuffer) {
+        <&[u8] as Writable>::write(&&self.0.to_be_bytes()[..], output);
+    }
+}
+
+// The length is VLQ encoded, the bytes are copied as is, see `Readable for &[u8]`
 impl Writable for &[u8] {
     fn write(&self, output: &mut impl OutputBuffer) {
         encode_vlq_int(output, self.len() as u32);
@@ -127,3 +352,158 @@ impl Writable for &str {
         output.output(bytes);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_buffer::ScratchOutput;
+
+    fn encode(value: impl Writable) -> ScratchOutput {
+        let mut out = ScratchOutput::new();
+        value.write(&mut out);
+        out
+    }
+
+    #[test]
+    fn fixed_width_byte_order() {
+        let le = encode(Le32(0x12345678));
+        assert_eq!(le.result(), &[4, 0x78, 0x56, 0x34, 0x12]);
+        let be = encode(Be32(0x12345678));
+        assert_eq!(be.result(), &[4, 0x12, 0x34, 0x56, 0x78]);
+
+        let mut data = le.result();
+        assert_eq!(Le32::read(&mut data), Ok(Le32(0x12345678)));
+        assert!(data.is_empty());
+        let mut data = be.result();
+        assert_eq!(Be32::read(&mut data), Ok(Be32(0x12345678)));
+        assert!(data.is_empty());
+        // The same bytes read with the other byte order
+        assert_eq!(Be32::read(&mut le.result()), Ok(Be32(0x78563412)));
+    }
+
+    #[test]
+    fn fixed_width_round_trip() {
+        for v in [0, 1, 0x7f, 0x80, 0xff00, 0x8000_0000, u32::MAX] {
+            assert_eq!(Le32::read(&mut encode(Le32(v)).result()), Ok(Le32(v)));
+            assert_eq!(Be32::read(&mut encode(Be32(v)).result()), Ok(Be32(v)));
+        }
+    }
+
+    #[test]
+    fn fixed_width_rejects_other_lengths() {
+        for bytes in [&[3u8, 1, 2, 3][..], &[5, 1, 2, 3, 4, 5], &[4, 1, 2]] {
+            let mut data = bytes;
+            assert!(Le32::read(&mut data).is_err());
+            assert!(Be32::read(&mut data).is_err());
+            // Not advanced on failure
+            assert_eq!(data, bytes);
+        }
+    }
+
+    #[test]
+    fn read_into_fits() {
+        let encoded = encode(&b"abcd"[..]);
+        // Exact fit
+        let mut buffer = [0u8; 4];
+        let mut data = encoded.result();
+        assert_eq!(read_into(&mut data, &mut buffer), Ok(4));
+        assert_eq!(&buffer, b"abcd");
+        assert!(data.is_empty());
+
+        // Under fit, the rest of the buffer is left alone
+        let mut buffer = [0xEEu8; 6];
+        let mut data = encoded.result();
+        assert_eq!(read_into(&mut data, &mut buffer), Ok(4));
+        assert_eq!(&buffer, b"abcd\xEE\xEE");
+        assert!(data.is_empty());
+
+        // Over fit is an error, leaving both the buffer and data untouched
+        let mut buffer = [0xEEu8; 3];
+        let mut data = encoded.result();
+        assert_eq!(read_into(&mut data, &mut buffer), Err(ReadError::Invalid));
+        assert_eq!(buffer, [0xEE; 3]);
+        assert_eq!(data, encoded.result());
+
+        // As is truncated data
+        let mut data = &encoded.result()[..3];
+        assert_eq!(
+            read_into(&mut data, &mut [0u8; 8]),
+            Err(ReadError::Truncated)
+        );
+        assert_eq!(data.len(), 3);
+    }
+
+    #[test]
+    fn checked_index_maps_bad_indexes() {
+        let mut table = [10, 20, 30];
+        assert_eq!(checked_index(&table, 2u8), Ok(&30));
+        assert_eq!(checked_index(&table, 0u32), Ok(&10));
+        // Out of range, and indexes with no `usize` value
+        assert_eq!(checked_index(&table, 3u8), Err(ReadError::Invalid));
+        assert_eq!(checked_index(&table, u32::MAX), Err(ReadError::Invalid));
+        assert_eq!(checked_index(&table, -1i32), Err(ReadError::Invalid));
+        assert_eq!(checked_index(&[0u8; 0], 0u8), Err(ReadError::Invalid));
+
+        *checked_index_mut(&mut table, 1u16).unwrap() = 21;
+        assert_eq!(table, [10, 21, 30]);
+        assert_eq!(checked_index_mut(&mut table, 3u16), Err(ReadError::Invalid));
+        assert_eq!(
+            checked_index_mut(&mut table, -1i32),
+            Err(ReadError::Invalid)
+        );
+    }
+
+    #[test]
+    fn char_is_ascii_only() {
+        // ASCII characters are sent like the `u32` of their code
+        for c in ['A', '0', ' ', 'z', '\x7f', '\0'] {
+            let encoded = encode(c);
+            assert_eq!(encoded.result(), encode(c as u32).result(), "{:?}", c);
+            assert_eq!(char::read(&mut encoded.result()), Ok(c));
+        }
+        // Anything else is sent as `?`
+        for c in ['é', '€', '\u{80}'] {
+            assert_eq!(encode(c).result(), encode('?').result(), "{:?}", c);
+        }
+        // Values above 0x7F are rejected, without advancing
+        for v in [0x80u32, 0xFF, 0x1F600] {
+            let encoded = encode(v);
+            let mut data = encoded.result();
+            assert_eq!(char::read(&mut data), Err(ReadError::Invalid));
+            assert_eq!(data, encoded.result());
+        }
+    }
+
+    #[test]
+    fn byte_array_is_length_and_raw_bytes() {
+        let bytes = [0x00, 0x5F, 0x60, 0x7F, 0x80, 0xFF];
+        assert_eq!(
+            encode(&bytes[..]).result(),
+            &[6, 0x00, 0x5F, 0x60, 0x7F, 0x80, 0xFF]
+        );
+
+        // Only the length is VLQ encoded
+        let long = [0xE0u8; 100];
+        let mut encoded = ScratchOutput::<128>::new();
+        (&long[..]).write(&mut encoded);
+        let len = encode(100u32);
+        assert_eq!(len.result().len(), 2);
+        assert_eq!(&encoded.result()[..2], len.result());
+        assert_eq!(&encoded.result()[2..], &long[..]);
+        let mut input = encoded.result();
+        assert_eq!(<&[u8]>::read(&mut input), Ok(&long[..]));
+        assert!(input.is_empty());
+
+        for data in [&bytes[..], &[]] {
+            let encoded = encode(data);
+            let mut input = encoded.result();
+            assert_eq!(<&[u8]>::read(&mut input), Ok(data));
+            assert!(input.is_empty());
+        }
+        let encoded = encode(&bytes[..]);
+        assert_eq!(
+            <&[u8]>::read(&mut &encoded.result()[..4]),
+            Err(ReadError::Truncated)
+        );
+    }
+}
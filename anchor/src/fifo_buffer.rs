@@ -1,3 +1,5 @@
+use crate::transport::{next_frame, Framing};
+
 /// FIFO buffer
 ///
 /// This implements a simple FIFO buffer which can be useful when managing data to/from Anchor
@@ -33,6 +35,11 @@ impl<const BUF_SIZE: usize> FifoBuffer<BUF_SIZE> {
         &mut self.buffer[self.used..]
     }
 
+    /// Return the non-filled part of the buffer
+    pub(crate) fn free_space(&self) -> &[u8] {
+        &self.buffer[self.used..]
+    }
+
     /// Append `buf` to the non-filled part of the buffer
     ///
     /// Any excess will be discarded.
@@ -49,8 +56,20 @@ impl<const BUF_SIZE: usize> FifoBuffer<BUF_SIZE> {
     /// Moves the used cursor forward
     ///
     /// This can be used after filling part of the non-filled buffer returned by `receive_buffer`.
-    pub fn advance(&mut self, n: usize) {
-        self.used = (self.used + n).clamp(0, self.buffer.len());
+    /// `n` must not exceed the length of that slice. Debug builds assert this, release builds
+    /// clamp `n` to it, so `data` never extends past the bytes `receive_buffer` exposed. Returns
+    /// the number of bytes actually added, which is less than `n` if it was clamped.
+    pub fn advance(&mut self, n: usize) -> usize {
+        let writable = self.buffer.len() - self.used;
+        debug_assert!(
+            n <= writable,
+            "advanced {} bytes with only {} writable",
+            n,
+            writable
+        );
+        let n = n.min(writable);
+        self.used += n;
+        n
     }
 
     /// Returns the filled part of the buffer
@@ -58,6 +77,26 @@ impl<const BUF_SIZE: usize> FifoBuffer<BUF_SIZE> {
         &self.buffer[0..self.used]
     }
 
+    /// Passes each complete frame at the front of the buffer to `f`, removing it
+    ///
+    /// Frames are split and checked exactly as `Transport::receive` does, but not dispatched.
+    /// `f` receives the whole frame, including header and trailer, e.g. to forward it elsewhere.
+    /// Invalid data is discarded, and a trailing partial frame is left in the buffer to be
+    /// completed by data received later.
+    pub fn drain_frames(&mut self, mut f: impl FnMut(&[u8])) {
+        let mut data = self.data();
+        let mut synchronized = true;
+        loop {
+            match next_frame(&mut data, &mut synchronized) {
+                Framing::Frame(frame) => f(frame),
+                Framing::Synchronized => {}
+                Framing::Incomplete => break,
+            }
+        }
+        let consumed = self.used - data.len();
+        self.pop(consumed);
+    }
+
     /// Removes `n` bytes from the front of the buffer
     ///
     /// This operation moves the used part of the buffer down in memory. This is linear in the
@@ -70,3 +109,50 @@ impl<const BUF_SIZE: usize> FifoBuffer<BUF_SIZE> {
         self.used = len;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "writable"))]
+    fn advance_past_writable_region() {
+        let mut fifo = FifoBuffer::<8>::new();
+        fifo.extend(&[1, 2, 3, 4, 5, 6]);
+        fifo.pop(4);
+        // Positions 2..6 still hold the popped bytes, all of them are handed out for writing
+        let rx = fifo.receive_buffer();
+        assert_eq!(rx.len(), 6);
+        rx.fill(0xaa);
+
+        assert_eq!(fifo.advance(10), 6);
+        assert_eq!(fifo.data(), &[5, 6, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa]);
+        assert_eq!(fifo.advance(1), 0);
+        assert_eq!(fifo.len(), 8);
+    }
+
+    #[test]
+    fn drain_frames_keeps_trailing_partial_frame() {
+        extern crate std;
+        use crate::transport::tests::frame;
+        use std::vec::Vec;
+
+        let frames = [frame(0, &[1, 2, 3]), frame(1, &[]), frame(2, &[4, 5])];
+        let mut fifo = FifoBuffer::<64>::new();
+        fifo.extend(&frames[0]);
+        fifo.extend(&frames[1]);
+        fifo.extend(&frames[2][..4]);
+
+        let mut drained = Vec::new();
+        fifo.drain_frames(|f| drained.push(f.to_vec()));
+        assert_eq!(drained, &frames[..2]);
+        assert_eq!(fifo.data(), &frames[2][..4]);
+
+        // The rest of the partial frame arrives
+        fifo.extend(&frames[2][4..]);
+        drained.clear();
+        fifo.drain_frames(|f| drained.push(f.to_vec()));
+        assert_eq!(drained, &frames[2..]);
+        assert!(fifo.is_empty());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::fifo_buffer::FifoBuffer;
+
 /// Trait representing a buffer that protocol messages can be read from
 pub trait InputBuffer {
     /// Retrieve all current data in the buffer
@@ -33,6 +35,18 @@ impl<'a> InputBuffer for SliceInputBuffer<'a> {
     }
 }
 
+/// A `FifoBuffer` can be passed to `Transport::receive` directly, which then removes the consumed
+/// data itself
+impl<const BUF_SIZE: usize> InputBuffer for FifoBuffer<BUF_SIZE> {
+    fn data(&self) -> &[u8] {
+        FifoBuffer::data(self)
+    }
+
+    fn pop(&mut self, count: usize) {
+        FifoBuffer::pop(self, count)
+    }
+}
+
 #[cfg(feature = "std")]
 impl InputBuffer for Vec<u8> {
     fn data(&self) -> &[u8] {
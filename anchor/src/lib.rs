@@ -76,6 +76,12 @@
 //! Note that in the example code above, no actual transmission is done. Instead, data is added to
 //! a buffer. This buffer will be flushed to the USB channel at a later time by the main loop.
 //!
+//! This pattern is common enough that Anchor provides it as [`BufferedTransportOutput`], which is
+//! generic over the mutex type used to protect the buffer. To shed load on a congested link, an
+//! output can be wrapped in a [`RateLimitedOutput`], which drops frames rejected by a predicate.
+//! For tests built with `std`, wrapping the output in a `CaptureOutput` allows collecting the
+//! frames sent by a command, see `invoke_command` below.
+//!
 //! With the [`TransportOutput`] ready, add the [`klipper_config_generate!`] invocation. Usually
 //! this is best done in the `main.rs` file of the project:
 //! ```
@@ -110,6 +116,19 @@
 //! this happens. No buffering is implemented within `receive`, it is the responsibility of the
 //! caller to maintain the input buffer.
 //!
+//! Buffers that own their data, such as [`FifoBuffer`], are drained by `receive` itself, so
+//! there is no consumed length to compute. When received data is collected in a [`FifoBuffer`],
+//! it can be passed directly, or through `pump_once`:
+//! ```ignore
+//! KLIPPER_TRANSPORT.pump_once(&mut receive_buffer, &mut self.state);
+//! ```
+//!
+//! With the `critical-section` feature, the transport can also buffer received data itself.
+//! Received bytes are then passed to `feed` as they arrive, without any receive buffer:
+//! ```ignore
+//! KLIPPER_TRANSPORT.feed(&bytes[..n], &mut self.state);
+//! ```
+//!
 //! With this, Anchor is hooked up and Klipper message handlers, commands, enumerations, and
 //! constants can be added as required. See the macros in this crate for more information.
 //!
@@ -126,9 +145,16 @@
 //! | `get_config`     | Must reply with `config`   |
 //! | `config_reset`   | See example                |
 //! | `finalize_config`| See example                |
+//!
+//! With the `defmt` feature enabled, the transport logs dropped frames and rejected commands
+//! using `defmt`. Without the feature, no logging code is compiled in.
 
 #![cfg_attr(not(feature = "std"), no_std)]
 
+// Must come first, the logging macros are used throughout the crate
+#[macro_use]
+mod log;
+
 #[doc(hidden)]
 pub mod encoding;
 #[doc(hidden)]
@@ -141,11 +167,39 @@ pub mod transport;
 #[doc(hidden)]
 pub mod transport_output;
 
+mod buffered_output;
+#[cfg(feature = "std")]
+mod capture_output;
+mod clock;
+mod config_state;
+mod crc;
 mod fifo_buffer;
+mod rate_limited_output;
+mod shutdown;
+mod stats;
+
+/// Internals exposed to the benchmarks, not part of the public API
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::crc::crc16;
+}
 
 pub use anchor_macro::*;
+pub use buffered_output::{BufferMutex, BufferedTransportOutput, FrameRingTransportOutput};
+#[cfg(feature = "std")]
+pub use capture_output::CaptureOutput;
+pub use clock::{Clock64, ClockFreq, InstantShort};
+pub use config_state::{ConfigPhase, ConfigPhaseError, ConfigState};
+pub use encoding::{checked_index, checked_index_mut, read_into, read_slice_into, Be32, Le32};
 pub use fifo_buffer::FifoBuffer;
 pub use input_buffer::{InputBuffer, SliceInputBuffer};
-pub use output_buffer::{OutputBuffer, ScratchOutput};
-pub use transport::Transport;
+pub use output_buffer::{
+    CrcOutputBuffer, FrameRingOutput, OutputBuffer, ScratchOutput, SliceOutput,
+};
+pub use rate_limited_output::RateLimitedOutput;
+pub use shutdown::{CommandError, Shutdown};
+pub use stats::{Stats, StatsReporter, STATS_SUMSQ_BASE};
+#[cfg(feature = "std")]
+pub use transport::{acknak_frame, parse_acknak};
+pub use transport::{CapacityError, SendError, Transport};
 pub use transport_output::TransportOutput;
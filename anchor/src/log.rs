@@ -0,0 +1,18 @@
+//! Optional `defmt` logging of protocol events
+//!
+//! The macros forward to `defmt` when the `defmt` feature is enabled. Without the feature they
+//! expand to nothing, so neither the log calls nor their format strings end up in the build.
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "defmt")]
+        ::defmt::debug!($($arg)*);
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "defmt")]
+        ::defmt::warn!($($arg)*);
+    };
+}
@@ -1,3 +1,5 @@
+use crate::fifo_buffer::FifoBuffer;
+
 /// Trait for output buffers that can accept encoded data.
 ///
 /// Message builders accept an argumenet of this type and will output their data in to the buffer.
@@ -9,18 +11,170 @@ pub trait OutputBuffer {
     type Cursor: Copy;
     /// Append bytes to the buffer
     fn output(&mut self, buf: &[u8]);
-    /// Retrieve the cursor representing the position of the last appended byte
+    /// Retrieve the cursor representing the position of the next appended byte
     fn cur_position(&self) -> Self::Cursor;
     /// Replace the byte at the cursor position with a new value
     fn update(&mut self, cursor: Self::Cursor, value: u8);
     /// Retrieve a reference to all data pushed after the cursor
     fn data_since(&self, cursor: Self::Cursor) -> &[u8];
+
+    /// Reserve a length byte, to be filled in by `patch_len`
+    ///
+    /// This allows writing a length prefixed region, e.g. a byte array argument, without knowing
+    /// its length up front:
+    /// ```ignore
+    /// let len = output.reserve_len();
+    /// for v in values {
+    ///     v.write(output);
+    /// }
+    /// output.patch_len(len);
+    /// ```
+    /// The length is a single byte, so the region must be shorter than 96 bytes, which is always
+    /// the case within a single message.
+    fn reserve_len(&mut self) -> Self::Cursor {
+        let cursor = self.cur_position();
+        self.output(&[0]);
+        cursor
+    }
+
+    /// Set a length byte from `reserve_len` to the number of bytes written after it
+    fn patch_len(&mut self, cursor: Self::Cursor) {
+        let len = self.data_since(cursor).len().saturating_sub(1);
+        // Lengths up to 95 are encoded as a single VLQ byte
+        debug_assert!(len < 96, "length prefixed region is too long");
+        self.update(cursor, len as u8);
+    }
+
+    /// Remove all data pushed after the cursor
+    ///
+    /// Used to drop a message that can't be sent, e.g. because it is too large. Buffers that
+    /// can't remove data can ignore this, in which case the data is sent as is.
+    fn truncate(&mut self, _cursor: Self::Cursor) {}
+
+    /// Start calculating a CRC incrementally from the current position
+    ///
+    /// Buffers that don't support incremental CRC calculation can ignore this.
+    fn crc_start(&mut self) {}
+
+    /// Retrieve the CRC of all data pushed since `crc_start` was called
+    ///
+    /// Returns `None` if the buffer does not support incremental CRC calculation, in which case
+    /// the CRC is calculated from `data_since`.
+    fn crc_result(&self) -> Option<u16> {
+        None
+    }
+}
+
+/// An `OutputBuffer` wrapper that calculates the frame CRC as data is appended.
+///
+/// This avoids a second pass over the message when the frame is finished, which is useful for
+/// large messages or for outputs where the data can't cheaply be read back.
+pub struct CrcOutputBuffer<B: OutputBuffer> {
+    inner: B,
+    start: Option<B::Cursor>,
+    tracked: usize,
+    crc: u16,
+}
+
+impl<B: OutputBuffer> CrcOutputBuffer<B> {
+    /// Create a new wrapper around `inner`
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            start: None,
+            tracked: 0,
+            crc: 0xFFFF,
+        }
+    }
+
+    /// Retrieve a reference to the wrapped buffer
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Retrieve a mutable reference to the wrapped buffer
+    ///
+    /// Data written directly to the wrapped buffer is not included in the CRC.
+    pub fn inner_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    /// Unwrap the buffer
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: OutputBuffer> OutputBuffer for CrcOutputBuffer<B> {
+    type Cursor = B::Cursor;
+
+    fn output(&mut self, buf: &[u8]) {
+        let Some(start) = self.start else {
+            self.inner.output(buf);
+            return;
+        };
+        self.inner.output(buf);
+        // Only account for the bytes the inner buffer actually accepted
+        let accepted = self.inner.data_since(start).len() - self.tracked;
+        for b in &buf[..accepted.min(buf.len())] {
+            self.crc = crate::crc::crc16_step(self.crc, *b);
+        }
+        self.tracked += accepted;
+    }
+
+    fn cur_position(&self) -> Self::Cursor {
+        self.inner.cur_position()
+    }
+
+    fn update(&mut self, cursor: Self::Cursor, value: u8) {
+        if self.start.is_some() {
+            let since = self.inner.data_since(cursor);
+            if let Some(old) = since.first() {
+                if since.len() <= self.tracked {
+                    self.crc ^= crate::crc::crc16_delta(old ^ value, since.len() - 1);
+                }
+            }
+        }
+        self.inner.update(cursor, value)
+    }
+
+    fn data_since(&self, cursor: Self::Cursor) -> &[u8] {
+        self.inner.data_since(cursor)
+    }
+
+    fn truncate(&mut self, cursor: Self::Cursor) {
+        // The removed data may be part of the CRC, which can't be undone
+        self.start = None;
+        self.inner.truncate(cursor)
+    }
+
+    fn crc_start(&mut self) {
+        self.start = Some(self.inner.cur_position());
+        self.tracked = 0;
+        self.crc = 0xFFFF;
+    }
+
+    fn crc_result(&self) -> Option<u16> {
+        self.start.map(|_| self.crc)
+    }
 }
 
 /// A scratch pad based `OutputBuffer`.
 ///
 /// Uses a statically sized inlined buffer. For serializing multiple messages in a row, the buffer
 /// can be reset if needed.
+///
+/// A single scratch can be reused for several frames, e.g. by a `TransportOutput` that batches
+/// frames before flushing them, by taking each frame out with `result_and_reset`:
+/// ```ignore
+/// let mut scratch = ScratchOutput::new();
+/// for f in frames {
+///     f(&mut scratch);
+///     batch.extend(scratch.result_and_reset());
+/// }
+/// ```
+/// As long as each frame is taken out before the next one is written, nothing needs clearing
+/// beyond the write index.
 pub struct ScratchOutput<const MAX_SIZE: usize = 64> {
     buffer: [u8; MAX_SIZE],
     idx: usize,
@@ -37,6 +191,15 @@ impl<const MAX_SIZE: usize> ScratchOutput<MAX_SIZE> {
         self.idx = 0;
     }
 
+    /// Retrieve the currently built buffer and reset it in one step
+    ///
+    /// The returned data stays valid until the next write, which starts over at the beginning
+    /// of the buffer.
+    pub fn result_and_reset(&mut self) -> &[u8] {
+        let len = core::mem::take(&mut self.idx);
+        &self.buffer[..len]
+    }
+
     /// Create a new buffer
     pub const fn new() -> Self {
         Self {
@@ -52,7 +215,7 @@ impl<const MAX_SIZE: usize> OutputBuffer for ScratchOutput<MAX_SIZE> {
     fn output(&mut self, buf: &[u8]) {
         let area = &mut self.buffer[self.idx..];
         let len = buf.len().clamp(0, area.len());
-        area[..len].copy_from_slice(buf);
+        area[..len].copy_from_slice(&buf[..len]);
         self.idx += len;
     }
 
@@ -75,6 +238,175 @@ impl<const MAX_SIZE: usize> OutputBuffer for ScratchOutput<MAX_SIZE> {
             &self.buffer[cursor..self.idx]
         }
     }
+
+    fn truncate(&mut self, cursor: Self::Cursor) {
+        self.idx = self.idx.min(cursor);
+    }
+}
+
+/// An `OutputBuffer` writing into a caller provided slice
+///
+/// Data that doesn't fit is dropped, and flags the buffer as overflowed. Used by
+/// [`Transport::encode_frame_to`](crate::Transport::encode_frame_to).
+pub struct SliceOutput<'a> {
+    buffer: &'a mut [u8],
+    idx: usize,
+    overflow: bool,
+}
+
+impl<'a> SliceOutput<'a> {
+    /// Create a new, empty buffer writing into `buffer`
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            idx: 0,
+            overflow: false,
+        }
+    }
+
+    /// Retrieve the data written so far
+    pub fn result(&self) -> &[u8] {
+        &self.buffer[..self.idx]
+    }
+
+    /// Checks if any data was dropped for lack of space
+    pub fn overflowed(&self) -> bool {
+        self.overflow
+    }
+}
+
+impl OutputBuffer for SliceOutput<'_> {
+    type Cursor = usize;
+
+    fn output(&mut self, buf: &[u8]) {
+        let area = &mut self.buffer[self.idx..];
+        if area.len() < buf.len() {
+            self.overflow = true;
+            return;
+        }
+        area[..buf.len()].copy_from_slice(buf);
+        self.idx += buf.len();
+    }
+
+    fn cur_position(&self) -> Self::Cursor {
+        self.idx
+    }
+
+    fn update(&mut self, cursor: Self::Cursor, value: u8) {
+        if cursor < self.idx {
+            self.buffer[cursor] = value;
+        }
+    }
+
+    fn data_since(&self, cursor: Self::Cursor) -> &[u8] {
+        self.result().get(cursor..).unwrap_or(&[])
+    }
+
+    fn truncate(&mut self, cursor: Self::Cursor) {
+        self.idx = self.idx.min(cursor);
+    }
+}
+
+/// A `FifoBuffer` that frames are written to in place
+///
+/// Data is written directly into the free part of the buffer, where it can still be patched
+/// through `update`. It only becomes part of the buffered data once `commit` is called, so a
+/// partially written frame is never visible to the code draining the buffer. A frame that
+/// doesn't fit in the free space is discarded as a whole.
+///
+/// Used by [`FrameRingTransportOutput`](crate::FrameRingTransportOutput), which commits after
+/// each frame.
+pub struct FrameRingOutput<const BUF_SIZE: usize> {
+    buffer: FifoBuffer<BUF_SIZE>,
+    pending: usize,
+    overflow: bool,
+}
+
+impl<const BUF_SIZE: usize> FrameRingOutput<BUF_SIZE> {
+    /// Create a new, empty buffer
+    pub const fn new() -> Self {
+        Self {
+            buffer: FifoBuffer::new(),
+            pending: 0,
+            overflow: false,
+        }
+    }
+
+    /// Append the pending frame to the buffered data
+    ///
+    /// Returns false, discarding the frame, if it didn't fit in the buffer.
+    pub fn commit(&mut self) -> bool {
+        let ok = !self.overflow;
+        if ok {
+            self.buffer.advance(self.pending);
+        }
+        self.discard();
+        ok
+    }
+
+    /// Discard the pending frame
+    pub fn discard(&mut self) {
+        self.pending = 0;
+        self.overflow = false;
+    }
+
+    /// Returns the committed data
+    pub fn data(&self) -> &[u8] {
+        self.buffer.data()
+    }
+
+    /// Removes `n` bytes from the front of the committed data
+    ///
+    /// Any pending frame is discarded.
+    pub fn pop(&mut self, n: usize) {
+        self.discard();
+        self.buffer.pop(n);
+    }
+
+    /// Checks if there is no committed data
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+impl<const BUF_SIZE: usize> Default for FrameRingOutput<BUF_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const BUF_SIZE: usize> OutputBuffer for FrameRingOutput<BUF_SIZE> {
+    type Cursor = usize;
+
+    fn output(&mut self, buf: &[u8]) {
+        let area = &mut self.buffer.receive_buffer()[self.pending..];
+        if area.len() < buf.len() {
+            self.overflow = true;
+            return;
+        }
+        area[..buf.len()].copy_from_slice(buf);
+        self.pending += buf.len();
+    }
+
+    fn cur_position(&self) -> Self::Cursor {
+        self.pending
+    }
+
+    fn update(&mut self, cursor: Self::Cursor, value: u8) {
+        if cursor < self.pending {
+            self.buffer.receive_buffer()[cursor] = value;
+        }
+    }
+
+    fn data_since(&self, cursor: Self::Cursor) -> &[u8] {
+        let pending = &self.buffer.free_space()[..self.pending];
+        pending.get(cursor..).unwrap_or(&[])
+    }
+
+    fn truncate(&mut self, cursor: Self::Cursor) {
+        self.pending = self.pending.min(cursor);
+        self.overflow = false;
+    }
 }
 
 #[cfg(feature = "std")]
@@ -86,7 +418,7 @@ impl OutputBuffer for Vec<u8> {
     }
 
     fn cur_position(&self) -> Self::Cursor {
-        self.len().saturating_sub(1)
+        self.len()
     }
 
     fn update(&mut self, cursor: Self::Cursor, value: u8) {
@@ -96,4 +428,113 @@ impl OutputBuffer for Vec<u8> {
     fn data_since(&self, cursor: Self::Cursor) -> &[u8] {
         &self[cursor..]
     }
+
+    fn truncate(&mut self, cursor: Self::Cursor) {
+        Vec::truncate(self, cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::crc16;
+
+    /// Returns the incremental CRC of `out`, checking it against one over the data since `start`
+    fn check_crc<B: OutputBuffer>(out: &CrcOutputBuffer<B>, start: B::Cursor) -> Option<u16> {
+        let crc = out.crc_result();
+        if let Some(crc) = crc {
+            assert_eq!(crc, crc16(out.data_since(start)));
+        }
+        crc
+    }
+
+    #[test]
+    fn crc_output_matches_crc16() {
+        let mut out = CrcOutputBuffer::new(ScratchOutput::<64>::new());
+        // Data before `crc_start` isn't covered
+        out.output(&[0xaa, 0xbb]);
+        let start = out.cur_position();
+        out.crc_start();
+        assert_eq!(check_crc(&out, start), Some(0xffff));
+
+        out.output(&[0, 0x10, 1, 2, 3]);
+        assert!(check_crc(&out, start).is_some());
+        // Patching bytes already covered, like the frame length
+        out.update(start, 12);
+        assert!(check_crc(&out, start).is_some());
+        let len = out.reserve_len();
+        out.output(&[4, 5, 6]);
+        out.patch_len(len);
+        assert_eq!(out.data_since(len), &[3, 4, 5, 6]);
+        assert!(check_crc(&out, start).is_some());
+
+        // Truncating can't be undone in the CRC, which falls back to a second pass
+        out.truncate(len);
+        assert_eq!(check_crc(&out, start), None);
+        let start = out.cur_position();
+        out.crc_start();
+        out.output(&[7, 8, 9]);
+        assert!(check_crc(&out, start).is_some());
+    }
+
+    #[test]
+    fn crc_output_counts_accepted_bytes() {
+        let mut out = CrcOutputBuffer::new(ScratchOutput::<4>::new());
+        let start = out.cur_position();
+        out.crc_start();
+        out.output(&[1, 2, 3]);
+        // Only one more byte fits
+        out.output(&[4, 5, 6]);
+        assert_eq!(out.data_since(start), &[1, 2, 3, 4]);
+        assert!(check_crc(&out, start).is_some());
+    }
+
+    #[test]
+    fn nested_length_prefixes() {
+        let mut out = ScratchOutput::<128>::new();
+        let outer = out.reserve_len();
+        out.output(&[1]);
+        let inner = out.reserve_len();
+        out.output(&[2, 3]);
+        out.patch_len(inner);
+        out.output(&[4]);
+        out.patch_len(outer);
+        assert_eq!(out.result(), &[5, 1, 2, 2, 3, 4]);
+
+        // An empty region, and the longest one a single length byte holds
+        out.reset();
+        let empty = out.reserve_len();
+        out.patch_len(empty);
+        let longest = out.reserve_len();
+        out.output(&[0xaa; 95]);
+        out.patch_len(longest);
+        assert_eq!(&out.result()[..3], &[0, 95, 0xaa]);
+        assert_eq!(out.result().len(), 97);
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "too long"))]
+    fn length_prefix_limit() {
+        let mut out = ScratchOutput::<128>::new();
+        let len = out.reserve_len();
+        out.output(&[0xaa; 96]);
+        out.patch_len(len);
+    }
+
+    #[test]
+    fn scratch_reused_across_frames() {
+        let mut out = ScratchOutput::<64>::new();
+        out.output(&[8, 0x10, 1, 2, 3, 0xab, 0xcd, 0x7e]);
+        assert_eq!(
+            out.result_and_reset(),
+            &[8, 0x10, 1, 2, 3, 0xab, 0xcd, 0x7e]
+        );
+        assert!(out.result().is_empty());
+
+        // A shorter second frame doesn't pick up the tail of the first
+        out.output(&[5, 0x11, 0x12, 0x34, 0x7e]);
+        assert_eq!(out.cur_position(), 5);
+        assert_eq!(out.result_and_reset(), &[5, 0x11, 0x12, 0x34, 0x7e]);
+        assert!(out.result_and_reset().is_empty());
+    }
 }
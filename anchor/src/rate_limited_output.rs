@@ -0,0 +1,145 @@
+use crate::output_buffer::{OutputBuffer, ScratchOutput};
+use crate::transport_output::TransportOutput;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// A `TransportOutput` wrapper that counts output and drops frames rejected by a predicate
+///
+/// Each frame is serialized to a `ScratchOutput` and passed to the `allow` predicate. Allowed
+/// frames are copied to the inner output, and their bytes counted. Rejected frames are dropped
+/// and counted. This allows shedding load on a congested link, e.g. by dropping frames while the
+/// buffer of the inner output is nearly full:
+/// ```ignore
+/// pub static AUX_OUTPUT: RateLimitedOutput<&UsbOutput, fn(&[u8]) -> bool> =
+///     RateLimitedOutput::new(&TRANSPORT_OUTPUT, |_| TRANSPORT_OUTPUT.with_buffer(|b| b.len()) < 96);
+/// ```
+/// The predicate only sees the encoded frame, and can't tell replies from `klipper_output!`
+/// messages without decoding it. To only drop low priority messages, send them with
+/// `klipper_output!([channel = aux] ...)` and wrap only the `aux_transport` output, which may
+/// share its buffer with the main one as above.
+///
+/// The counters are updated without read-modify-write atomics, so they are exact only if output
+/// isn't requested concurrently, e.g. from both the main loop and an interrupt.
+pub struct RateLimitedOutput<T, P> {
+    inner: T,
+    allow: P,
+    sent_bytes: AtomicU32,
+    dropped: AtomicU32,
+}
+
+impl<T, P> RateLimitedOutput<T, P> {
+    /// Wraps `inner`, emitting only frames for which `allow` returns true
+    pub const fn new(inner: T, allow: P) -> Self {
+        RateLimitedOutput {
+            inner,
+            allow,
+            sent_bytes: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the wrapped output
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the number of bytes passed to the inner output
+    pub fn sent_bytes(&self) -> u32 {
+        self.sent_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of dropped frames
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Resets both counters to zero
+    pub fn reset_counts(&self) {
+        self.sent_bytes.store(0, Ordering::Relaxed);
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<T: TransportOutput, P: Fn(&[u8]) -> bool> TransportOutput for RateLimitedOutput<T, P> {
+    type Output = ScratchOutput;
+    fn output(&self, f: impl FnOnce(&mut Self::Output)) {
+        let mut scratch = ScratchOutput::new();
+        f(&mut scratch);
+        let frame = scratch.result();
+        if frame.is_empty() {
+            return;
+        }
+        if (self.allow)(frame) {
+            self.inner.output(|out| out.output(frame));
+            let sent = self.sent_bytes.load(Ordering::Relaxed);
+            self.sent_bytes
+                .store(sent.wrapping_add(frame.len() as u32), Ordering::Relaxed);
+        } else {
+            let dropped = self.dropped.load(Ordering::Relaxed);
+            self.dropped
+                .store(dropped.wrapping_add(1), Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::cell::{Cell, RefCell};
+    use std::{vec, vec::Vec};
+
+    /// Collects the frames passed on to it
+    #[derive(Default)]
+    struct Collect(RefCell<Vec<Vec<u8>>>);
+
+    impl TransportOutput for Collect {
+        type Output = ScratchOutput;
+        fn output(&self, f: impl FnOnce(&mut Self::Output)) {
+            let mut scratch = ScratchOutput::new();
+            f(&mut scratch);
+            self.0.borrow_mut().push(scratch.result().to_vec());
+        }
+    }
+
+    fn send(output: &impl TransportOutput, frame: &[u8]) {
+        output.output(|out| out.output(frame));
+    }
+
+    #[test]
+    fn drops_and_counts_rejected_frames() {
+        // Room for 8 bytes, as if the inner buffer filled up
+        let room = Cell::new(8usize);
+        let output = RateLimitedOutput::new(Collect::default(), |frame: &[u8]| {
+            let fits = frame.len() <= room.get();
+            if fits {
+                room.set(room.get() - frame.len());
+            }
+            fits
+        });
+
+        send(&output, &[1, 2, 3, 4, 5]);
+        send(&output, &[6, 7, 8, 9]);
+        send(&output, &[10, 11, 12]);
+        send(&output, &[13]);
+        assert_eq!(
+            *output.inner().0.borrow(),
+            vec![vec![1, 2, 3, 4, 5], vec![10, 11, 12]]
+        );
+        assert_eq!(output.sent_bytes(), 8);
+        assert_eq!(output.dropped(), 2);
+
+        // An empty frame is neither counted nor passed on
+        output.output(|_| {});
+        assert_eq!((output.sent_bytes(), output.dropped()), (8, 2));
+        assert_eq!(output.inner().0.borrow().len(), 2);
+
+        output.reset_counts();
+        assert_eq!((output.sent_bytes(), output.dropped()), (0, 0));
+        room.set(1);
+        send(&output, &[14]);
+        send(&output, &[15]);
+        assert_eq!((output.sent_bytes(), output.dropped()), (1, 1));
+        assert_eq!(output.inner().0.borrow().last(), Some(&vec![14]));
+    }
+}
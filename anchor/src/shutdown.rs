@@ -0,0 +1,63 @@
+use crate::encoding::ReadError;
+
+/// A fatal error returned from a command handler
+///
+/// Command handlers may return `Result<(), Shutdown>` instead of `()`. When a handler returns an
+/// error, a `shutdown` message with the given static string and clock is sent to the remote end,
+/// exactly as if `klipper_shutdown!` had been called:
+/// ```ignore
+/// #[klipper_command]
+/// fn config_thing(context: &mut State, oid: u8) -> Result<(), Shutdown> {
+///     if !context.has_oid(oid) {
+///         return Err(Shutdown::new(klipper_static_string!("Invalid oid"), context.clock()));
+///     }
+///     ...
+///     Ok(())
+/// }
+/// ```
+///
+/// As with `klipper_shutdown!`, it is up to the user code to perform any further
+/// shutdown-related handling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Shutdown {
+    pub static_string_id: u16,
+    pub clock: u32,
+}
+
+impl Shutdown {
+    /// Creates a shutdown error from a `klipper_static_string!` id and the current clock
+    pub const fn new(static_string_id: u16, clock: u32) -> Self {
+        Shutdown {
+            static_string_id,
+            clock,
+        }
+    }
+}
+
+/// Any error a command handler may return
+///
+/// Handlers may return `Result<(), Shutdown>` or `Result<(), ReadError>`, or this type to use `?`
+/// on both. A `Shutdown` is reported as described there. A `ReadError` is handled as if the
+/// command failed to decode: the rest of the frame is dropped, and no shutdown is sent. This
+/// suits values from the host that the handler rejects, see [`checked_index`].
+///
+/// [`checked_index`]: crate::checked_index
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// Shut down, as with `klipper_shutdown!`
+    Shutdown(Shutdown),
+    /// Reject the command as malformed
+    Read(ReadError),
+}
+
+impl From<Shutdown> for CommandError {
+    fn from(s: Shutdown) -> Self {
+        CommandError::Shutdown(s)
+    }
+}
+
+impl From<ReadError> for CommandError {
+    fn from(e: ReadError) -> Self {
+        CommandError::Read(e)
+    }
+}
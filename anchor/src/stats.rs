@@ -0,0 +1,174 @@
+/// Scaling of the `sumsq` field of the `stats` message
+///
+/// Klippy reads this from the `STATS_SUMSQ_BASE` constant, which the firmware must expose:
+/// ```ignore
+/// #[klipper_constant]
+/// const STATS_SUMSQ_BASE: u32 = anchor::STATS_SUMSQ_BASE;
+/// ```
+pub const STATS_SUMSQ_BASE: u32 = 256;
+
+/// Values of a Klipper `stats` message
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of ticks since the previous report
+    pub count: u32,
+    /// Clock ticks elapsed since the previous report
+    pub sum: u32,
+    /// Sum of the squared tick durations, divided by `STATS_SUMSQ_BASE`
+    pub sumsq: u32,
+}
+
+/// Main loop statistics for the periodic Klipper `stats` message
+///
+/// Klippy expects the MCU to periodically report how busy its main loop is. Call `tick` once per
+/// main loop iteration with the current clock. Once `interval` clock ticks have passed since the
+/// previous report, `tick` returns the values to send:
+/// ```ignore
+/// let mut stats = StatsReporter::new(5 * CLOCK_FREQ);
+/// loop {
+///     // Receive and handle commands...
+///     if let Some(s) = stats.tick(cur_clock()) {
+///         klipper_reply!(stats, count: u32 = s.count, sum: u32 = s.sum, sumsq: u32 = s.sumsq);
+///     }
+/// }
+/// ```
+/// The clock is the 32 bit MCU clock, and may wrap around. Klipper uses an interval of 5
+/// seconds.
+#[derive(Debug, Clone)]
+pub struct StatsReporter {
+    interval: u32,
+    last_send: Option<u32>,
+    last_tick: u32,
+    count: u32,
+    sumsq: u32,
+}
+
+impl StatsReporter {
+    /// Creates a reporter sending a report every `interval` clock ticks
+    pub const fn new(interval: u32) -> Self {
+        StatsReporter {
+            interval,
+            last_send: None,
+            last_tick: 0,
+            count: 0,
+            sumsq: 0,
+        }
+    }
+
+    /// Records a main loop iteration ending at `now`
+    ///
+    /// Returns the statistics to report if the interval has passed. The first call only starts
+    /// the measurement.
+    pub fn tick(&mut self, now: u32) -> Option<Stats> {
+        let last_send = match self.last_send {
+            Some(t) => t,
+            None => {
+                self.last_send = Some(now);
+                self.last_tick = now;
+                return None;
+            }
+        };
+
+        let diff = now.wrapping_sub(self.last_tick);
+        self.last_tick = now;
+        self.count += 1;
+        // Same saturating accumulation as Klipper, avoiding 64 bit math
+        let add = if diff <= 0xFFFF {
+            (diff * diff).div_ceil(STATS_SUMSQ_BASE)
+        } else if diff <= 0xFFFFF {
+            diff.div_ceil(STATS_SUMSQ_BASE).saturating_mul(diff)
+        } else {
+            u32::MAX
+        };
+        self.sumsq = self.sumsq.saturating_add(add);
+
+        let elapsed = now.wrapping_sub(last_send);
+        if elapsed < self.interval {
+            return None;
+        }
+        let stats = Stats {
+            count: self.count,
+            sum: elapsed,
+            sumsq: self.sumsq,
+        };
+        self.last_send = Some(now);
+        self.count = 0;
+        self.sumsq = 0;
+        Some(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the `sumsq` reported for a single iteration taking `diff` ticks
+    fn sumsq_of(diff: u32) -> u32 {
+        let mut stats = StatsReporter::new(0);
+        stats.tick(1000);
+        stats.tick(1000u32.wrapping_add(diff)).unwrap().sumsq
+    }
+
+    #[test]
+    fn first_tick_starts_measurement() {
+        let mut stats = StatsReporter::new(100);
+        assert_eq!(stats.tick(5000), None);
+        assert_eq!(stats.tick(5050), None);
+        let s = stats.tick(5100).unwrap();
+        assert_eq!((s.count, s.sum), (2, 100));
+    }
+
+    #[test]
+    fn one_report_per_interval() {
+        let mut stats = StatsReporter::new(100);
+        let reports: [Option<Stats>; 101] = core::array::from_fn(|i| stats.tick(i as u32 * 10));
+        let sent: [_; 10] = core::array::from_fn(|i| reports[(i + 1) * 10]);
+        assert_eq!(reports.iter().flatten().count(), 10);
+        for s in sent {
+            // Counts and sums restart after each report
+            assert_eq!(
+                s,
+                Some(Stats {
+                    count: 10,
+                    sum: 100,
+                    sumsq: 10 * (10 * 10u32).div_ceil(STATS_SUMSQ_BASE),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn sumsq_matches_klipper() {
+        // Squared, for short iterations
+        assert_eq!(sumsq_of(0), 0);
+        assert_eq!(sumsq_of(1), 1);
+        assert_eq!(sumsq_of(0x100), 0x100);
+        assert_eq!(sumsq_of(0xFFFF), 16776705);
+        // Scaled before multiplying, for longer ones
+        assert_eq!(sumsq_of(0x10000), 0x100 * 0x10000);
+        assert_eq!(sumsq_of(0xFFFFF), 0x1000 * 0xFFFFF);
+        // Saturated beyond that
+        assert_eq!(sumsq_of(0x100000), u32::MAX);
+
+        // The sum saturates too, each iteration here fits on its own
+        let mut stats = StatsReporter::new(0x200000);
+        stats.tick(0);
+        assert_eq!(stats.tick(0xFFFFF), None);
+        assert_eq!(stats.tick(0x1FFFFE), None);
+        assert_eq!(stats.tick(0x200000).unwrap().sumsq, u32::MAX);
+    }
+
+    #[test]
+    fn clock_wraps_past_u32_max() {
+        let mut stats = StatsReporter::new(100);
+        let start = u32::MAX - 50;
+        stats.tick(start);
+        assert_eq!(stats.tick(start.wrapping_add(60)), None);
+        let s = stats.tick(start.wrapping_add(100)).unwrap();
+        assert_eq!((s.count, s.sum), (2, 100));
+        assert_eq!(
+            s.sumsq,
+            (60 * 60u32).div_ceil(256) + (40 * 40u32).div_ceil(256)
+        );
+    }
+}
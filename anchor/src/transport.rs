@@ -1,6 +1,8 @@
+use crate::crc::crc16;
 use crate::encoding::*;
+use crate::fifo_buffer::FifoBuffer;
 use crate::input_buffer::InputBuffer;
-use crate::output_buffer::OutputBuffer;
+use crate::output_buffer::{OutputBuffer, SliceOutput};
 use crate::transport_output::TransportOutput;
 use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
@@ -16,20 +18,219 @@ const MESSAGE_VALUE_SYNC: u8 = 0x7E;
 const MESSAGE_DEST: u8 = 0x10;
 const MESSAGE_SEQ_MASK: u8 = 0x0F;
 
-fn crc16(buf: &[u8]) -> u16 {
-    let mut crc = 0xFFFFu16;
-    for b in buf {
-        let b = *b ^ ((crc & 0xFF) as u8);
-        let b = b ^ (b << 4);
-        let b16 = b as u16;
-        crc = (b16 << 8 | crc >> 8) ^ (b16 >> 4) ^ (b16 << 3);
+/// The maximum number of payload bytes in a single message
+pub const MESSAGE_PAYLOAD_MAX: usize = MESSAGE_LENGTH_MAX - MESSAGE_LENGTH_MIN;
+
+/// Error returned when a message could not be sent
+///
+/// The encoded message did not fit in a single frame, and was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError;
+
+/// Error returned when a frame could not be encoded into a buffer
+///
+/// The frame didn't fit in the buffer, or exceeded the maximum frame size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityError;
+
+// Writes a frame with the given sequence byte, with its contents written by `f`
+//
+// An oversized frame would either be cut short by the output or carry a wrapped length byte. The
+// host can't decode it either way, so it is dropped instead of sending garbage.
+fn write_frame<O: OutputBuffer>(
+    output: &mut O,
+    seq: u8,
+    f: impl FnOnce(&mut O),
+) -> Result<(), SendError> {
+    let cursor = output.cur_position();
+    output.crc_start();
+    output.output(&[0, seq]); // Output header
+    f(output); // Output actual frame contents
+    let len = output.data_since(cursor).len() + MESSAGE_TRAILER_SIZE;
+    if len > MESSAGE_LENGTH_MAX {
+        log_warn!("anchor: dropped oversized frame of {=usize} bytes", len);
+        output.truncate(cursor);
+        return Err(SendError);
     }
-    crc
+    output.update(cursor, len as u8);
+    let crc = output
+        .crc_result()
+        .unwrap_or_else(|| crc16(output.data_since(cursor)));
+    output.output(&[
+        ((crc & 0xFF00) >> 8) as u8,
+        (crc & 0xFF) as u8,
+        MESSAGE_VALUE_SYNC,
+    ]);
+    Ok(())
+}
+
+// Builds an ACK/NAK frame from the raw sequence byte
+fn acknak(seq: u8) -> [u8; MESSAGE_LENGTH_MIN] {
+    let crc = crc16(&[MESSAGE_LENGTH_MIN as u8, seq]);
+    [
+        MESSAGE_LENGTH_MIN as u8,
+        seq,
+        ((crc & 0xFF00) >> 8) as u8,
+        (crc & 0xFF) as u8,
+        MESSAGE_VALUE_SYNC,
+    ]
+}
+
+/// Builds the ACK/NAK frame requesting `next_sequence` (0-15) as the next frame
+///
+/// For host side tools and tests driving the protocol from the other end.
+#[cfg(feature = "std")]
+pub fn acknak_frame(next_sequence: u8) -> [u8; 5] {
+    acknak((next_sequence & MESSAGE_SEQ_MASK) | MESSAGE_DEST)
+}
+
+/// Parses an ACK/NAK frame at the start of `data`, returning the next sequence (0-15) requested
+///
+/// Returns `None` if `data` doesn't start with a valid ACK/NAK frame. Every frame sent by the
+/// MCU acknowledges received frames, but only ACK/NAK frames carry no payload.
+#[cfg(feature = "std")]
+pub fn parse_acknak(data: &[u8]) -> Option<u8> {
+    let frame = data.get(..MESSAGE_LENGTH_MIN)?;
+    let seq = frame[MESSAGE_POSITION_SEQ];
+    (seq & !MESSAGE_SEQ_MASK == MESSAGE_DEST && frame == acknak(seq))
+        .then_some(seq & MESSAGE_SEQ_MASK)
+}
+
+// Result of scanning for the next frame
+pub(crate) enum Framing<'a> {
+    // A complete, CRC-valid frame including header and trailer
+    Frame(&'a [u8]),
+    // A sync byte was found after a framing error
+    Synchronized,
+    // No complete frame is left in the data
+    Incomplete,
+}
+
+// Result of checking the header of the frame at the front of the data
+enum FrameHeader {
+    // More data is needed to tell
+    Incomplete,
+    // The length byte is out of range
+    BadLength(usize),
+    // The sequence byte has the wrong destination bits
+    BadDestination(u8),
+    // All `len` bytes of the frame are present, but not yet checked
+    Complete(usize),
+}
+
+// Checks the header of the frame at the front of `data`, which must not start with a sync byte
+fn check_header(data: &[u8]) -> FrameHeader {
+    if data.len() < MESSAGE_LENGTH_MIN {
+        return FrameHeader::Incomplete;
+    }
+
+    // A frame of exactly `MESSAGE_LENGTH_MIN` bytes has an empty payload. This is valid, and is
+    // sequenced and acknowledged like any other frame, which lets the remote end use it to
+    // request an ACK. Shorter lengths can't hold the header and trailer, and are treated as a
+    // framing error.
+    let len = data[MESSAGE_POSITION_LENGTH] as usize;
+    if !(MESSAGE_LENGTH_MIN..=MESSAGE_LENGTH_MAX).contains(&len) {
+        return FrameHeader::BadLength(len);
+    }
+
+    let seq = data[MESSAGE_POSITION_SEQ];
+    if seq & !MESSAGE_SEQ_MASK != MESSAGE_DEST {
+        return FrameHeader::BadDestination(seq);
+    }
+    if data.len() < len {
+        return FrameHeader::Incomplete;
+    }
+    FrameHeader::Complete(len)
+}
+
+// Returns true if `next_frame` would return a frame or discard data
+//
+// Only the header and trailer sync byte are checked, a frame failing its CRC check is discarded
+// instead. Leading sync bytes alone don't count, they are skipped along with the next frame.
+pub(crate) fn has_frame(mut data: &[u8], synchronized: bool) -> bool {
+    if !synchronized {
+        // Everything up to the next sync byte is discarded
+        return !data.is_empty();
+    }
+    while let [MESSAGE_VALUE_SYNC, rest @ ..] = data {
+        data = rest;
+    }
+    !matches!(check_header(data), FrameHeader::Incomplete)
+}
+
+// Splits the next frame off the front of `data`
+//
+// Invalid data is skipped, clearing `synchronized` until the next sync byte. When a partial frame
+// is left, `data` still starts at it, so the caller can keep it until more data arrives.
+pub(crate) fn next_frame<'a>(data: &mut &'a [u8], synchronized: &mut bool) -> Framing<'a> {
+    while !data.is_empty() {
+        if !*synchronized {
+            // Look for a sync byte
+            if let Some(n) = data.iter().position(|b| *b == MESSAGE_VALUE_SYNC) {
+                *data = &data[n + 1..];
+                *synchronized = true;
+                return Framing::Synchronized;
+            } else {
+                *data = &[];
+            }
+        } else {
+            if data[0] == MESSAGE_VALUE_SYNC {
+                *data = &data[1..];
+                continue;
+            }
+
+            let len = match check_header(data) {
+                FrameHeader::Incomplete => break,
+                FrameHeader::BadLength(_len) => {
+                    log_warn!("anchor: invalid frame length {=usize}", _len);
+                    *synchronized = false;
+                    continue;
+                }
+                FrameHeader::BadDestination(_seq) => {
+                    log_warn!("anchor: invalid frame destination {=u8:#x}", _seq);
+                    *synchronized = false;
+                    continue;
+                }
+                FrameHeader::Complete(len) => len,
+            };
+            if data[len - MESSAGE_TRAILER_SYNC] != MESSAGE_VALUE_SYNC {
+                log_warn!("anchor: missing frame trailer sync");
+                *synchronized = false;
+                continue;
+            }
+
+            let frame_crc = ((data[len - MESSAGE_TRAILER_CRC] as u16) << 8)
+                | (data[len - MESSAGE_TRAILER_CRC + 1] as u16);
+            let actual_crc = crc16(&data[0..len - MESSAGE_TRAILER_SIZE]);
+            if frame_crc != actual_crc {
+                log_warn!(
+                    "anchor: frame CRC mismatch, got {=u16:#x}, expected {=u16:#x}",
+                    frame_crc,
+                    actual_crc
+                );
+                *synchronized = false;
+                continue;
+            }
+
+            let frame = &data[..len];
+            *data = &data[len..];
+            return Framing::Frame(frame);
+        }
+    }
+    Framing::Incomplete
 }
 
 pub trait Config {
     type TransportOutput: TransportOutput;
     type Context<'c>;
+    /// Returns true if `cmd` is only accepted before the configuration is finalized
+    fn is_config_only(_cmd: u16) -> bool {
+        false
+    }
+    /// Returns true if `cmd` is not accepted while shut down
+    fn is_rejected_in_shutdown(_cmd: u16) -> bool {
+        false
+    }
     fn dispatch<'c>(
         cmd: u16,
         frame: &mut &[u8],
@@ -38,117 +239,419 @@ pub trait Config {
 }
 
 /// Protocol transport implementation
+///
+/// `receive` must only be driven from a single context at a time, typically the main loop.
+/// Messages may be sent from any context, as long as the `TransportOutput` supports this.
+///
+/// Replies can thus be sent from interrupt handlers while the main loop is sending too. Each
+/// frame is built within a single `TransportOutput::output` call, and only reads the sequence
+/// from the transport state, so frames stay whole as long as the output emits each call as a
+/// unit, see [`TransportOutput::output`]. [`BufferedTransportOutput`] and
+/// [`FrameRingTransportOutput`] do, given a mutex that excludes interrupts, e.g. the
+/// `critical-section` one. An acknowledgement may however be sent twice when a reply from an
+/// interrupt races the main loop, which the host ignores.
+///
+/// [`BufferedTransportOutput`]: crate::BufferedTransportOutput
+/// [`FrameRingTransportOutput`]: crate::FrameRingTransportOutput
+///
+/// The transport state is kept in atomics so the transport can live in a `static`. Each atomic is
+/// an independent value that does not guard any other data, so all accesses use `Relaxed`
+/// ordering. Since a single context updates the state, no read-modify-write sequences can race,
+/// and avoiding full barriers keeps the receive path cheap on small cores.
 pub struct Transport<C: Config + 'static> {
     is_synchronized: AtomicBool,
+    is_configured: AtomicBool,
+    is_shutdown: AtomicBool,
+    defer_acks: AtomicBool,
+    coalesce_acks: AtomicBool,
+    ack_pending: AtomicBool,
     next_sequence: AtomicU8,
     output: C::TransportOutput,
+    #[cfg(feature = "critical-section")]
+    feed_buffer: critical_section::Mutex<core::cell::RefCell<FifoBuffer<MESSAGE_LENGTH_MAX>>>,
 }
 
 impl<C: Config> Transport<C> {
     #[doc(hidden)]
     pub const fn new(_config: &'static C, output: C::TransportOutput) -> Self {
+        Self::with_output(output)
+    }
+
+    /// Creates a transport sending through `output`
+    ///
+    /// Firmware uses the `KLIPPER_TRANSPORT` static generated by `klipper_config_generate!`. This
+    /// constructor needs no `'static` configuration instance, which allows creating short-lived,
+    /// independent transports, e.g. for tests using an in-memory output.
+    pub const fn with_output(output: C::TransportOutput) -> Self {
         Self {
             is_synchronized: AtomicBool::new(true),
+            is_configured: AtomicBool::new(false),
+            is_shutdown: AtomicBool::new(false),
+            defer_acks: AtomicBool::new(false),
+            coalesce_acks: AtomicBool::new(false),
+            ack_pending: AtomicBool::new(false),
             next_sequence: AtomicU8::new(MESSAGE_DEST),
             output,
+            #[cfg(feature = "critical-section")]
+            feed_buffer: critical_section::Mutex::new(core::cell::RefCell::new(FifoBuffer::new())),
         }
     }
 
-    /// Decodes messages from an `InputBuffer`
-    pub fn receive<'c>(&self, input: &mut impl InputBuffer, mut context: C::Context<'c>) {
-        // Drive state machine forward until we either have no
-        // input or know we don't have enough input.
-        let mut data = input.data();
-        while !data.is_empty() {
-            if !self.is_synchronized.load(Ordering::SeqCst) {
-                // Look for a sync byte
-                if let Some(n) = data.iter().position(|b| *b == MESSAGE_VALUE_SYNC) {
-                    data = &data[n + 1..];
-                    self.is_synchronized.store(true, Ordering::SeqCst);
-                    self.encode_acknak();
-                } else {
-                    data = &[];
-                }
-            } else {
-                if data[0] == MESSAGE_VALUE_SYNC {
-                    data = &data[1..];
-                    continue;
-                }
+    /// Makes the transport start unsynchronized, see `set_synchronized`
+    ///
+    /// For transports constructed in a `static`, e.g.
+    /// `Transport::with_output(&OUTPUT).start_unsynchronized()`.
+    pub const fn start_unsynchronized(mut self) -> Self {
+        self.is_synchronized = AtomicBool::new(false);
+        self
+    }
 
-                if data.len() < MESSAGE_LENGTH_MIN {
-                    break;
-                }
+    /// Returns the output messages are sent through
+    pub fn output(&self) -> &C::TransportOutput {
+        &self.output
+    }
 
-                let len = data[MESSAGE_POSITION_LENGTH] as usize;
-                if !(MESSAGE_LENGTH_MIN..=MESSAGE_LENGTH_MAX).contains(&len) {
-                    self.is_synchronized.store(false, Ordering::SeqCst);
-                    continue;
-                }
+    /// Returns the maximum number of payload bytes that fit in a single message
+    ///
+    /// This is [`MESSAGE_PAYLOAD_MAX`], derived from the maximum message length minus the header and
+    /// trailer, and is the largest command block the host will send in one message.
+    pub const fn max_payload(&self) -> usize {
+        MESSAGE_PAYLOAD_MAX
+    }
 
-                let seq = data[MESSAGE_POSITION_SEQ];
-                if seq & !MESSAGE_SEQ_MASK != MESSAGE_DEST {
-                    self.is_synchronized.store(false, Ordering::SeqCst);
-                    continue;
-                }
-                if data.len() < len {
-                    break;
-                }
-                if data[len - MESSAGE_TRAILER_SYNC] != MESSAGE_VALUE_SYNC {
-                    self.is_synchronized.store(false, Ordering::SeqCst);
-                    continue;
-                }
+    /// Sets the receive synchronization state
+    ///
+    /// A new transport assumes it is synchronized, and treats the first received byte as the
+    /// start of a frame. On links that may carry line noise when coming up, e.g. a UART at
+    /// power-on, call this with `false` before the first `receive`, or construct the transport
+    /// with `start_unsynchronized`. Incoming data is then discarded until a sync byte is seen, so
+    /// only frames sent after it are processed.
+    pub fn set_synchronized(&self, synchronized: bool) {
+        self.is_synchronized.store(synchronized, Ordering::Relaxed);
+    }
 
-                let frame_crc = ((data[len - MESSAGE_TRAILER_CRC] as u16) << 8)
-                    | (data[len - MESSAGE_TRAILER_CRC + 1] as u16);
-                let actual_crc = crc16(&data[0..len - MESSAGE_TRAILER_SIZE]);
-                if frame_crc != actual_crc {
-                    self.is_synchronized.store(false, Ordering::SeqCst);
-                    continue;
-                }
+    /// Returns true if the receiver is synchronized to the frame boundaries
+    pub fn is_synchronized(&self) -> bool {
+        self.is_synchronized.load(Ordering::Relaxed)
+    }
 
-                let frame = &data[MESSAGE_HEADER_SIZE..len - MESSAGE_TRAILER_SIZE];
-                data = &data[len..];
-                if seq == self.next_sequence.load(Ordering::SeqCst) {
-                    self.next_sequence.store(
-                        ((seq + 1) & MESSAGE_SEQ_MASK) | MESSAGE_DEST,
-                        Ordering::SeqCst,
-                    );
-                    let _ = self.parse_frame(frame, &mut context);
+    /// Marks the configuration phase as finished or restarted
+    ///
+    /// Once configured, commands declared with `#[klipper_command(phase = "config")]` are
+    /// rejected and not dispatched. Firmware should call this with `true` from its
+    /// `finalize_config` handler and with `false` from `config_reset`.
+    pub fn set_configured(&self, configured: bool) {
+        self.is_configured.store(configured, Ordering::Relaxed);
+    }
+
+    /// Returns true if the configuration phase has been finished
+    pub fn is_configured(&self) -> bool {
+        self.is_configured.load(Ordering::Relaxed)
+    }
+
+    /// Enters or leaves the shutdown state
+    ///
+    /// While shut down, commands declared with `#[klipper_command(shutdown = "reject")]` are
+    /// rejected and not dispatched. Firmware should call this with `true` from its
+    /// `emergency_stop` handler, or wherever it shuts down, and with `false` from `config_reset`.
+    /// The state should be reported to the host as `is_shutdown` in the `config` reply.
+    pub fn set_shutdown(&self, shutdown: bool) {
+        self.is_shutdown.store(shutdown, Ordering::Relaxed);
+    }
+
+    /// Returns true if in the shutdown state
+    pub fn is_shutdown(&self) -> bool {
+        self.is_shutdown.load(Ordering::Relaxed)
+    }
+
+    /// Checks that a received command `cmd` may be dispatched
+    ///
+    /// Returns `ReadError::Rejected` for config phase only commands once configured, and for
+    /// commands rejected in shutdown while shut down. `receive` applies this to every command.
+    pub fn check_command(&self, cmd: u16) -> Result<(), ReadError> {
+        if C::is_config_only(cmd) && self.is_configured() {
+            log_warn!(
+                "anchor: config command {=u16} rejected after configuration",
+                cmd
+            );
+            return Err(ReadError::Rejected);
+        }
+        if C::is_rejected_in_shutdown(cmd) && self.is_shutdown() {
+            log_warn!("anchor: command {=u16} rejected in shutdown", cmd);
+            return Err(ReadError::Rejected);
+        }
+        Ok(())
+    }
+
+    /// Enables or disables deferred acknowledgements
+    ///
+    /// Every message sent to the host carries the next expected sequence number, and thus also
+    /// acknowledges received frames. With deferred acks, no separate ACK message is sent when a
+    /// frame is received. Instead, the acknowledgement is carried by the next reply, or sent by
+    /// `poll_output` if no reply was sent in the meantime. This reduces the number of small
+    /// packets on slow links.
+    ///
+    /// `poll_output` must be called regularly, typically right after `receive`, as the host will
+    /// retransmit frames that are not acknowledged in time.
+    pub fn set_deferred_acks(&self, defer: bool) {
+        self.defer_acks.store(defer, Ordering::Relaxed);
+        if !defer {
+            self.poll_output();
+        }
+    }
+
+    /// Enables or disables coalescing acknowledgements into replies
+    ///
+    /// Without this, a separate ACK message is sent for every received frame, even if a reply
+    /// sent while handling it already acknowledged it. With coalesced acks, the ACK is only sent
+    /// when handling the frame sent no message. This roughly halves the number of packets for
+    /// request/response traffic, most notably the `identify` requests transferring the
+    /// dictionary at startup.
+    ///
+    /// Unlike deferred acks, no `poll_output` calls are needed, as the ACK is sent right after
+    /// the frame was handled.
+    pub fn set_coalesced_acks(&self, coalesce: bool) {
+        self.coalesce_acks.store(coalesce, Ordering::Relaxed);
+    }
+
+    /// Returns true if `receive` has work to do on the data in `input`
+    ///
+    /// This is a cheap check of the frame header, meant for power sensitive firmware that would
+    /// rather sleep than call `receive` on a partial frame. It returns true if a complete frame
+    /// is buffered, and also for invalid data, which `receive` has to discard. The CRC isn't
+    /// checked, so a frame may still turn out to be corrupt.
+    pub fn has_complete_frame(&self, input: &impl InputBuffer) -> bool {
+        has_frame(input.data(), self.is_synchronized.load(Ordering::Relaxed))
+    }
+
+    /// Sends any deferred acknowledgement
+    ///
+    /// Does nothing if deferred acks are disabled, or if a reply already acknowledged all
+    /// received frames.
+    pub fn poll_output(&self) {
+        if self.ack_pending.load(Ordering::Relaxed) {
+            self.ack_pending.store(false, Ordering::Relaxed);
+            self.encode_acknak();
+        }
+    }
+
+    /// Decodes messages from an `InputBuffer`
+    ///
+    /// All complete frames, and any data that can't start a frame, are removed from the front of
+    /// `input` with `pop`. Any incomplete frame is left in the buffer, to be completed by data
+    /// received later, so the caller only needs to append incoming data.
+    ///
+    /// Frames are only dispatched in sequence order. A frame with any other sequence number is
+    /// dropped and answered with a NAK carrying the expected sequence, upon which the host
+    /// retransmits everything from there. This matches the Klipper firmware, and Klippy relies on
+    /// it: the host may send several frames ahead, but an acknowledgement always covers all
+    /// frames before the sequence it carries, so there is no way to accept a later frame
+    /// without the ones before it.
+    ///
+    /// A frame with an empty payload holds no commands, but is sequenced and acknowledged like
+    /// any other, so the host can use one to request an ACK. A length byte too small for the
+    /// header and trailer is a framing error. Data is then discarded up to the next sync byte,
+    /// and a NAK with the expected sequence is sent once synchronized again.
+    ///
+    /// Commands are not length delimited, so the arguments of an unknown command can't be
+    /// skipped. An unknown command therefore drops the rest of its frame, including any valid
+    /// commands following it. Klippy only sends commands listed in the dictionary, so this only
+    /// happens with mismatched dictionaries. `ConfigBuilder::report_unknown_commands` makes such
+    /// cases visible.
+    pub fn receive<'c>(&self, input: &mut impl InputBuffer, context: C::Context<'c>) {
+        self.receive_limited(input, context, usize::MAX);
+    }
+
+    /// Decodes messages from an `InputBuffer`, handling at most `max_frames` frames
+    ///
+    /// Works like `receive`, but stops after `max_frames` frames, leaving the rest in `input`.
+    /// This bounds the time spent in a single call when many frames arrived at once, so the main
+    /// loop can interleave other work:
+    /// ```ignore
+    /// while KLIPPER_TRANSPORT.receive_limited(&mut rx, &mut state, 4) {
+    ///     service_steppers();
+    /// }
+    /// ```
+    /// Returns true if `input` holds more data to handle, as reported by `has_complete_frame`.
+    /// Every frame counts towards the limit, including those dropped as out of sequence.
+    pub fn receive_limited<'c>(
+        &self,
+        input: &mut impl InputBuffer,
+        mut context: C::Context<'c>,
+        max_frames: usize,
+    ) -> bool {
+        self.receive_frames(input, &mut context, max_frames)
+    }
+
+    /// Decodes messages from raw received bytes, buffering partial frames internally
+    ///
+    /// This is the simplest way to hook up the receive path, as no receive buffer needs to be
+    /// managed. Pass the bytes as they arrive, in chunks of any size:
+    /// ```ignore
+    /// let n = uart.read(&mut buf);
+    /// KLIPPER_TRANSPORT.feed(&buf[..n], &mut state);
+    /// ```
+    /// The transport keeps up to one frame of received data between calls. Like `receive`, this
+    /// must only be driven from a single context at a time, and must not be mixed with `receive`.
+    ///
+    /// Only available with the `critical-section` feature, which guards the internal buffer.
+    #[cfg(feature = "critical-section")]
+    pub fn feed<'c>(&self, mut data: &[u8], mut context: C::Context<'c>) {
+        // The buffer is moved out, so the commands aren't dispatched inside a critical section
+        let mut rx =
+            critical_section::with(|cs| self.feed_buffer.borrow(cs).replace(FifoBuffer::new()));
+        while !data.is_empty() {
+            let free = rx.receive_buffer();
+            let n = free.len().min(data.len());
+            free[..n].copy_from_slice(&data[..n]);
+            rx.advance(n);
+            data = &data[n..];
+            self.receive_frames(&mut rx, &mut context, usize::MAX);
+        }
+        critical_section::with(|cs| self.feed_buffer.borrow(cs).replace(rx));
+    }
+
+    fn receive_frames<'c>(
+        &self,
+        input: &mut impl InputBuffer,
+        context: &mut C::Context<'c>,
+        max_frames: usize,
+    ) -> bool {
+        // Drive state machine forward until we either have no
+        // input or know we don't have enough input.
+        let mut data = input.data();
+        let mut synchronized = self.is_synchronized.load(Ordering::Relaxed);
+        let mut frames = 0;
+        while frames < max_frames {
+            match next_frame(&mut data, &mut synchronized) {
+                Framing::Incomplete => break,
+                Framing::Synchronized => {
+                    self.is_synchronized.store(true, Ordering::Relaxed);
+                    self.request_acknak();
+                }
+                Framing::Frame(frame) => {
+                    frames += 1;
+                    self.is_synchronized.store(true, Ordering::Relaxed);
+                    let seq = frame[MESSAGE_POSITION_SEQ];
+                    let frame = &frame[MESSAGE_HEADER_SIZE..frame.len() - MESSAGE_TRAILER_SIZE];
+                    if seq == self.next_sequence.load(Ordering::Relaxed) {
+                        self.next_sequence.store(
+                            ((seq + 1) & MESSAGE_SEQ_MASK) | MESSAGE_DEST,
+                            Ordering::Relaxed,
+                        );
+                        let defer = self.defer_acks.load(Ordering::Relaxed);
+                        if defer || self.coalesce_acks.load(Ordering::Relaxed) {
+                            // Mark the ack as pending before dispatching, so that any reply sent
+                            // by the handlers carries the acknowledgement instead.
+                            self.ack_pending.store(true, Ordering::Relaxed);
+                            let _ = self.parse_frame(frame, context);
+                            if !defer {
+                                self.poll_output();
+                            }
+                            continue;
+                        }
+                        let _ = self.parse_frame(frame, context);
+                    } else {
+                        // Not buffered for later, see the notes on in-order delivery above
+                        log_debug!(
+                            "anchor: out of sequence frame {=u8}, expected {=u8}",
+                            seq & MESSAGE_SEQ_MASK,
+                            self.next_sequence.load(Ordering::Relaxed) & MESSAGE_SEQ_MASK
+                        );
+                    }
+                    self.request_acknak();
                 }
-                self.encode_acknak();
             }
         }
-        // Remove consumed bytes from front
-        let consumed = input.available() - data.len();
+        self.is_synchronized.store(synchronized, Ordering::Relaxed);
+        // Remove consumed bytes from front. `data` is a suffix of `input.data()`, so this is
+        // computed from the same slice rather than from `available`, which may be overridden.
+        let consumed = input.data().len() - data.len();
         if consumed > 0 {
             input.pop(consumed);
         }
+        has_frame(input.data(), synchronized)
+    }
+
+    /// Decodes messages from a `FifoBuffer`, removing the consumed data
+    ///
+    /// This implements the common main loop pattern of receiving into a `FifoBuffer` and passing
+    /// the buffered data to `receive`. Any incomplete frame is left in the buffer, to be completed
+    /// by data received later. Equivalent to passing the buffer to `receive` directly.
+    pub fn pump_once<'c, const BUF_SIZE: usize>(
+        &self,
+        rx: &mut FifoBuffer<BUF_SIZE>,
+        context: C::Context<'c>,
+    ) {
+        if rx.is_empty() {
+            return;
+        }
+        self.receive(rx, context);
     }
 
+    // Parses and dispatches all commands in a frame payload.
+    //
+    // Commands are dispatched in order as they are decoded:
+    //   * An empty payload contains no commands and is accepted as a no-op.
+    //   * A command id with truncated arguments is not dispatched, and `ReadError` is returned.
+    //   * Trailing bytes after a valid command are decoded as a new command. Commands preceding
+    //     the garbage have already been dispatched when the resulting `ReadError` is returned.
+    //   * A config phase only command received after configuration is not dispatched, and
+    //     `ReadError` is returned.
+    //   * A command rejected in shutdown received while shut down is not dispatched, and
+    //     `ReadError` is returned.
+    //   * An unknown command id returns `ReadError`, and the commands following it in the same
+    //     frame are dropped. The frame is acknowledged, so the host doesn't retransmit them.
+    //
+    // Commands carry no length of their own, the end of a command is wherever its handler
+    // stopped decoding arguments. Generated handlers check that arguments with a custom
+    // `Readable`, e.g. `KlipperTransparent` newtypes, consume exactly the bytes of their wire
+    // type. A mismatch is reported as `ReadError` before the handler is called, and the rest of
+    // the block is dropped rather than decoded from the wrong offset.
     fn parse_frame<'c>(
         &self,
         mut frame: &[u8],
         context: &mut C::Context<'c>,
     ) -> Result<(), ReadError> {
-        while !frame.is_empty() {
-            let cmd = <u16 as Readable>::read(&mut frame)?;
-            C::dispatch(cmd, &mut frame, context)?;
+        if frame.is_empty() {
+            return Ok(());
+        }
+        loop {
+            let cmd = <u16 as Readable>::read(&mut frame).inspect_err(|_| {
+                log_warn!("anchor: truncated command id");
+            })?;
+            self.check_command(cmd)?;
+            C::dispatch(cmd, &mut frame, context).inspect_err(|_e| {
+                #[cfg(feature = "defmt")]
+                if let ReadError::InvalidEnumValue { name, value } = _e {
+                    log_warn!(
+                        "anchor: command {=u16}: invalid value {=u32} for {=str}",
+                        cmd,
+                        value,
+                        name
+                    );
+                    return;
+                }
+                log_warn!("anchor: command {=u16} unknown or truncated", cmd);
+            })?;
+            if frame.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn request_acknak(&self) {
+        if self.defer_acks.load(Ordering::Relaxed) {
+            self.ack_pending.store(true, Ordering::Relaxed);
+        } else {
+            self.encode_acknak();
         }
-        Ok(())
     }
 
     // Fast path for ACK/NAK
     fn encode_acknak(&self) {
         self.output.output(|output| {
-            let ns = self.next_sequence.load(Ordering::SeqCst);
-            let crc = crc16(&[5, ns]);
-            output.output(&[
-                5,
-                ns,
-                ((crc & 0xFF00) >> 8) as u8,
-                (crc & 0xFF) as u8,
-                MESSAGE_VALUE_SYNC,
-            ]);
+            output.output(&acknak(self.next_sequence.load(Ordering::Relaxed)));
         });
     }
 
@@ -156,21 +659,689 @@ impl<C: Config> Transport<C> {
     pub fn encode_frame(
         &self,
         f: impl FnOnce(&mut <<C as Config>::TransportOutput as TransportOutput>::Output),
-    ) {
+    ) -> Result<(), SendError> {
+        let mut result = Ok(());
         self.output.output(|output| {
-            let cursor = output.cur_position();
-            output.output(&[0, self.next_sequence.load(Ordering::SeqCst)]); // Output header
-            f(output); // Output actual frame contents
-            {
-                let changed = output.data_since(cursor).len();
-                output.update(cursor, (changed + MESSAGE_TRAILER_SIZE) as u8);
+            result = write_frame(output, self.next_sequence.load(Ordering::Relaxed), f);
+            if result.is_ok() {
+                // The frame carries the next sequence, acknowledging everything received so far
+                self.ack_pending.store(false, Ordering::Relaxed);
+            }
+        });
+        result
+    }
+
+    /// Encodes a frame into `buffer` instead of the configured output, returning its length
+    ///
+    /// The frame is built like the ones sent by `klipper_reply!`, with `f` writing the messages,
+    /// e.g. to forward them over another medium:
+    /// ```ignore
+    /// let mut buf = [0u8; 64];
+    /// let len = KLIPPER_TRANSPORT.encode_frame_to(
+    ///     |out| {
+    ///         REPLY_ID.write(out);
+    ///         value.write(out);
+    ///     },
+    ///     &mut buf,
+    /// )?;
+    /// forward(&buf[..len]);
+    /// ```
+    /// Nothing is sent on the configured output. The frame carries the current sequence, but
+    /// doesn't count as acknowledging the received frames.
+    ///
+    /// Fails if the frame doesn't fit in `buffer` or exceeds the maximum frame size, a 64 byte
+    /// buffer fits any frame.
+    pub fn encode_frame_to(
+        &self,
+        f: impl FnOnce(&mut SliceOutput),
+        buffer: &mut [u8],
+    ) -> Result<usize, CapacityError> {
+        let mut output = SliceOutput::new(buffer);
+        write_frame(&mut output, self.next_sequence.load(Ordering::Relaxed), f)
+            .map_err(|_| CapacityError)?;
+        if output.overflowed() {
+            return Err(CapacityError);
+        }
+        Ok(output.result().len())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::input_buffer::SliceInputBuffer;
+    use crate::output_buffer::ScratchOutput;
+    use std::{cell::RefCell, vec, vec::Vec};
+
+    /// Wraps `payload` in a frame with sequence number `seq` (0-15)
+    pub(crate) fn frame(seq: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![
+            (payload.len() + MESSAGE_LENGTH_MIN) as u8,
+            MESSAGE_DEST | seq,
+        ];
+        frame.extend_from_slice(payload);
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame.push(MESSAGE_VALUE_SYNC);
+        frame
+    }
+
+    /// Collects the frames sent through it
+    #[derive(Default)]
+    struct TestOutput(RefCell<Vec<Vec<u8>>>);
+
+    impl TransportOutput for TestOutput {
+        type Output = ScratchOutput;
+        fn output(&self, f: impl FnOnce(&mut Self::Output)) {
+            let mut scratch = ScratchOutput::new();
+            f(&mut scratch);
+            if !scratch.result().is_empty() {
+                self.0.borrow_mut().push(scratch.result().to_vec());
+            }
+        }
+    }
+
+    /// `set value=%u`
+    const SET: u8 = 10;
+    /// `ping`
+    const PING: u8 = 11;
+    /// `configure`, only accepted during the configuration phase
+    const CONFIGURE: u8 = 12;
+    /// `step`, rejected in shutdown
+    const STEP: u8 = 13;
+
+    /// Records the dispatched commands with their argument in the context
+    struct TestConfig;
+
+    impl Config for TestConfig {
+        type TransportOutput = TestOutput;
+        type Context<'c> = &'c mut Vec<(u8, u32)>;
+
+        fn is_config_only(cmd: u16) -> bool {
+            cmd == CONFIGURE as u16
+        }
+
+        fn is_rejected_in_shutdown(cmd: u16) -> bool {
+            cmd == STEP as u16
+        }
+
+        fn dispatch<'c>(
+            cmd: u16,
+            frame: &mut &[u8],
+            context: &mut Self::Context<'c>,
+        ) -> Result<(), ReadError> {
+            let cmd = cmd as u8;
+            let arg = match cmd {
+                SET => u32::read(frame)?,
+                PING | CONFIGURE | STEP => 0,
+                _ => return Err(ReadError::UnknownCommand),
+            };
+            context.push((cmd, arg));
+            Ok(())
+        }
+    }
+
+    fn transport() -> Transport<TestConfig> {
+        Transport::with_output(TestOutput::default())
+    }
+
+    /// Parses `payload` as the payload of a frame, returning the result and dispatched commands
+    fn parse(
+        transport: &Transport<TestConfig>,
+        payload: &[u8],
+    ) -> (Result<(), ReadError>, Vec<(u8, u32)>) {
+        let mut commands = Vec::new();
+        let result = transport.parse_frame(payload, &mut &mut commands);
+        (result, commands)
+    }
+
+    /// `echo value=%u`, replied to with `echoed value=%u`
+    const ECHO: u8 = 20;
+    const ECHOED: u8 = 21;
+    /// `quiet`, sending no reply
+    const QUIET: u8 = 22;
+
+    /// Replies to `echo` through the transport passed as context
+    struct EchoConfig;
+
+    impl Config for EchoConfig {
+        type TransportOutput = TestOutput;
+        type Context<'c> = &'c Transport<EchoConfig>;
+
+        fn dispatch<'c>(
+            cmd: u16,
+            frame: &mut &[u8],
+            context: &mut Self::Context<'c>,
+        ) -> Result<(), ReadError> {
+            match cmd as u8 {
+                ECHO => {
+                    let value = u32::read(frame)?;
+                    let _ = context.encode_frame(|out| {
+                        out.output(&[ECHOED]);
+                        value.write(out);
+                    });
+                }
+                QUIET => {}
+                _ => return Err(ReadError::UnknownCommand),
+            }
+            Ok(())
+        }
+    }
+
+    /// Receives `frames` on `transport`
+    fn receive_all(transport: &Transport<EchoConfig>, frames: &[Vec<u8>]) {
+        let data = frames.concat();
+        transport.receive(&mut SliceInputBuffer::new(&data), transport);
+    }
+
+    /// Returns the frames sent since the previous call
+    fn take_sent<C: Config<TransportOutput = TestOutput>>(
+        transport: &Transport<C>,
+    ) -> Vec<Vec<u8>> {
+        transport.output().0.take()
+    }
+
+    #[test]
+    fn parse_frame_matrix() {
+        use ReadError::*;
+        let transport = transport();
+
+        // Empty payload
+        assert_eq!(parse(&transport, &[]), (Ok(()), vec![]));
+        // Single command, with and without arguments
+        assert_eq!(parse(&transport, &[SET, 42]), (Ok(()), vec![(SET, 42)]));
+        assert_eq!(parse(&transport, &[PING]), (Ok(()), vec![(PING, 0)]));
+        // Several commands
+        assert_eq!(
+            parse(&transport, &[SET, 0x81, 0x00, PING, SET, 1]),
+            (Ok(()), vec![(SET, 128), (PING, 0), (SET, 1)])
+        );
+        // Truncated arguments, missing or cut short
+        assert_eq!(parse(&transport, &[SET]), (Err(Truncated), vec![]));
+        assert_eq!(parse(&transport, &[SET, 0x81]), (Err(Truncated), vec![]));
+        assert_eq!(
+            parse(&transport, &[PING, SET]),
+            (Err(Truncated), vec![(PING, 0)])
+        );
+        // Trailing garbage is decoded as a command, after dispatching the ones before it
+        assert_eq!(
+            parse(&transport, &[PING, 0x05, PING]),
+            (Err(UnknownCommand), vec![(PING, 0)])
+        );
+        assert_eq!(
+            parse(&transport, &[SET, 7, 0x81]),
+            (Err(Truncated), vec![(SET, 7)])
+        );
+        // Commands rejected in the current state
+        transport.set_configured(true);
+        assert_eq!(
+            parse(&transport, &[PING, CONFIGURE, PING]),
+            (Err(Rejected), vec![(PING, 0)])
+        );
+        transport.set_shutdown(true);
+        assert_eq!(parse(&transport, &[STEP]), (Err(Rejected), vec![]));
+        transport.set_configured(false);
+        transport.set_shutdown(false);
+        assert_eq!(
+            parse(&transport, &[CONFIGURE, STEP]),
+            (Ok(()), vec![(CONFIGURE, 0), (STEP, 0)])
+        );
+    }
+
+    #[test]
+    fn next_frame_splits_concatenated_frames() {
+        let first = frame(0, &[1, 2, 3]);
+        let second = frame(1, &[]);
+        let third = frame(2, &[4, 5]);
+        let data = [first.as_slice(), &second, &third[..4]].concat();
+
+        let mut rest = data.as_slice();
+        let mut synchronized = true;
+        assert!(
+            matches!(next_frame(&mut rest, &mut synchronized), Framing::Frame(f) if f == first)
+        );
+        assert!(
+            matches!(next_frame(&mut rest, &mut synchronized), Framing::Frame(f) if f == second)
+        );
+        assert!(matches!(
+            next_frame(&mut rest, &mut synchronized),
+            Framing::Incomplete
+        ));
+        // The partial frame is left in place, and stays synchronized
+        assert_eq!(rest, &third[..4]);
+        assert!(synchronized);
+
+        // Once complete, it yields the frame
+        let mut rest = third.as_slice();
+        assert!(
+            matches!(next_frame(&mut rest, &mut synchronized), Framing::Frame(f) if f == third)
+        );
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn empty_frame_is_acked() {
+        let empty = frame(0, &[]);
+        assert_eq!(empty.len(), MESSAGE_LENGTH_MIN);
+        let mut rest = empty.as_slice();
+        let mut synchronized = true;
+        assert!(
+            matches!(next_frame(&mut rest, &mut synchronized), Framing::Frame(f) if f == empty)
+        );
+
+        let transport = transport();
+        let mut commands = Vec::new();
+        transport.receive(&mut SliceInputBuffer::new(&empty), &mut commands);
+        assert!(commands.is_empty());
+        assert_eq!(
+            *transport.output().0.borrow(),
+            vec![acknak(MESSAGE_DEST | 1)]
+        );
+    }
+
+    #[test]
+    fn short_frame_resynchronizes() {
+        // A length of 4 can't hold header and trailer, even with a valid CRC
+        let crc = crc16(&[4, MESSAGE_DEST]);
+        let short = [4, MESSAGE_DEST, (crc >> 8) as u8, MESSAGE_VALUE_SYNC];
+        let valid = frame(0, &[PING]);
+        let data = [&short[..], &valid].concat();
+
+        let mut rest = data.as_slice();
+        let mut synchronized = true;
+        assert!(matches!(
+            next_frame(&mut rest, &mut synchronized),
+            Framing::Synchronized
+        ));
+        // Everything up to and including the sync byte is skipped
+        assert_eq!(rest, valid.as_slice());
+        assert!(synchronized);
+
+        let transport = transport();
+        let mut commands = Vec::new();
+        transport.receive(&mut SliceInputBuffer::new(&data), &mut commands);
+        assert_eq!(commands, vec![(PING, 0)]);
+        let nak = acknak(MESSAGE_DEST);
+        let ack = acknak(MESSAGE_DEST | 1);
+        assert_eq!(*transport.output().0.borrow(), vec![nak, ack]);
+    }
+
+    #[test]
+    fn receive_limited_handles_frames_in_batches() {
+        let data: Vec<u8> = (0..10u8).flat_map(|i| frame(i, &[SET, i])).collect();
+        let transport = transport();
+        let mut commands = Vec::new();
+        let mut input = FifoBuffer::<128>::new();
+        input.extend(&data);
+
+        let mut calls = Vec::new();
+        loop {
+            let more = transport.receive_limited(&mut input, &mut commands, 3);
+            calls.push((commands.len(), more));
+            if !more {
+                break;
+            }
+        }
+        assert_eq!(calls, vec![(3, true), (6, true), (9, true), (10, false)]);
+        assert!(input.is_empty());
+        let expected: Vec<_> = (0..10u8).map(|i| (SET, i as u32)).collect();
+        assert_eq!(commands, expected);
+        assert_eq!(transport.output().0.borrow().len(), 10);
+    }
+
+    #[test]
+    fn has_complete_frame_checks_header_and_sync() {
+        let transport = transport();
+        let complete = frame(0, &[SET, 1]);
+        let has = |data: &[u8]| transport.has_complete_frame(&SliceInputBuffer::new(data));
+
+        assert!(!has(&[]));
+        assert!(!has(&[MESSAGE_VALUE_SYNC, MESSAGE_VALUE_SYNC]));
+        for n in 1..complete.len() {
+            assert!(!has(&complete[..n]), "{} bytes", n);
+        }
+        assert!(has(&complete));
+        assert!(has(&[&[MESSAGE_VALUE_SYNC][..], &complete].concat()));
+
+        // Invalid data has to be discarded by `receive`, so it counts too
+        let mut bad_sync = complete.clone();
+        *bad_sync.last_mut().unwrap() = 0;
+        assert!(has(&bad_sync));
+        assert!(has(&[2, 0, 0, 0, 0]));
+        // The CRC isn't checked
+        let mut bad_crc = complete.clone();
+        bad_crc[2] ^= 1;
+        assert!(has(&bad_crc));
+
+        // While unsynchronized, any data is discarded
+        transport.set_synchronized(false);
+        assert!(has(&complete[..1]));
+        assert!(!has(&[]));
+    }
+
+    #[test]
+    fn encode_frame_to_round_trips_through_receive() {
+        let sender = transport();
+        let mut buffer = [0u8; MESSAGE_LENGTH_MAX];
+        let len = sender
+            .encode_frame_to(|out| out.output(&[SET, 42, PING]), &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer[..len], frame(0, &[SET, 42, PING]));
+        // Nothing went to the configured output
+        assert!(sender.output().0.borrow().is_empty());
+
+        let receiver = transport();
+        let mut commands = Vec::new();
+        receiver.receive(&mut SliceInputBuffer::new(&buffer[..len]), &mut commands);
+        assert_eq!(commands, vec![(SET, 42), (PING, 0)]);
+    }
+
+    #[test]
+    fn encode_frame_to_checks_capacity() {
+        let transport = transport();
+        // Exactly fitting, one byte short, and too large for any frame
+        assert_eq!(
+            transport.encode_frame_to(|out| out.output(&[PING, PING]), &mut [0; 7]),
+            Ok(7)
+        );
+        assert_eq!(
+            transport.encode_frame_to(|out| out.output(&[PING, PING]), &mut [0; 6]),
+            Err(CapacityError)
+        );
+        let payload = [PING; MESSAGE_PAYLOAD_MAX + 1];
+        assert_eq!(
+            transport.encode_frame_to(|out| out.output(&payload), &mut [0; 128]),
+            Err(CapacityError)
+        );
+    }
+
+    #[test]
+    fn oversized_frame_is_dropped() {
+        let transport = transport();
+        let payload = [PING; MESSAGE_PAYLOAD_MAX + 1];
+        assert_eq!(
+            transport.encode_frame(|out| out.output(&payload[..MESSAGE_PAYLOAD_MAX])),
+            Ok(())
+        );
+        assert_eq!(
+            transport.encode_frame(|out| out.output(&payload)),
+            Err(SendError)
+        );
+        let frames = transport.output().0.borrow();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), MESSAGE_LENGTH_MAX);
+
+        // Data written before the frame is kept
+        let mut out = ScratchOutput::<128>::new();
+        out.output(&[1, 2]);
+        let result = write_frame(&mut out, MESSAGE_DEST, |out| out.output(&payload));
+        assert_eq!(result, Err(SendError));
+        assert_eq!(out.result(), &[1, 2]);
+    }
+
+    #[test]
+    fn deferred_acks_combine_with_replies() {
+        let transport = Transport::<EchoConfig>::with_output(TestOutput::default());
+        transport.set_deferred_acks(true);
+
+        // Frames without replies are acknowledged once, by `poll_output`
+        receive_all(&transport, &[frame(0, &[QUIET, QUIET]), frame(1, &[QUIET])]);
+        assert!(take_sent(&transport).is_empty());
+        transport.poll_output();
+        transport.poll_output();
+        assert_eq!(take_sent(&transport), vec![acknak(MESSAGE_DEST | 2)]);
+
+        // A reply carries the acknowledgement in its sequence byte, nothing is left to flush
+        receive_all(&transport, &[frame(2, &[QUIET, ECHO, 5, QUIET])]);
+        transport.poll_output();
+        assert_eq!(take_sent(&transport), vec![frame(3, &[ECHOED, 5])]);
+
+        // Disabling deferred acks flushes a pending one
+        receive_all(&transport, &[frame(3, &[QUIET])]);
+        assert!(take_sent(&transport).is_empty());
+        transport.set_deferred_acks(false);
+        assert_eq!(take_sent(&transport), vec![acknak(MESSAGE_DEST | 4)]);
+    }
+
+    #[test]
+    fn coalesced_acks_halve_identify_traffic() {
+        // Like the `identify` requests fetching the dictionary, each answered by a reply
+        let requests: Vec<_> = (0..8u8).map(|i| frame(i, &[ECHO, i])).collect();
+        let replies: Vec<_> = (0..8u8).map(|i| frame(i + 1, &[ECHOED, i])).collect();
+
+        let separate = Transport::<EchoConfig>::with_output(TestOutput::default());
+        receive_all(&separate, &requests);
+        let sent = take_sent(&separate);
+        assert_eq!(sent.len(), 16);
+        let acks: Vec<_> = sent.iter().skip(1).step_by(2).cloned().collect();
+        let expected: Vec<_> = (1..=8).map(|i| acknak(MESSAGE_DEST | i).to_vec()).collect();
+        assert_eq!(acks, expected);
+
+        let coalesced = Transport::<EchoConfig>::with_output(TestOutput::default());
+        coalesced.set_coalesced_acks(true);
+        receive_all(&coalesced, &requests);
+        assert_eq!(take_sent(&coalesced), replies);
+
+        // A frame without reply is still acknowledged right away
+        receive_all(&coalesced, &[frame(8, &[QUIET])]);
+        assert_eq!(take_sent(&coalesced), vec![acknak(MESSAGE_DEST | 9)]);
+    }
+
+    #[test]
+    fn unsynchronized_start_waits_for_sync() {
+        let transport =
+            Transport::<TestConfig>::with_output(TestOutput::default()).start_unsynchronized();
+        assert!(!transport.is_synchronized());
+        let mut commands = Vec::new();
+
+        // Without a sync byte, even a valid frame is discarded
+        let first = frame(0, &[SET, 1]);
+        let mut input = FifoBuffer::<128>::new();
+        input.extend(&first[..first.len() - 1]);
+        transport.receive(&mut input, &mut commands);
+        assert!(commands.is_empty() && input.is_empty());
+        assert!(!transport.is_synchronized());
+
+        // Its trailing sync byte synchronizes, the next frame is handled
+        input.extend(&[MESSAGE_VALUE_SYNC]);
+        input.extend(&frame(0, &[SET, 2]));
+        transport.receive(&mut input, &mut commands);
+        assert_eq!(commands, vec![(SET, 2)]);
+        assert!(transport.is_synchronized());
+        let nak = acknak(MESSAGE_DEST);
+        assert_eq!(take_sent(&transport), vec![nak, acknak(MESSAGE_DEST | 1)]);
+    }
+
+    #[test]
+    fn out_of_sequence_frame_is_nacked() {
+        let transport = transport();
+        let mut commands = Vec::new();
+
+        // Frame 1 before frame 0, and a repeated frame 0
+        let data = [
+            frame(1, &[SET, 1]),
+            frame(0, &[SET, 0]),
+            frame(0, &[SET, 9]),
+        ]
+        .concat();
+        transport.receive(&mut SliceInputBuffer::new(&data), &mut commands);
+        assert_eq!(commands, vec![(SET, 0)]);
+        let expected = [MESSAGE_DEST, MESSAGE_DEST | 1, MESSAGE_DEST | 1].map(acknak);
+        assert_eq!(take_sent(&transport), expected);
+
+        // The retransmission is accepted
+        transport.receive(
+            &mut SliceInputBuffer::new(&frame(1, &[SET, 1])),
+            &mut commands,
+        );
+        assert_eq!(commands, vec![(SET, 0), (SET, 1)]);
+        assert_eq!(take_sent(&transport), vec![acknak(MESSAGE_DEST | 2)]);
+    }
+
+    #[test]
+    fn transports_keep_separate_state() {
+        let first = transport();
+        let second = transport();
+        let (mut first_commands, mut second_commands) = (Vec::new(), Vec::new());
+
+        let data = [frame(0, &[SET, 1]), frame(1, &[SET, 2])].concat();
+        first.receive(&mut SliceInputBuffer::new(&data), &mut first_commands);
+        first.set_shutdown(true);
+        first.set_configured(true);
+
+        // The second transport still expects frame 0, and isn't shut down or configured
+        second.receive(
+            &mut SliceInputBuffer::new(&frame(1, &[SET, 3])),
+            &mut second_commands,
+        );
+        let data = [frame(0, &[STEP, CONFIGURE]), frame(1, &[SET, 3])].concat();
+        second.receive(&mut SliceInputBuffer::new(&data), &mut second_commands);
+        assert_eq!(second_commands, vec![(STEP, 0), (CONFIGURE, 0), (SET, 3)]);
+        let acks = [MESSAGE_DEST, MESSAGE_DEST | 1, MESSAGE_DEST | 2].map(acknak);
+        assert_eq!(take_sent(&second), acks);
+
+        assert_eq!(first_commands, vec![(SET, 1), (SET, 2)]);
+        let acks = [MESSAGE_DEST | 1, MESSAGE_DEST | 2].map(acknak);
+        assert_eq!(take_sent(&first), acks);
+        assert!(first.is_shutdown() && !second.is_shutdown());
+    }
+
+    #[test]
+    fn max_payload_follows_message_length() {
+        let transport = transport();
+        assert_eq!(
+            transport.max_payload(),
+            MESSAGE_LENGTH_MAX - MESSAGE_LENGTH_MIN
+        );
+        let payload = [PING; MESSAGE_LENGTH_MAX];
+        let mut buffer = [0u8; MESSAGE_LENGTH_MAX + 1];
+        assert_eq!(
+            transport.encode_frame_to(
+                |out| out.output(&payload[..transport.max_payload()]),
+                &mut buffer
+            ),
+            Ok(MESSAGE_LENGTH_MAX)
+        );
+        assert_eq!(
+            transport.encode_frame_to(
+                |out| out.output(&payload[..transport.max_payload() + 1]),
+                &mut buffer
+            ),
+            Err(CapacityError)
+        );
+    }
+
+    #[test]
+    fn single_context_keeps_sequence_across_wraparound() {
+        // `receive` and the replies it sends all run in one context, as documented
+        let transport = Transport::<EchoConfig>::with_output(TestOutput::default());
+        for i in 0..40u8 {
+            let seq = i & MESSAGE_SEQ_MASK;
+            let next = (i + 1) & MESSAGE_SEQ_MASK;
+            receive_all(&transport, &[frame(seq, &[ECHO, i])]);
+            assert_eq!(
+                take_sent(&transport),
+                vec![
+                    frame(next, &[ECHOED, i]),
+                    acknak(MESSAGE_DEST | next).to_vec()
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn pump_once_matches_manual_receive() {
+        let third = frame(2, &[SET, 2]);
+        let data = [frame(0, &[SET, 1]), frame(1, &[PING]), third[..5].to_vec()].concat();
+
+        let pumped = transport();
+        let mut pumped_commands = Vec::new();
+        let mut rx = FifoBuffer::<128>::new();
+        let manual = transport();
+        let mut manual_commands = Vec::new();
+        let mut buffer = FifoBuffer::<128>::new();
+
+        for chunk in [&data[..], &third[5..]] {
+            rx.extend(chunk);
+            pumped.pump_once(&mut rx, &mut pumped_commands);
+
+            buffer.extend(chunk);
+            let recv_data = buffer.data();
+            let mut wrap = SliceInputBuffer::new(recv_data);
+            manual.receive(&mut wrap, &mut manual_commands);
+            let consumed = recv_data.len() - wrap.available();
+            buffer.pop(consumed);
+
+            // Both dispatched the complete frames, and kept only the partial one
+            assert_eq!(pumped_commands, manual_commands);
+            assert_eq!(rx.data(), buffer.data());
+            assert_eq!(*pumped.output().0.borrow(), *manual.output().0.borrow());
+        }
+        assert_eq!(pumped_commands, vec![(SET, 1), (PING, 0), (SET, 2)]);
+        assert!(rx.is_empty());
+        assert_eq!(pumped.output().0.borrow().len(), 3);
+
+        // Nothing to do on an empty buffer
+        pumped.pump_once(&mut rx, &mut pumped_commands);
+        assert_eq!(pumped.output().0.borrow().len(), 3);
+    }
+
+    #[cfg(feature = "critical-section")]
+    #[test]
+    fn feed_dispatches_frames_in_any_chunks() {
+        let payloads: [&[u8]; 4] = [&[SET, 1], &[PING], &[], &[SET, 0x81, 0x00, PING]];
+        let data: Vec<u8> = (0..12)
+            .flat_map(|i| frame(i as u8 & 0xf, payloads[i % payloads.len()]))
+            .collect();
+        let expected: Vec<_> = (0..3)
+            .flat_map(|_| [(SET, 1), (PING, 0), (SET, 128), (PING, 0)])
+            .collect();
+        // The data spans several internal buffers, whole, byte by byte and in odd bursts
+        assert!(data.len() > MESSAGE_LENGTH_MAX);
+        for chunk in [1, 2, 7, MESSAGE_LENGTH_MAX + 3, data.len()] {
+            let transport = transport();
+            let mut commands = Vec::new();
+            for part in data.chunks(chunk) {
+                transport.feed(part, &mut commands);
             }
-            let crc = crc16(output.data_since(cursor));
-            output.output(&[
-                ((crc & 0xFF00) >> 8) as u8,
-                (crc & 0xFF) as u8,
-                MESSAGE_VALUE_SYNC,
-            ]);
-        })
+            assert_eq!(commands, expected, "chunks of {}", chunk);
+            let acks: Vec<_> = (1..=12).map(|i| acknak(MESSAGE_DEST | (i & 0xf))).collect();
+            assert_eq!(*transport.output().0.borrow(), acks, "chunks of {}", chunk);
+        }
+    }
+
+    #[cfg(feature = "critical-section")]
+    #[test]
+    fn feed_keeps_partial_frame() {
+        let transport = transport();
+        let mut commands = Vec::new();
+        let data = [frame(0, &[SET, 5]), frame(1, &[PING])].concat();
+        let (head, tail) = data.split_at(9);
+        transport.feed(head, &mut commands);
+        assert_eq!(commands, vec![(SET, 5)]);
+        transport.feed(tail, &mut commands);
+        assert_eq!(commands, vec![(SET, 5), (PING, 0)]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn receive_acks_with_next_sequence() {
+        let transport = transport();
+        let mut commands = Vec::new();
+        let data = [frame(0, &[PING]), frame(1, &[SET, 3])].concat();
+        transport.receive(&mut SliceInputBuffer::new(&data), &mut commands);
+        assert_eq!(commands, vec![(PING, 0), (SET, 3)]);
+        let acks: Vec<_> = transport
+            .output()
+            .0
+            .borrow()
+            .iter()
+            .map(|f| parse_acknak(f))
+            .collect();
+        assert_eq!(acks, vec![Some(1), Some(2)]);
+        assert_eq!(parse_acknak(&acknak_frame(2)), Some(2));
+        assert_eq!(parse_acknak(&frame(2, &[PING])), None);
     }
 }
@@ -9,6 +9,12 @@ pub trait TransportOutput {
     ///
     /// The `f` callback will be called with an empty `OutputBuffer` that must be filled with the
     /// message to be sent.
+    ///
+    /// The data written by `f` must be emitted as a unit. If the output is used from several
+    /// contexts, e.g. the main loop and an interrupt handler, a call must not interleave its data
+    /// with another call, or the frames are corrupted. Either write to a scratch buffer and append
+    /// it under a lock, like `BufferedTransportOutput`, or hold the lock while `f` runs, like
+    /// `FrameRingTransportOutput`. The lock must exclude all contexts sending messages.
     fn output(&self, f: impl FnOnce(&mut Self::Output));
 }
 
@@ -1,12 +1,17 @@
-use crate::msg_desc::{build_message_descriptor, DescArg};
+use crate::msg_desc::{
+    build_message_descriptor, optional_inner_type, slice_element_type, DescArg, TransparentTypes,
+};
+use crate::utils::get_lit_str;
 use proc_macro2::TokenStream;
 use proc_macro_error::abort;
-use quote::{format_ident, quote};
+use quote::{format_ident, quote, ToTokens};
 use syn::{
     parse::{Parse, ParseStream, Result},
     parse_str,
-    token::Colon,
-    Ident, ItemFn, PatIdent, PatType, Type,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    token::{Colon, Comma},
+    Attribute, Error, Ident, ItemFn, Meta, NestedMeta, PatIdent, PatType, ReturnType, Type,
 };
 
 #[derive(Debug, Eq, PartialEq)]
@@ -29,7 +34,13 @@ pub struct Command {
     pub id: Option<u16>,
     pub handler_name: Ident,
     pub module: Option<Vec<Ident>>,
+    pub impl_type: Option<Type>,
     pub has_context: bool,
+    pub config_only: bool,
+    pub reject_in_shutdown: bool,
+    pub fallible: bool,
+    /// The handler receives the rest of the frame as a single `&[u8]`, see `CommandAttrs::raw`
+    pub raw: bool,
     pub args: Vec<Arg>,
 }
 
@@ -40,23 +51,27 @@ impl Command {
 
     pub fn target(&self) -> TokenStream {
         let hn = &self.handler_name;
-        match &self.module {
-            None => quote! { #hn },
-            Some(mp) => {
-                quote! {
-                    crate:: #(#mp::)* #hn
-                }
+        let mp = self.module.iter().flatten();
+        match &self.impl_type {
+            // Paths starting at the crate root are used as written
+            Some(Type::Path(p)) if p.path.segments.first().is_some_and(|s| s.ident == "crate") => {
+                quote! { #p::#hn }
             }
+            Some(ty) => quote! { crate:: #(#mp::)* #ty::#hn },
+            None if self.module.is_none() => quote! { #hn },
+            None => quote! { crate:: #(#mp::)* #hn },
         }
     }
 
-    pub fn get_desc_string(&self) -> String {
+    pub fn get_desc_string(&self, transparent: &TransparentTypes) -> String {
         build_message_descriptor(
             &self.name,
+            // Optional arguments are described like required ones, see `klipper_command`
             self.args.iter().map(|a| DescArg {
                 name: &a.name,
-                type_: &a.type_,
+                type_: optional_inner_type(&a.type_).unwrap_or(&a.type_),
             }),
+            transparent,
         )
     }
 }
@@ -64,6 +79,11 @@ impl Command {
 fn parse_has_context_param<'a>(
     iter: &mut std::iter::Peekable<impl Iterator<Item = (usize, &'a syn::FnArg)>>,
 ) -> bool {
+    // Methods receive the context as `self`
+    if let Some((_, syn::FnArg::Receiver(_))) = iter.peek() {
+        let _ = iter.next();
+        return true;
+    }
     if let Some((_, syn::FnArg::Typed(PatType { pat, .. }))) = iter.peek() {
         if let syn::Pat::Ident(PatIdent { ident, .. }) = pat.as_ref() {
             let name = ident.to_string();
@@ -77,10 +97,102 @@ fn parse_has_context_param<'a>(
     false
 }
 
+/// Options given in `#[klipper_command(...)]`
+#[derive(Debug, Default)]
+pub struct CommandAttrs {
+    pub config_only: bool,
+    pub reject_in_shutdown: bool,
+    pub group: Option<String>,
+    pub name: Option<Ident>,
+    /// Set by `#[klipper_command(raw)]`, the handler gets the undecoded payload
+    pub raw: bool,
+}
+
+impl CommandAttrs {
+    /// Collects the options from all `#[klipper_command]` attributes of an item
+    pub fn from_attrs(attrs: &[Attribute]) -> Result<CommandAttrs> {
+        let mut opts = CommandAttrs::default();
+        for attr in attrs.iter().filter(|a| a.path.is_ident("klipper_command")) {
+            let nested = match attr.parse_meta()? {
+                Meta::Path(_) => continue,
+                Meta::List(list) => list.nested,
+                Meta::NameValue(nv) => {
+                    return Err(Error::new(nv.span(), "expected #[klipper_command(...)]"))
+                }
+            };
+            opts.add(nested)?;
+        }
+        Ok(opts)
+    }
+
+    fn add(&mut self, nested: impl IntoIterator<Item = NestedMeta>) -> Result<()> {
+        for meta in nested {
+            match meta {
+                NestedMeta::Meta(Meta::Path(p)) if p.is_ident("raw") => {
+                    self.raw = true;
+                }
+                NestedMeta::Meta(Meta::NameValue(m)) if m.path.is_ident("phase") => {
+                    let phase = get_lit_str(&m.lit)?;
+                    self.config_only = match phase.value().as_str() {
+                        "config" => true,
+                        "any" => false,
+                        _ => return Err(Error::new(phase.span(), "unknown command phase")),
+                    };
+                }
+                NestedMeta::Meta(Meta::NameValue(m)) if m.path.is_ident("shutdown") => {
+                    let shutdown = get_lit_str(&m.lit)?;
+                    self.reject_in_shutdown = match shutdown.value().as_str() {
+                        "reject" => true,
+                        "allow" => false,
+                        _ => return Err(Error::new(shutdown.span(), "unknown shutdown option")),
+                    };
+                }
+                NestedMeta::Meta(Meta::NameValue(m)) if m.path.is_ident("group") => {
+                    self.group = Some(get_lit_str(&m.lit)?.value());
+                }
+                NestedMeta::Meta(Meta::NameValue(m)) if m.path.is_ident("name") => {
+                    let name = get_lit_str(&m.lit)?;
+                    self.name = Some(
+                        name.parse()
+                            .map_err(|_| Error::new(name.span(), "invalid command name"))?,
+                    );
+                }
+                other => {
+                    return Err(Error::new(
+                        other.span(),
+                        "unknown klipper_command attribute",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the arguments of the `#[klipper_command(...)]` attribute macro itself
+impl Parse for CommandAttrs {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let nested = Punctuated::<NestedMeta, Comma>::parse_terminated(input)?;
+        let mut opts = CommandAttrs::default();
+        opts.add(nested)?;
+        Ok(opts)
+    }
+}
+
 impl Parse for Command {
     fn parse(input: ParseStream) -> Result<Self> {
         let func: ItemFn = input.parse()?;
+        let opts = CommandAttrs::from_attrs(&func.attrs)?;
+        Command::from_fn(func, &opts)
+    }
+}
 
+impl Command {
+    /// Builds the command for a handler, with options given separately
+    ///
+    /// Used by the `#[klipper_command]` macro itself, which doesn't see its own attribute on the
+    /// function.
+    pub fn from_fn(func: ItemFn, opts: &CommandAttrs) -> Result<Self> {
         let mut inputs = func.sig.inputs.iter().enumerate().peekable();
 
         let has_context = parse_has_context_param(&mut inputs);
@@ -103,14 +215,72 @@ impl Parse for Command {
             }
         }
 
-        let name = func.sig.ident;
+        // Slices borrow the received frame, which is reused once the handler returns. A named
+        // lifetime, e.g. `'ctx`, would claim a longer borrow than the handler is given.
+        for a in &args {
+            let type_ = optional_inner_type(&a.type_).unwrap_or(&a.type_);
+            if let Type::Reference(syn::TypeReference {
+                lifetime: Some(lifetime),
+                ..
+            }) = type_
+            {
+                return Err(Error::new(
+                    lifetime.span(),
+                    "Slice arguments only borrow the received frame for the duration of the call \
+                     and can't have a named lifetime, copy the data to retain it",
+                ));
+            }
+        }
+
+        let mut optional = false;
+        for a in &args {
+            match optional_inner_type(&a.type_) {
+                Some(inner) if slice_element_type(inner).is_some() => {
+                    return Err(Error::new(
+                        a.type_.span(),
+                        "Typed slices can't be optional arguments",
+                    ))
+                }
+                Some(_) => optional = true,
+                None if optional => {
+                    return Err(Error::new(
+                        a.type_.span(),
+                        "Optional arguments must follow all required arguments",
+                    ))
+                }
+                None => {}
+            }
+        }
+
+        // A raw command is declared without arguments, its payload isn't described to the host
+        if opts.raw {
+            match args.as_slice() {
+                [a] if a.type_.to_token_stream().to_string() == "& [u8]" => args.clear(),
+                _ => {
+                    return Err(Error::new(
+                        func.sig.inputs.span(),
+                        "A raw command takes a single `&[u8]` argument besides the context",
+                    ))
+                }
+            }
+        }
+
+        // Handlers returning a value must return `Result<(), E>`, where `E` converts into
+        // `CommandError`, which is type checked by the generated handler
+        let fallible = !matches!(func.sig.output, ReturnType::Default);
+        let handler_name = func.sig.ident;
 
         Ok(Command {
-            name: name.clone(),
+            name: opts.name.clone().unwrap_or_else(|| handler_name.clone()),
             module: None,
-            handler_name: name,
+            impl_type: None,
+            handler_name,
             id: None,
             has_context,
+            config_only: opts.config_only,
+            reject_in_shutdown: opts.reject_in_shutdown,
+            fallible,
+            raw: opts.raw,
             args,
         })
     }
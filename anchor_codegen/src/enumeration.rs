@@ -12,6 +12,11 @@ use syn::{
 #[derive(Debug, Serialize)]
 pub struct DictionaryEnumeration(pub BTreeMap<String, DictionaryEnumerationItem>);
 
+/// Value of an enumeration entry in the dictionary
+///
+/// Klipper enumerations are always keyed by name and valued by number, the host rejects any other
+/// shape. A range is keyed by the name of its first item, e.g. `"PA0": [0, 16]`, and the host
+/// derives the following names by incrementing the trailing number.
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
 pub enum DictionaryEnumerationItem {
@@ -70,6 +75,8 @@ impl Enumeration {
             }
         });
         let max_variant = self.max_variant();
+        let dictionary_name = self.dictionary_name();
+        let roundtrip_test = cfg!(feature = "enumeration_tests").then(|| self.roundtrip_test());
 
         quote! {
             #(#attrs)*
@@ -85,7 +92,100 @@ impl Enumeration {
 
             #(#from_converters)*
             #(#to_converters)*
+
+            // Enumerations are transferred as `%u`, see `Processor::process_enumeration`
+            impl<'de> ::anchor::encoding::Readable<'de> for #ident {
+                fn read(data: &mut &'de [u8]) -> Result<Self, ::anchor::encoding::ReadError> {
+                    let mut cursor = *data;
+                    let value = <u32 as ::anchor::encoding::Readable>::read(&mut cursor)?;
+                    let value = Self::try_from(value).map_err(|_| {
+                        ::anchor::encoding::ReadError::InvalidEnumValue {
+                            name: #dictionary_name,
+                            value,
+                        }
+                    })?;
+                    *data = cursor;
+                    Ok(value)
+                }
+            }
+
+            #roundtrip_test
+        }
+    }
+
+    // Generates a test converting every variant to each input type and back. Values of variants
+    // that are compiled out, and the first value past the last variant, must be rejected.
+    fn roundtrip_test(&self) -> TokenStream {
+        let ident = &self.ident;
+        let test_name = format_ident!("_anchor_enumeration_roundtrip_{}", ident);
+        let input_types: Vec<Type> = self
+            .valid_input_types()
+            .iter()
+            .map(|t| parse_str(t).unwrap())
+            .collect();
+
+        let mut checks = Vec::new();
+        for (v, start, cnt) in self.numbered_variants() {
+            let cfg: Vec<TokenStream> = v
+                .opts()
+                .attrs
+                .iter()
+                .filter(|a| a.path.is_ident("cfg"))
+                .map(|a| a.parse_args().unwrap_or_default())
+                .collect();
+            let cfg = quote! { all(#(#cfg),*) };
+            let idents: Vec<Ident> = match v {
+                EnumVariant::Single(_, ident) => vec![ident.clone()],
+                EnumVariant::Range(_, prefix, ident_start, _) => (*ident_start..*ident_start + cnt)
+                    .map(|n| format_ident!("{prefix}{n}"))
+                    .collect(),
+            };
+            for (variant, i) in idents.iter().zip(start..) {
+                let i = TokenStream::from_str(&format!("{i}")).unwrap();
+                checks.push(quote! {
+                    #(
+                        #[cfg(#cfg)]
+                        assert_eq!(#input_types::from(#ident::#variant), #i);
+                        #[cfg(#cfg)]
+                        assert!(matches!(
+                            <#ident as ::core::convert::TryFrom<#input_types>>::try_from(#i),
+                            Ok(#ident::#variant)
+                        ));
+                        #[cfg(not(#cfg))]
+                        assert!(<#ident as ::core::convert::TryFrom<#input_types>>::try_from(#i).is_err());
+                    )*
+                });
+            }
         }
+
+        let past_end = self.max_variant() as u64 + 1;
+        let past_end_types = self
+            .valid_input_types()
+            .iter()
+            .filter(|t| match **t {
+                "u8" => past_end <= u8::MAX as u64,
+                "u16" => past_end <= u16::MAX as u64,
+                "u32" => past_end <= u32::MAX as u64,
+                _ => true,
+            })
+            .map(|t| parse_str::<Type>(t).unwrap());
+        let past_end = TokenStream::from_str(&format!("{past_end}")).unwrap();
+
+        quote! {
+            #[cfg(test)]
+            #[test]
+            #[allow(non_snake_case)]
+            fn #test_name() {
+                #(#checks)*
+                #(
+                    assert!(<#ident as ::core::convert::TryFrom<#past_end_types>>::try_from(#past_end).is_err());
+                )*
+            }
+        }
+    }
+
+    pub fn ident(&self) -> &Ident {
+        &self.ident
     }
 
     fn variant_decl(variant: &EnumVariant) -> Vec<TokenStream> {
@@ -164,10 +264,11 @@ impl Enumeration {
                     }
                     EnumVariant::Range(_, prefix, ident_start, _) => {
                         let cfg_attrs = cfg_attrs.collect::<Vec<_>>();
-                        (*ident_start..*ident_start + cnt)
-                            .map(|i| {
-                                let ident = format_ident!("{prefix}{i}");
-                                let i = TokenStream::from_str(&format!("{}", i + start)).unwrap();
+                        (start..start + cnt)
+                            .zip(*ident_start..*ident_start + cnt)
+                            .map(|(i, n)| {
+                                let ident = format_ident!("{prefix}{n}");
+                                let i = TokenStream::from_str(&format!("{i}")).unwrap();
                                 quote! {
                                     #(#cfg_attrs)*
                                     #self_ident::#ident => #i,
@@ -223,9 +324,9 @@ impl Enumeration {
                         DictionaryEnumerationItem::Number(start as i64),
                     );
                 }
-                EnumVariant::Range(_, _, _, _) => {
+                EnumVariant::Range(_, _, ident_start, _) => {
                     out.insert(
-                        variant.name(self.opts.rename_all),
+                        format!("{}{}", variant.name(self.opts.rename_all), ident_start),
                         DictionaryEnumerationItem::Range(start as i64, cnt as i64),
                     );
                 }
@@ -247,6 +348,24 @@ impl Parse for Enumeration {
         let variants: Punctuated<EnumVariant, Token![,]> =
             content.parse_terminated(EnumVariant::parse)?;
 
+        // Enumerations are transferred as `%u`, so every value must fit in a `u32`. Disabled
+        // variants still take up their values.
+        let total = variants
+            .iter()
+            .try_fold(0usize, |total, v| total.checked_add(v.count()));
+        match total {
+            Some(total) if total as u64 <= u32::MAX as u64 + 1 => {}
+            _ => {
+                return Err(Error::new(
+                    ident.span(),
+                    format!(
+                        "Enumeration '{}' has more variants than its `u32` values can number",
+                        ident
+                    ),
+                ))
+            }
+        }
+
         Ok(Enumeration {
             opts: EnumerationOptions::parse(&attrs)?,
             attrs,
@@ -442,12 +561,74 @@ impl Parse for EnumVariant {
             let _brace = parenthesized!(content in input);
             let prefix = content.parse()?;
             content.parse::<Token![,]>()?;
-            let start = content.parse::<LitInt>()?.base10_parse()?;
+            let start: usize = content.parse::<LitInt>()?.base10_parse()?;
             content.parse::<Token![,]>()?;
-            let count = content.parse::<LitInt>()?.base10_parse()?;
+            let count_lit = content.parse::<LitInt>()?;
+            let count: usize = count_lit.base10_parse()?;
+            if count == 0 {
+                return Err(Error::new(
+                    count_lit.span(),
+                    "A `Range` must have at least one variant",
+                ));
+            }
+            if start.checked_add(count).is_none() {
+                return Err(Error::new(
+                    count_lit.span(),
+                    "The `Range` numbering overflows",
+                ));
+            }
             Ok(EnumVariant::Range(opts, prefix, start, count))
         } else {
             Ok(EnumVariant::Single(opts, ident))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(src: &str) -> String {
+        match syn::parse_str::<Enumeration>(src) {
+            Ok(_) => panic!("enumeration should be rejected"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn rejects_zero_count_range() {
+        assert_eq!(
+            parse_error("enum Pins { PA0, Range(PB, 0, 0) }"),
+            "A `Range` must have at least one variant"
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_enumeration() {
+        let msg = "Enumeration 'Pins' has more variants than its `u32` values can number";
+        assert_eq!(
+            parse_error("enum Pins { Range(PA, 0, 4294967296), Extra }"),
+            msg
+        );
+        assert_eq!(
+            parse_error("enum Pins { Range(PA, 0, 2147483648), Range(PB, 0, 2147483649) }"),
+            msg
+        );
+        // Exactly filling the `u32` values is fine
+        assert!(
+            syn::parse_str::<Enumeration>("enum Pins { Range(PA, 0, 4294967295), Extra }").is_ok()
+        );
+    }
+
+    #[test]
+    fn range_matches_klipper_dictionary_shape() {
+        let enumeration = syn::parse_str::<Enumeration>(
+            "enum Pins { Range(PA, 0, 16), Range(PB, 4, 8), AdcTemperature }",
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(enumeration.to_dictionary()).unwrap(),
+            serde_json::json!({"PA0": [0, 16], "PB4": [16, 8], "AdcTemperature": 24})
+        );
+    }
+}
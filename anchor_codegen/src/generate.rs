@@ -8,7 +8,9 @@ use syn::{
 #[derive(Debug)]
 pub struct GenerateConfig {
     pub transport: Option<(Path, Type)>,
+    pub aux_transport: Option<(Path, Type)>,
     pub context: Type,
+    pub name: Option<Ident>,
 }
 
 impl GenerateConfig {
@@ -21,11 +23,18 @@ impl GenerateConfig {
 
         Ok(())
     }
+
+    /// Returns true if no context (or the empty tuple) was given
+    pub fn has_unit_context(&self) -> bool {
+        matches!(&self.context, Type::Tuple(t) if t.elems.is_empty())
+    }
 }
 
 impl Parse for GenerateConfig {
     fn parse(input: ParseStream) -> Result<Self> {
         let mut transport = None;
+        let mut aux_transport = None;
+        let mut name = None;
         let mut context = Type::Tuple(TypeTuple {
             paren_token: Paren { span: input.span() },
             elems: Punctuated::new(),
@@ -42,9 +51,18 @@ impl Parse for GenerateConfig {
                     let type_ = input.parse()?;
                     transport = Some((name, type_));
                 }
+                "aux_transport" => {
+                    let name = input.parse()?;
+                    input.parse::<Colon>()?;
+                    let type_ = input.parse()?;
+                    aux_transport = Some((name, type_));
+                }
                 "context" => {
                     context = input.parse()?;
                 }
+                "name" => {
+                    name = Some(input.parse()?);
+                }
                 unkn => {
                     return Err(Error::new(
                         key.span(),
@@ -57,6 +75,11 @@ impl Parse for GenerateConfig {
             while input.parse::<Comma>().is_ok() {}
         }
 
-        Ok(GenerateConfig { transport, context })
+        Ok(GenerateConfig {
+            transport,
+            aux_transport,
+            context,
+            name,
+        })
     }
 }
@@ -1,5 +1,19 @@
 //! Build step library for Anchor.
 //! See the main library documentation for documentation on how to use Anchor.
+//!
+//! With the `introspection` feature enabled, the generated code contains an `arg_names` module
+//! with the argument names of each command and reply, e.g. `arg_names::REPLY_CONFIG`, in the
+//! same order as in the dictionary.
+//!
+//! With the `enumeration_tests` feature enabled, `klipper_enumeration!` also generates a test
+//! checking the conversions of each enumeration, run by `cargo test` in the firmware crate.
+//!
+//! The generated code always contains a `CONSTANTS` list with the name and value of each
+//! `klipper_constant`, values rendered as strings, e.g. `("CLOCK_FREQ", "100000000")`. This
+//! allows firmware to log its constants, e.g. at boot, without decompressing the dictionary.
+//!
+//! For static allocation, the generated code also contains the `NUM_COMMANDS` and
+//! `NUM_RESPONSES` counts, and `COMMAND_ID_LIMIT`, which sizes tables indexed by command id.
 
 use anyhow::Result;
 use proc_macro2::TokenStream;
@@ -11,9 +25,11 @@ use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use syn::{
+    ext::IdentExt,
     parse2,
     visit::{self, Visit},
-    Ident, ItemConst, ItemFn, ItemMod, LitInt, LitStr, Macro,
+    Ident, ImplItemMethod, ItemConst, ItemFn, ItemImpl, ItemMod, ItemStruct, LitInt, LitStr, Macro,
+    Meta, NestedMeta, Type,
 };
 
 #[doc(hidden)]
@@ -30,14 +46,18 @@ pub mod output;
 pub mod reply;
 #[doc(hidden)]
 pub mod static_string;
+#[doc(hidden)]
+pub mod transparent;
 mod utils;
 
 use crate::enumeration::{DictionaryEnumeration, DictionaryEnumerationItem, Enumeration};
-use command::Command;
+use command::{Command, CommandAttrs};
 use generate::GenerateConfig;
+use msg_desc::TransparentTypes;
 use output::Output;
 use reply::Reply;
 use static_string::{Shutdown, StaticString};
+use transparent::Transparent;
 use utils::*;
 
 /// Build step for generating runtime functions and dictionary
@@ -46,7 +66,25 @@ pub struct ConfigBuilder {
     entries: Vec<(PathBuf, Vec<Ident>)>,
     version: Option<String>,
     build_versions: Option<String>,
+    serial: Option<String>,
     skip_commands: BTreeSet<String>,
+    skip_modules: Vec<Vec<Ident>>,
+    emit_json_dictionary: bool,
+    reference: Option<PathBuf>,
+    id_map: Option<PathBuf>,
+    dump_dictionary_command: bool,
+    report_dictionary_size: bool,
+    output_buffer_size: Option<usize>,
+    report_unknown_commands: bool,
+    store_dictionary_uncompressed: bool,
+    enabled_groups: BTreeSet<String>,
+    variants: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+    extra_messages: Vec<Message>,
+    static_string_prefix: Option<String>,
+    emit_descriptions: bool,
+    allow_unused_replies: bool,
+    deny_unused_replies: bool,
+    inline_handlers: bool,
 }
 
 impl ConfigBuilder {
@@ -83,6 +121,18 @@ impl ConfigBuilder {
         self
     }
 
+    /// Sets the version string from an application name and a version number
+    ///
+    /// The components are assembled as `"<app> <version>"`, e.g. `set_version_components("jig",
+    /// "0.1.0")` results in the version string `jig 0.1.0`.
+    pub fn set_version_components(self, app: impl AsRef<str>, version: impl AsRef<str>) -> Self {
+        self.set_version(format!(
+            "{} {}",
+            app.as_ref().trim(),
+            version.as_ref().trim()
+        ))
+    }
+
     /// Sets the build version string that will be placed in the dictionary
     ///
     /// It is customary for this string to be formatted as a space separated list of pairs as so:
@@ -96,6 +146,32 @@ impl ConfigBuilder {
         self
     }
 
+    /// Sets a serial string that will be placed in the dictionary
+    ///
+    /// The serial is added as the `SERIAL` constant, and is also available to the firmware as
+    /// `_anchor_config::SERIAL`, e.g. for reporting it in a custom reply or for deriving a CAN
+    /// UUID. As it is part of the dictionary, the host receives it through `identify` like any
+    /// other constant, and two builds with different serials serve different dictionaries.
+    ///
+    /// The serial identifies a build, not a device. Devices flashed with the same image share it,
+    /// so per-device identifiers, e.g. from a chip ID, must be reported at runtime.
+    pub fn set_serial(mut self, serial: impl AsRef<str>) -> Self {
+        self.serial = Some(serial.as_ref().into());
+        self
+    }
+
+    /// Sets the serial from an environment variable, if it is set
+    ///
+    /// This allows CI to stamp builds, e.g. `.set_serial_from_env("BUILD_SERIAL")`. Without the
+    /// variable, no serial is set. The build script is rerun when the variable changes.
+    pub fn set_serial_from_env(self, name: &str) -> Self {
+        println!("cargo:rerun-if-env-changed={}", name);
+        match env::var(name) {
+            Ok(serial) => self.set_serial(serial),
+            Err(_) => self,
+        }
+    }
+
     /// Ignores the `klipper_command` with a given name
     ///
     /// This can be used for disabling certain commands in specific builds. Generally it is
@@ -105,6 +181,275 @@ impl ConfigBuilder {
         self
     }
 
+    /// Ignores a module and all its submodules
+    ///
+    /// The module is given as a path like for `entry_module`, e.g. `crate::vendor::sensors` would
+    /// be `[vendor, sensors]`. No commands, replies, enumerations or constants are collected from
+    /// the module. This can be used to exclude shared source where `#[cfg]` attributes aren't
+    /// convenient.
+    pub fn skip_module(mut self, module: &[Ident]) -> Self {
+        self.skip_modules.push(module.to_vec());
+        self
+    }
+
+    /// Enables a group of commands
+    ///
+    /// Commands declared with `#[klipper_command(group = "name")]` are only included if their
+    /// group is enabled. The attribute also puts `#[cfg(anchor_group = "name")]` on the handler,
+    /// and the build step sets this cfg for enabled groups, so the handlers of disabled groups
+    /// are not compiled. Other items, e.g. helpers used by the handlers, can be gated using the
+    /// same cfg.
+    ///
+    /// This allows optional feature sets of the firmware to be selected in one place.
+    pub fn enable_group(mut self, name: &str) -> Self {
+        self.enabled_groups.insert(name.to_owned());
+        self
+    }
+
+    /// Also emit the dictionary as uncompressed JSON
+    ///
+    /// When enabled, a `DICT_JSON: &str` constant is generated next to the compressed dictionary.
+    /// This is useful for simulators that load the firmware in-process and want to read the
+    /// dictionary directly. This is disabled by default, as it adds the full dictionary to the
+    /// binary.
+    pub fn emit_json_dictionary(mut self, enabled: bool) -> Self {
+        self.emit_json_dictionary = enabled;
+        self
+    }
+
+    /// Adds the doc comments of commands to the dictionary
+    ///
+    /// When enabled, the dictionary gets a `descriptions` map from command name to the doc
+    /// comment of its handler, for host tooling. Klippy ignores it. This is disabled by default,
+    /// as it grows the dictionary embedded in the binary. Commands without a doc comment are
+    /// left out.
+    pub fn emit_descriptions(mut self, enabled: bool) -> Self {
+        self.emit_descriptions = enabled;
+        self
+    }
+
+    /// Don't warn about replies that are never sent
+    ///
+    /// By default, the build warns about replies in the dictionary that the scanned sources never
+    /// send, as they only grow the dictionary. A reply counts as sent if the sources invoke
+    /// `klipper_reply!` or `try_klipper_reply!` for it, or refer to its generated `send_reply_*`
+    /// function. Replies registered with `add_reply` and sent only from code the scan doesn't see
+    /// are reported too, enable this to silence the warning for them.
+    pub fn allow_unused_replies(mut self, enabled: bool) -> Self {
+        self.allow_unused_replies = enabled;
+        self
+    }
+
+    /// Fails the build on replies that are never sent, instead of warning
+    ///
+    /// See `allow_unused_replies`.
+    pub fn deny_unused_replies(mut self, enabled: bool) -> Self {
+        self.deny_unused_replies = enabled;
+        self
+    }
+
+    /// Adds the `anchor_dump_dict` command
+    ///
+    /// When received, this command sends the entire dictionary as a sequence of
+    /// `identify_response` replies, without waiting for the host to request each chunk. This is
+    /// intended for bring-up tools and cooperating hosts on slow links, Klippy itself uses
+    /// `identify`, which keeps working as usual. Make sure the `TransportOutput` can buffer or
+    /// transmit the full dictionary, or replies will be lost.
+    ///
+    /// Each reply fills a whole frame, and carries its offset in the compressed dictionary. The
+    /// last reply carries a shorter chunk than the others, possibly an empty one, which marks the
+    /// end of the dictionary.
+    pub fn dump_dictionary_command(mut self, enabled: bool) -> Self {
+        self.dump_dictionary_command = enabled;
+        self
+    }
+
+    /// Reports received commands that the firmware doesn't implement
+    ///
+    /// When enabled, an `Unknown command %u` output message with the command id is sent for each
+    /// unknown command received. This is useful during bring-up, to spot commands Klippy expects
+    /// but the firmware lacks. This is disabled by default, as it adds the message to the
+    /// dictionary and the binary.
+    ///
+    /// Only the first unknown command of a frame is reported. Its arguments can't be skipped, so
+    /// the rest of the frame is dropped.
+    pub fn report_unknown_commands(mut self, enabled: bool) -> Self {
+        self.report_unknown_commands = enabled;
+        self
+    }
+
+    /// Stores the dictionary without compression
+    ///
+    /// The dictionary is still embedded and served as a zlib stream, as the host expects, but
+    /// using uncompressed blocks. The JSON is then readable as is in the binary, which helps when
+    /// debugging, e.g. in host simulation builds. Such builds can enable this only when built
+    /// with `std`:
+    /// ```ignore
+    /// .store_dictionary_uncompressed(std::env::var_os("CARGO_FEATURE_STD").is_some())
+    /// ```
+    pub fn store_dictionary_uncompressed(mut self, enabled: bool) -> Self {
+        self.store_dictionary_uncompressed = enabled;
+        self
+    }
+
+    /// Inlines the generated command handlers into the dispatcher
+    ///
+    /// By default each command gets its own handler function, decoding its arguments and calling
+    /// it, and the dispatcher calls these. When enabled, the handlers are marked
+    /// `#[inline(always)]`, which merges them into a single dispatch function. This saves the
+    /// call overhead and lets the compiler share the decoding and error paths between commands,
+    /// at the cost of a larger stack frame for the dispatcher. Commands are dispatched exactly as
+    /// before.
+    ///
+    /// This mostly helps builds with little or no optimization. Optimizing builds already inline
+    /// handlers on their own, as each is called from a single place, and forcing it may cost some
+    /// bytes instead. Built for x86-64, the test jig saved about 600 bytes of code at opt-level 0,
+    /// while at opt-level 1 and above the change ranged from saving 24 bytes to costing 300, so
+    /// measure on the actual target before enabling it.
+    pub fn inline_handlers(mut self, enabled: bool) -> Self {
+        self.inline_handlers = enabled;
+        self
+    }
+
+    /// Reports the size of the embedded dictionary as a build warning
+    ///
+    /// The report includes the embedded and uncompressed sizes, and the number of commands,
+    /// responses, outputs and enumerations. This helps keeping an eye on flash usage on
+    /// constrained targets.
+    pub fn report_dictionary_size(mut self, enabled: bool) -> Self {
+        self.report_dictionary_size = enabled;
+        self
+    }
+
+    /// Checks that all replies and outputs fit in an output buffer of `size` bytes
+    ///
+    /// The largest possible encoding of each message is estimated from its argument types,
+    /// including the frame header and trailer. The build fails, naming the message, if it exceeds
+    /// `size` or the maximum frame length. Byte arrays only count their length prefix, so a
+    /// message passing the check may still be too large at runtime, depending on the data. Use
+    /// 64 for `ScratchOutput` with its default size.
+    pub fn check_output_buffer_size(mut self, size: usize) -> Self {
+        self.output_buffer_size = Some(size);
+        self
+    }
+
+    /// Names the generated static string id constants `<prefix><id>`
+    ///
+    /// By default, the constants in the generated `static_strings` module are named after a hex
+    /// encoding of the string, like `STATIC_STRING_GHGJ...`. With a prefix, e.g. `"MSG_"`, they
+    /// are named after their id instead, like `MSG_2`, and documented with the string. The hex
+    /// names are kept as hidden aliases, as `klipper_static_string!` and `klipper_shutdown!`
+    /// refer to them.
+    pub fn static_string_prefix(mut self, prefix: impl AsRef<str>) -> Self {
+        let prefix = prefix.as_ref();
+        if syn::parse_str::<Ident>(&format!("{}0", prefix)).is_err() {
+            panic!(
+                "Static string prefix '{}' can't start an identifier",
+                prefix
+            );
+        }
+        self.static_string_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Overrides a constant in a dictionary variant
+    ///
+    /// Firmware that can present itself as different MCUs, e.g. depending on a strap pin, may
+    /// embed several dictionaries. Each variant is a copy of the dictionary with some constants
+    /// overridden, and is created by the first override for it. The generated
+    /// `select_dictionary(name)` function selects the variant served by `identify` at runtime:
+    /// ```ignore
+    /// if strap_pin.is_high() {
+    ///     _anchor_config::select_dictionary("right");
+    /// }
+    /// ```
+    /// Until a variant is selected, the base dictionary is served. Commands and responses are the
+    /// same in all variants. Only the base dictionary is checked against a reference, and emitted
+    /// as JSON.
+    pub fn variant_constant(
+        mut self,
+        variant: &str,
+        name: &str,
+        value: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.variants
+            .entry(variant.to_owned())
+            .or_default()
+            .insert(name.to_owned(), value.into());
+        self
+    }
+
+    /// Registers a reply that isn't sent from the scanned sources
+    ///
+    /// The arguments are given as name and type pairs, using the types supported by
+    /// `klipper_reply!`. The reply is added to the dictionary and its sender is generated, so it
+    /// can be sent using `klipper_reply!` from code the scan doesn't see, e.g. code generated by
+    /// the build script itself:
+    /// ```ignore
+    /// ConfigBuilder::new()
+    ///     .add_reply("adc_state", &[("oid", "u8"), ("value", "u16")])
+    /// ```
+    /// If the sources also send the reply, the arguments must match. If they don't send it at
+    /// all, the build warns that the reply is unused, see `allow_unused_replies`.
+    pub fn add_reply(mut self, name: &str, args: &[(&str, &str)]) -> Self {
+        let args = args
+            .iter()
+            .map(|(arg, type_)| reply::Arg {
+                name: format_ident!("{}", arg),
+                type_: syn::parse_str(type_).unwrap_or_else(|e| {
+                    panic!(
+                        "Invalid type '{}' for argument '{}' of reply '{}': {}",
+                        type_, arg, name, e
+                    )
+                }),
+                value: None,
+            })
+            .collect();
+        self.extra_messages.push(Message::Reply(Reply {
+            name: format_ident!("{}", name),
+            id: None,
+            chunked: false,
+            args,
+        }));
+        self
+    }
+
+    /// Registers an output message that isn't sent from the scanned sources
+    ///
+    /// The argument types are taken from the `printf`-style format string, as for
+    /// `klipper_output!`. The message is sent through the main transport.
+    pub fn add_output(mut self, format: &str) -> Self {
+        let output = Output::from_format(format, false)
+            .unwrap_or_else(|e| panic!("Invalid output format '{}': {}", format, e));
+        self.extra_messages.push(Message::Output(output));
+        self
+    }
+
+    /// Checks the generated dictionary against a reference dictionary
+    ///
+    /// The reference is a JSON data dictionary as produced by Klipper, e.g. `out/klipper.dict`.
+    /// Any command or response present in both dictionaries must have identical argument names,
+    /// types and order, otherwise the build fails. This catches accidental changes to messages the
+    /// host relies on.
+    pub fn check_against(mut self, reference: impl AsRef<Path>) -> Self {
+        self.reference = Some(reference.as_ref().to_owned());
+        self
+    }
+
+    /// Writes the message ids to a JSON file, for hosts caching them
+    ///
+    /// The file holds the dictionary `version` and the ids of the `commands` and `responses` by
+    /// name, and of the `output` messages by format, with the same ids as in the dictionary:
+    /// ```json
+    /// {"version":"v1.0","commands":{"get_clock":4,...},"responses":{...},"output":{...}}
+    /// ```
+    /// A host can key its cache by the version, and check it against the version the firmware
+    /// reports through `identify`. Relative paths are relative to the package directory.
+    pub fn write_id_map(mut self, path: impl AsRef<Path>) -> Self {
+        self.id_map = Some(path.as_ref().to_owned());
+        self
+    }
+
     /// Runs the build step
     pub fn build(self) {
         let mut processor = Processor {
@@ -116,21 +461,59 @@ impl ConfigBuilder {
             errors: vec![],
             current_file: None,
             current_module: vec![],
+            current_impl: None,
+            skip_modules: self.skip_modules,
+            visited_files: BTreeSet::new(),
+            watched_dirs: BTreeSet::new(),
+            enabled_groups: self.enabled_groups,
+            used_groups: BTreeSet::new(),
 
             messages: BTreeMap::new(),
+            message_origins: BTreeMap::new(),
             static_strings: StaticStringsTracker::new(),
+            transparent_types: TransparentTypes::new(),
             dictionary: Dictionary::default(),
             generate_cfg: None,
+            emit_json_dictionary: self.emit_json_dictionary,
+            dump_dictionary_command: self.dump_dictionary_command,
+            report_unknown_commands: self.report_unknown_commands,
+            inline_handlers: self.inline_handlers,
+            dictionary_compression: if self.store_dictionary_uncompressed {
+                flate2::Compression::none()
+            } else {
+                flate2::Compression::default()
+            },
+            variants: self.variants,
+            serial: None,
+            static_string_prefix: self.static_string_prefix,
+            emit_descriptions: self.emit_descriptions,
+            command_docs: BTreeMap::new(),
+            sent_replies: BTreeSet::new(),
         };
 
         if let Some(s) = self.version {
+            check_version(&s);
             processor.dictionary.version = s;
         }
         if let Some(s) = self.build_versions {
             processor.dictionary.build_versions = s;
         }
 
+        println!("cargo:rustc-check-cfg=cfg(anchor_group, values(any()))");
+        for group in &processor.enabled_groups {
+            println!("cargo:rustc-cfg=anchor_group=\"{}\"", group);
+        }
+
         processor.add_identify();
+        if processor.dump_dictionary_command {
+            processor.add_dump_dictionary();
+        }
+        if processor.report_unknown_commands {
+            processor.add_unknown_command_output();
+        }
+        for message in self.extra_messages {
+            processor.add_registered_message(message);
+        }
         if let Err(e) = processor.process_all() {
             if e.is::<syn::parse::Error>() {
                 // We ignore parse errors as we'd like the user to see these
@@ -140,13 +523,54 @@ impl ConfigBuilder {
             }
         }
 
+        if let Some(serial) = self.serial {
+            if processor.dictionary.config.contains_key("SERIAL") {
+                panic!(
+                    "The SERIAL constant is set by `set_serial`, and also defined in the sources"
+                );
+            }
+            processor
+                .dictionary
+                .config
+                .insert("SERIAL".into(), serial.as_str().into());
+            processor.serial = Some(serial);
+        }
+
+        for group in processor.enabled_groups.difference(&processor.used_groups) {
+            println!("cargo:warning=Command group '{group}' is enabled, but no command uses it");
+        }
+
         for cmd in self.skip_commands {
             processor.messages.remove(&cmd);
         }
 
+        if !self.allow_unused_replies || self.deny_unused_replies {
+            processor.check_unused_replies(self.deny_unused_replies);
+        }
+
+        processor.check_generated_names();
+        processor.check_aux_outputs();
         processor.assign_ids();
+        if let Some(size) = self.output_buffer_size {
+            processor.check_message_sizes(size);
+        }
         processor.finalize_dictionary();
 
+        if let Some(reference) = self.reference {
+            processor.dictionary.check_against(&reference);
+        }
+        if let Some(path) = self.id_map {
+            processor.write_id_map(&path);
+        }
+        if self.report_dictionary_size {
+            println!(
+                "cargo:warning={}",
+                processor
+                    .dictionary
+                    .size_report(processor.dictionary_compression)
+            );
+        }
+
         // panic!("{:#?}", processor.dictionary);
 
         let outfile = format!(
@@ -158,6 +582,30 @@ impl ConfigBuilder {
     }
 }
 
+const VERSION_LENGTH_MAX: usize = 64;
+
+/// The names and ids of the messages Anchor registers for the identify handshake
+const RESERVED_MESSAGES: [(&str, u16); 2] = [("identify_response", 0), ("identify", 1)];
+
+fn check_version(version: &str) {
+    if version.trim().is_empty() {
+        panic!("The version string must not be empty");
+    }
+    if version.len() > VERSION_LENGTH_MAX {
+        println!(
+            "cargo:warning=Version string is {} bytes long, consider keeping it below {}",
+            version.len(),
+            VERSION_LENGTH_MAX
+        );
+    }
+    if version.trim() != version {
+        println!("cargo:warning=Version string '{version}' has leading or trailing whitespace");
+    }
+    if version.chars().any(char::is_control) {
+        println!("cargo:warning=Version string {version:?} contains control characters");
+    }
+}
+
 #[derive(Debug)]
 struct Task {
     path: PathBuf,
@@ -166,7 +614,7 @@ struct Task {
 
 #[derive(Debug, Eq, PartialEq)]
 enum Message {
-    Command(Command),
+    Command(Box<Command>),
     Reply(Reply),
     Output(Output),
 }
@@ -195,11 +643,30 @@ struct Processor {
     errors: Vec<anyhow::Error>,
     current_file: Option<PathBuf>,
     current_module: Vec<Ident>,
+    current_impl: Option<Type>,
+    skip_modules: Vec<Vec<Ident>>,
+    visited_files: BTreeSet<PathBuf>,
+    watched_dirs: BTreeSet<PathBuf>,
+    enabled_groups: BTreeSet<String>,
+    used_groups: BTreeSet<String>,
 
     messages: BTreeMap<String, Message>,
+    message_origins: BTreeMap<String, String>,
     static_strings: StaticStringsTracker,
+    transparent_types: TransparentTypes,
     dictionary: Dictionary,
     generate_cfg: Option<GenerateConfig>,
+    emit_json_dictionary: bool,
+    dump_dictionary_command: bool,
+    report_unknown_commands: bool,
+    inline_handlers: bool,
+    dictionary_compression: flate2::Compression,
+    variants: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+    serial: Option<String>,
+    static_string_prefix: Option<String>,
+    emit_descriptions: bool,
+    command_docs: BTreeMap<String, String>,
+    sent_replies: BTreeSet<String>,
 }
 
 #[derive(Debug)]
@@ -236,14 +703,93 @@ struct Dictionary {
     output: BTreeMap<String, i16>,
     #[serde(skip_serializing_if = "BTreeMap::is_empty")]
     enumerations: BTreeMap<String, DictionaryEnumeration>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    descriptions: BTreeMap<String, String>,
 }
 
 impl Dictionary {
-    pub fn to_compressed(&self) -> Vec<u8> {
-        let mut e = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Could not serialize data dictionary")
+    }
+
+    pub fn check_against(&self, reference: &Path) {
+        println!("cargo:rerun-if-changed={}", reference.display());
+        let content = std::fs::read_to_string(reference).unwrap_or_else(|e| {
+            panic!(
+                "Could not read reference dictionary {}: {}",
+                reference.display(),
+                e
+            )
+        });
+        let reference_dict: serde_json::Value = serde_json::from_str(&content)
+            .unwrap_or_else(|e| panic!("Could not parse reference dictionary: {}", e));
+
+        fn by_name<'a>(descs: impl Iterator<Item = &'a String>) -> BTreeMap<&'a str, &'a str> {
+            descs
+                .map(|d| (d.split(' ').next().unwrap_or_default(), d.as_str()))
+                .collect()
+        }
+
+        let mut mismatches = vec![];
+        for (kind, ours) in [("commands", &self.commands), ("responses", &self.responses)] {
+            let theirs: Vec<String> = reference_dict
+                .get(kind)
+                .and_then(|v| v.as_object())
+                .map(|o| o.keys().cloned().collect())
+                .unwrap_or_default();
+            let theirs = by_name(theirs.iter());
+            for (name, desc) in by_name(ours.keys()) {
+                if let Some(reference_desc) = theirs.get(name) {
+                    if *reference_desc != desc {
+                        mismatches.push(format!(
+                            "  {}: '{}' does not match reference '{}'",
+                            kind, desc, reference_desc
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !mismatches.is_empty() {
+            panic!(
+                "Dictionary does not match reference {}:\n{}",
+                reference.display(),
+                mismatches.join("\n")
+            );
+        }
+    }
+
+    pub fn size_report(&self, level: flate2::Compression) -> String {
+        format!(
+            "Dictionary is {} bytes embedded ({} bytes uncompressed) with {} commands, {} responses, {} outputs and {} enumerations",
+            self.to_compressed(level).len(),
+            self.to_json().len(),
+            self.commands.len(),
+            self.responses.len(),
+            self.output.len(),
+            self.enumerations.len(),
+        )
+    }
+
+    pub fn to_compressed(&self, level: flate2::Compression) -> Vec<u8> {
+        let mut e = flate2::write::ZlibEncoder::new(Vec::new(), level);
         serde_json::to_writer(&mut e, self).expect("Could not serialize data dictionary");
         e.finish().expect("Could not serialize data dictionary")
     }
+
+    pub fn to_compressed_variant(
+        &self,
+        constants: &BTreeMap<String, serde_json::Value>,
+        level: flate2::Compression,
+    ) -> Vec<u8> {
+        let mut dict = serde_json::to_value(self).expect("Could not serialize data dictionary");
+        for (name, value) in constants {
+            dict["config"][name] = value.clone();
+        }
+        let mut e = flate2::write::ZlibEncoder::new(Vec::new(), level);
+        serde_json::to_writer(&mut e, &dict).expect("Could not serialize data dictionary");
+        e.finish().expect("Could not serialize data dictionary")
+    }
 }
 
 macro_rules! check_error {
@@ -259,7 +805,9 @@ impl<'ast> Visit<'ast> for Processor {
         match path_last_name(&node.path).map(Ident::to_string).as_deref() {
             Some("klipper_static_string") => check_error!(self, self.process_static_string(node)),
             Some("klipper_shutdown") => check_error!(self, self.process_klipper_shutdown(node)),
-            Some("klipper_reply") => check_error!(self, self.process_reply(node)),
+            Some("klipper_reply" | "try_klipper_reply") => {
+                check_error!(self, self.process_reply(node))
+            }
             Some("klipper_output") => check_error!(self, self.process_output(node)),
             Some("klipper_enumeration") => check_error!(self, self.process_enumeration(node)),
             Some("klipper_config_generate") => {
@@ -271,7 +819,19 @@ impl<'ast> Visit<'ast> for Processor {
         visit::visit_macro(self, node);
     }
 
+    fn visit_path(&mut self, node: &'ast syn::Path) {
+        if let Some(name) = path_last_name(node) {
+            if let Some(reply) = name.to_string().strip_prefix("send_reply_") {
+                self.sent_replies.insert(reply.to_owned());
+            }
+        }
+        visit::visit_path(self, node);
+    }
+
     fn visit_item_fn(&mut self, node: &'ast ItemFn) {
+        if !self.is_group_enabled(&node.attrs) {
+            return;
+        }
         for attr in &node.attrs {
             if path_last_name(&attr.path).map_or(false, |i| i == "klipper_command") {
                 check_error!(self, self.process_command(node));
@@ -284,6 +844,38 @@ impl<'ast> Visit<'ast> for Processor {
         }
     }
 
+    fn visit_item_impl(&mut self, node: &'ast ItemImpl) {
+        if check_is_disabled(&node.attrs) {
+            return;
+        }
+        let outer = self.current_impl.replace((*node.self_ty).clone());
+        visit::visit_item_impl(self, node);
+        self.current_impl = outer;
+    }
+
+    fn visit_impl_item_method(&mut self, node: &'ast ImplItemMethod) {
+        if !self.is_group_enabled(&node.attrs) {
+            return;
+        }
+        if node
+            .attrs
+            .iter()
+            .any(|attr| path_last_name(&attr.path).is_some_and(|i| i == "klipper_command"))
+        {
+            let func = ItemFn {
+                attrs: node.attrs.clone(),
+                vis: node.vis.clone(),
+                sig: node.sig.clone(),
+                block: Box::new(node.block.clone()),
+            };
+            check_error!(self, self.process_command(&func));
+        }
+
+        if check_is_enabled(&node.attrs) {
+            visit::visit_impl_item_method(self, node);
+        }
+    }
+
     fn visit_item_const(&mut self, node: &'ast ItemConst) {
         for attr in &node.attrs {
             if path_last_name(&attr.path).map_or(false, |i| i == "klipper_constant") {
@@ -294,10 +886,22 @@ impl<'ast> Visit<'ast> for Processor {
         visit::visit_item_const(self, node)
     }
 
+    fn visit_item_struct(&mut self, node: &'ast ItemStruct) {
+        if check_is_enabled(&node.attrs) && has_derive(&node.attrs, "KlipperTransparent") {
+            check_error!(self, self.process_transparent(node));
+        }
+        visit::visit_item_struct(self, node)
+    }
+
     fn visit_item_mod(&mut self, node: &'ast ItemMod) {
         if check_is_disabled(&node.attrs) {
             return;
         }
+        let mut module_path = self.current_module.clone();
+        module_path.push(node.ident.clone());
+        if self.skip_modules.contains(&module_path) {
+            return;
+        }
         let pop = if node.content.is_some() {
             self.current_module.push(node.ident.clone());
             true
@@ -321,7 +925,29 @@ impl Processor {
     }
 
     fn process_one(&mut self, task: Task) -> Result<()> {
+        // A file reachable through several `mod` declarations, e.g. because of a module cycle or
+        // a symlink, is only processed once
+        let canonical = std::fs::canonicalize(&task.path)?;
+        if !self.visited_files.insert(canonical) {
+            let module = std::iter::once("crate".to_string())
+                .chain(task.module_path.iter().map(Ident::to_string))
+                .collect::<Vec<_>>();
+            println!(
+                "cargo:warning=Skipping {} (module {}), the file was already processed",
+                task.path.display(),
+                module.join("::")
+            );
+            return Ok(());
+        }
         println!("cargo:rerun-if-changed={}", task.path.display());
+        // Cargo rescans watched directories recursively, so adding a module file next to an
+        // existing one, or in a new subdirectory, reruns the build script even before any
+        // tracked file declares it
+        if let Some(dir) = task.path.parent() {
+            if self.watched_dirs.insert(dir.to_owned()) {
+                println!("cargo:rerun-if-changed={}", dir.display());
+            }
+        }
         let content = std::fs::read_to_string(&task.path)?;
         let ast = syn::parse_file(&content)?;
         self.current_file = Some(task.path);
@@ -374,6 +1000,9 @@ impl Processor {
     fn process_enumeration(&mut self, mac: &Macro) -> Result<()> {
         let enumeration = mac.parse_body::<Enumeration>()?;
         self.add_enum(enumeration.dictionary_name(), enumeration.to_dictionary());
+        // Enumerations can be used as command arguments, carried as their numeric value
+        self.transparent_types
+            .insert(enumeration.ident().to_string(), syn::parse_quote!(u32));
         Ok(())
     }
 
@@ -386,12 +1015,20 @@ impl Processor {
     fn process_klipper_shutdown(&mut self, mac: &Macro) -> Result<()> {
         let ss = mac.parse_body::<Shutdown>()?;
         self.static_strings.insert(ss.msg);
+        self.add_shutdown_reply();
+        Ok(())
+    }
+
+    fn add_shutdown_reply(&mut self) {
+        // Sent by the generated dispatcher
+        self.sent_replies.insert("shutdown".into());
         if !self.messages.contains_key("shutdown") {
             self.add_message(
                 "shutdown".into(),
                 Message::Reply(Reply {
                     name: format_ident!("shutdown"),
                     id: None,
+                    chunked: false,
                     args: vec![
                         reply::Arg {
                             name: format_ident!("clock"),
@@ -407,25 +1044,123 @@ impl Processor {
                 }),
             );
         }
-        Ok(())
+    }
+
+    // Commands of a disabled group are compiled out by `#[klipper_command]`, so neither the
+    // command nor any messages sent from it may be collected
+    fn is_group_enabled(&mut self, attrs: &[syn::Attribute]) -> bool {
+        // Malformed attributes are reported by `process_command`
+        let group = match CommandAttrs::from_attrs(attrs) {
+            Ok(CommandAttrs { group: Some(g), .. }) => g,
+            _ => return true,
+        };
+        let enabled = self.enabled_groups.contains(&group);
+        self.used_groups.insert(group);
+        enabled
     }
 
     fn process_command(&mut self, func: &ItemFn) -> Result<()> {
         let mut c = parse2::<Command>(func.to_token_stream())?;
         c.module = Some(self.current_module.clone());
+        c.impl_type = self.current_impl.clone();
         if check_is_enabled(&func.attrs) {
-            self.add_message(c.name.to_string(), Message::Command(c));
+            self.check_reserved(
+                "command",
+                &c.name.to_string(),
+                c.id,
+                &self.source_location(&func.sig.ident),
+            );
+            if c.fallible {
+                self.add_shutdown_reply();
+            }
+            if self.emit_descriptions {
+                if let Some(doc) = doc_comment(&func.attrs) {
+                    self.command_docs.insert(c.name.to_string(), doc);
+                }
+            }
+            self.add_message(c.name.to_string(), Message::Command(Box::new(c)));
         }
         Ok(())
     }
 
     fn process_reply(&mut self, mac: &Macro) -> Result<()> {
         let mut reply = parse2::<Reply>(mac.tokens.clone())?;
+        self.check_reserved(
+            "reply",
+            &reply.name.to_string(),
+            reply.id,
+            &self.source_location(&reply.name),
+        );
         reply.clear_arg_values();
+        // Chunking only affects how a call site sends the reply, not the reply itself
+        reply.chunked = false;
+        self.check_reply_signature(&reply);
+        self.sent_replies.insert(reply.name.to_string());
         self.add_message(reply.name.to_string(), Message::Reply(reply));
         Ok(())
     }
 
+    // Replies may be sent from several places, which must all agree on the arguments. This gives
+    // a more precise error than the generic duplicate check in `add_message`.
+    fn check_reply_signature(&self, reply: &Reply) {
+        let existing = match self.messages.get(&reply.name.to_string()) {
+            Some(Message::Reply(r)) => r,
+            _ => return,
+        };
+        if existing.args == reply.args {
+            return;
+        }
+        let describe = |r: &Reply| {
+            r.args
+                .iter()
+                .map(|a| format!("{}: {}", a.name, a.type_.to_token_stream()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        panic!(
+            "Reply '{}' sent at {} with {} argument(s) ({}), but it was already declared in {} with {} argument(s) ({})",
+            reply.name,
+            self.source_location(&reply.name),
+            reply.args.len(),
+            describe(reply),
+            self.message_origins[&reply.name.to_string()],
+            existing.args.len(),
+            describe(existing),
+        );
+    }
+
+    // Returns the `file:line:column` of an identifier in the file being processed
+    fn source_location(&self, ident: &Ident) -> String {
+        let start = ident.span().start();
+        format!(
+            "{}:{}:{}",
+            self.current_file
+                .as_ref()
+                .map_or("?".into(), |f| f.display().to_string()),
+            start.line,
+            start.column + 1
+        )
+    }
+
+    // The built-in `identify` messages are registered before the sources are processed, so a
+    // collision would otherwise only show up as a confusing duplicate in `add_message`
+    fn check_reserved(&self, kind: &str, name: &str, id: Option<u16>, location: &str) {
+        for (reserved, reserved_id) in RESERVED_MESSAGES {
+            if name == reserved {
+                panic!(
+                    "The {} '{}' at {} collides with Anchor's built-in '{}', which is reserved for the identify handshake. Rename the {}.",
+                    kind, name, location, reserved, kind
+                );
+            }
+            if id == Some(reserved_id) {
+                panic!(
+                    "The {} '{}' at {} uses id {}, which is reserved for Anchor's built-in '{}'",
+                    kind, name, location, reserved_id, reserved
+                );
+            }
+        }
+    }
+
     fn process_output(&mut self, mac: &Macro) -> Result<()> {
         let mut output = parse2::<Output>(mac.tokens.clone())?;
         output.clear_arg_values();
@@ -433,6 +1168,12 @@ impl Processor {
         Ok(())
     }
 
+    fn process_transparent(&mut self, node: &ItemStruct) -> Result<()> {
+        let t = parse2::<Transparent>(node.to_token_stream())?;
+        self.transparent_types.insert(t.ident.to_string(), t.inner);
+        Ok(())
+    }
+
     fn process_config_generate(&mut self, mac: &Macro) -> Result<()> {
         if self.generate_cfg.is_some() {
             return Err(anyhow::anyhow!(
@@ -449,16 +1190,12 @@ impl Processor {
         }
 
         let name = node.ident.to_string();
-        let expr = &node.expr;
-        let value: serde_json::Value = if let Ok(v) = parse2::<LitInt>(expr.to_token_stream()) {
-            v.base10_parse::<u32>()?.into()
-        } else if let Ok(v) = parse2::<LitStr>(expr.to_token_stream()) {
-            v.value().into()
-        } else {
-            panic!(
+        let value = match constant_value(&node.expr)? {
+            Some(v) => v,
+            None => panic!(
                 "Can't understand constant {}, only types convertable to JSON are supported",
                 name
-            );
+            ),
         };
 
         if self.dictionary.config.contains_key(&name) {
@@ -470,12 +1207,29 @@ impl Processor {
     }
 
     fn add_message(&mut self, name: String, message: Message) {
+        let origin = self.current_origin();
         if let Some(current) = self.messages.get(&name) {
             if current != &message {
-                panic!("A command named {} already exists", name);
+                panic!(
+                    "A command named {} already exists (declared in {} and {})",
+                    name, self.message_origins[&name], origin
+                );
+            }
+        }
+        self.messages.insert(name.clone(), message);
+        self.message_origins.entry(name).or_insert(origin);
+    }
+
+    fn current_origin(&self) -> String {
+        match &self.current_file {
+            None => "anchor".into(),
+            Some(file) => {
+                let module = std::iter::once("crate".to_string())
+                    .chain(self.current_module.iter().map(Ident::to_string))
+                    .collect::<Vec<_>>();
+                format!("{} (module {})", file.display(), module.join("::"))
             }
         }
-        self.messages.insert(name, message);
     }
 
     fn add_enum(&mut self, name: String, enumeration: DictionaryEnumeration) {
@@ -486,11 +1240,13 @@ impl Processor {
     }
 
     fn add_identify(&mut self) {
+        self.sent_replies.insert("identify_response".into());
         self.add_message(
             "identify_response".into(),
             Message::Reply(Reply {
                 name: format_ident!("identify_response"),
                 id: Some(0),
+                chunked: false,
                 args: vec![
                     reply::Arg {
                         name: format_ident!("offset"),
@@ -508,12 +1264,17 @@ impl Processor {
 
         self.add_message(
             "identify".into(),
-            Message::Command(Command {
+            Message::Command(Box::new(Command {
                 name: format_ident!("identify"),
                 id: Some(1),
                 module: None,
+                impl_type: None,
                 handler_name: format_ident!("handle_identify"),
                 has_context: false,
+                config_only: false,
+                fallible: false,
+                raw: false,
+                reject_in_shutdown: false,
                 args: vec![
                     command::Arg {
                         name: format_ident!("offset"),
@@ -524,10 +1285,137 @@ impl Processor {
                         type_: syn::parse_str("u32").unwrap(),
                     },
                 ],
-            }),
+            })),
+        );
+    }
+
+    fn add_dump_dictionary(&mut self) {
+        self.add_message(
+            "anchor_dump_dict".into(),
+            Message::Command(Box::new(Command {
+                name: format_ident!("anchor_dump_dict"),
+                id: None,
+                module: None,
+                impl_type: None,
+                handler_name: format_ident!("handle_dump_dict"),
+                has_context: false,
+                config_only: false,
+                fallible: false,
+                raw: false,
+                reject_in_shutdown: false,
+                args: vec![],
+            })),
         );
     }
 
+    fn unknown_command_output() -> Output {
+        Output::from_format("Unknown command %u", false).unwrap()
+    }
+
+    fn add_registered_message(&mut self, message: Message) {
+        let name = match &message {
+            Message::Reply(r) => r.name.to_string(),
+            Message::Output(o) => o.format.clone(),
+            Message::Command(_) => unreachable!(),
+        };
+        if let Message::Reply(r) = &message {
+            self.check_reserved("reply", &name, r.id, "the build script");
+        }
+        self.message_origins
+            .insert(name.clone(), "the build script".into());
+        self.add_message(name, message);
+    }
+
+    fn add_unknown_command_output(&mut self) {
+        let output = Self::unknown_command_output();
+        self.add_message(output.format.clone(), Message::Output(output));
+    }
+
+    fn check_unused_replies(&self, deny: bool) {
+        let unused: Vec<_> = self
+            .messages
+            .iter()
+            .filter(|(name, m)| {
+                matches!(m, Message::Reply(_)) && !self.sent_replies.contains(name.as_str())
+            })
+            .map(|(name, _)| format!("'{}' (declared in {})", name, self.message_origins[name]))
+            .collect();
+        if unused.is_empty() {
+            return;
+        }
+        let msg = format!("Replies never sent: {}", unused.join(", "));
+        if deny {
+            panic!("{}", msg);
+        }
+        println!("cargo:warning={}", msg);
+    }
+
+    fn check_aux_outputs(&self) {
+        let has_aux = self
+            .generate_cfg
+            .as_ref()
+            .is_some_and(|cfg| cfg.aux_transport.is_some());
+        for m in self.messages.values() {
+            if let Message::Output(o) = m {
+                if o.aux && !has_aux {
+                    panic!(
+                        "Output \"{}\" uses the aux channel, but no aux_transport is configured",
+                        o.format
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_message_sizes(&self, buffer_size: usize) {
+        const FRAME_OVERHEAD: usize = 5;
+        const FRAME_LENGTH_MAX: usize = 64;
+        let limit = buffer_size.min(FRAME_LENGTH_MAX);
+        for m in self.messages.values() {
+            let (desc, types): (_, Vec<_>) = match m {
+                Message::Command(_) => continue,
+                Message::Reply(r) => (
+                    format!("reply '{}'", r.name),
+                    r.args.iter().map(|a| &a.type_).collect(),
+                ),
+                Message::Output(o) => (
+                    format!("output \"{}\"", o.format),
+                    o.args.iter().map(|a| &a.type_).collect(),
+                ),
+            };
+            let id_size = if m.id().unwrap() < 96 { 1 } else { 2 };
+            let size = FRAME_OVERHEAD
+                + id_size
+                + types
+                    .into_iter()
+                    .map(|t| msg_desc::max_fixed_size(t, &self.transparent_types))
+                    .sum::<usize>();
+            if size > limit {
+                panic!(
+                    "The {} may encode to {} bytes, which exceeds the output buffer size of {} bytes",
+                    desc, size, limit
+                );
+            }
+        }
+    }
+
+    fn check_generated_names(&self) {
+        let mut names: BTreeMap<String, String> = BTreeMap::new();
+        for m in self.messages.values() {
+            let (generated, desc) = match m {
+                Message::Command(c) => (c.handler_fn_name(), format!("command '{}'", c.name)),
+                Message::Reply(r) => (r.sender_fn_name(), format!("reply '{}'", r.name)),
+                Message::Output(o) => (o.sender_fn_name(), format!("output \"{}\"", o.format)),
+            };
+            if let Some(other) = names.insert(generated.to_string(), desc.clone()) {
+                panic!(
+                    "The {} and the {} both generate a function named {}. Rename one of them.",
+                    other, desc, generated
+                );
+            }
+        }
+    }
+
     fn assign_ids(&mut self) {
         self.assign_command_ids();
     }
@@ -580,18 +1468,50 @@ impl Processor {
         v as i16
     }
 
+    fn write_id_map(&self, path: &Path) {
+        let mut ids: BTreeMap<&str, BTreeMap<String, i16>> = BTreeMap::new();
+        for m in self.messages.values() {
+            let (kind, name, id) = match m {
+                Message::Command(c) => ("commands", c.name.to_string(), c.id),
+                Message::Reply(r) => ("responses", r.name.to_string(), r.id),
+                Message::Output(o) => ("output", o.format.clone(), o.id),
+            };
+            ids.entry(kind)
+                .or_default()
+                .insert(name, Self::convert_id(id.unwrap()));
+        }
+        let mut map = serde_json::Map::new();
+        map.insert("version".into(), self.dictionary.version.clone().into());
+        for kind in ["commands", "responses", "output"] {
+            map.insert(
+                kind.into(),
+                serde_json::to_value(ids.remove(kind).unwrap_or_default()).unwrap(),
+            );
+        }
+        let json = serde_json::Value::Object(map).to_string();
+        std::fs::write(path, json)
+            .unwrap_or_else(|e| panic!("Could not write id map {}: {}", path.display(), e));
+    }
+
     fn finalize_dictionary(&mut self) {
         for m in self.messages.values() {
             match m {
                 Message::Command(c) => {
-                    self.dictionary
-                        .commands
-                        .insert(c.get_desc_string(), Self::convert_id(c.id.unwrap()));
+                    self.dictionary.commands.insert(
+                        c.get_desc_string(&self.transparent_types),
+                        Self::convert_id(c.id.unwrap()),
+                    );
+                    if let Some(doc) = self.command_docs.get(&c.name.to_string()) {
+                        self.dictionary
+                            .descriptions
+                            .insert(c.name.to_string(), doc.clone());
+                    }
                 }
                 Message::Reply(r) => {
-                    self.dictionary
-                        .responses
-                        .insert(r.get_desc_string(), Self::convert_id(r.id.unwrap()));
+                    self.dictionary.responses.insert(
+                        r.get_desc_string(&self.transparent_types),
+                        Self::convert_id(r.id.unwrap()),
+                    );
                 }
                 Message::Output(o) => {
                     self.dictionary
@@ -613,8 +1533,77 @@ impl Processor {
     fn write(self, target: &mut impl Write) -> Result<()> {
         let dispatcher = self.write_message_dispatcher();
         let message_handlers = self.write_message_handlers();
+        let message_decoders = self.write_message_decoders();
         let static_string_ids = self.write_static_string_ids();
+        let arg_names = cfg!(feature = "introspection").then(|| {
+            let consts = self.write_arg_names();
+            quote! {
+                pub mod arg_names {
+                    #(#consts)*
+                }
+            }
+        });
         let data_dictionary = self.write_data_dictionary();
+        let serial = match &self.serial {
+            Some(serial) => quote! { Some(#serial) },
+            None => quote! { None },
+        };
+        let (constant_names, constant_values): (Vec<_>, Vec<_>) = self
+            .dictionary
+            .config
+            .iter()
+            .map(|(name, value)| match value {
+                serde_json::Value::String(s) => (name, s.clone()),
+                v => (name, v.to_string()),
+            })
+            .unzip();
+        let command_ids: Vec<u16> = self
+            .messages
+            .values()
+            .filter_map(|m| match m {
+                Message::Command(c) => c.id,
+                _ => None,
+            })
+            .collect();
+        let (command_names, command_name_ids): (Vec<_>, Vec<_>) = self
+            .messages
+            .values()
+            .filter_map(|m| match m {
+                Message::Command(c) => c.id.map(|id| (c.name.to_string(), id)),
+                _ => None,
+            })
+            .unzip();
+        let num_commands = command_ids.len();
+        let command_id_limit = command_ids.iter().max().map_or(0, |id| *id as usize + 1);
+        let num_responses = self
+            .messages
+            .values()
+            .filter(|m| matches!(m, Message::Reply(_)))
+            .count();
+
+        let aux_transport = self
+            .generate_cfg
+            .as_ref()
+            .and_then(|cfg| cfg.aux_transport.as_ref())
+            .map(|(aux_name, aux_type)| {
+                quote! {
+                    type AuxOutput = &'static #aux_type;
+
+                    pub(crate) struct AuxConfig;
+
+                    impl ::anchor::transport::Config for AuxConfig {
+                        type TransportOutput = AuxOutput;
+                        type Context<'ctx> = ();
+
+                        fn dispatch(cmd: u16, frame: &mut &[u8], context: &mut ()) -> Result<(), ::anchor::encoding::ReadError> {
+                            Err(::anchor::encoding::ReadError::UnknownCommand)
+                        }
+                    }
+
+                    pub(crate) const AUX_CONFIG: AuxConfig = AuxConfig;
+                    pub(crate) static AUX_TRANSPORT: Transport<AuxConfig> = Transport::new(&AUX_CONFIG, &#aux_name);
+                }
+            });
 
         let cfg_opts = self.generate_cfg.as_ref().map(|cfg| {
             let (transport_name, transport_type) = &cfg.transport.as_ref().unwrap();
@@ -638,9 +1627,14 @@ impl Processor {
                     use super::*;
                     #(#message_handlers)*
                 }
+                #[cfg(test)]
+                pub mod message_decoders {
+                    #(#message_decoders)*
+                }
                 pub mod static_strings {
                     #(#static_string_ids)*
                 }
+                #arg_names
 
                 #cfg_opts
 
@@ -655,14 +1649,61 @@ impl Processor {
                 pub(crate) const CONFIG: Config = Config;
                 pub(crate) static TRANSPORT: Transport<Config> = Transport::new(&CONFIG, &TRANSPORT_OUTPUT);
 
+                /// Runs the handler of the command `name` on the encoded arguments in `data`
+                ///
+                /// The command is dispatched like a received one, but regardless of the config
+                /// phase and shutdown state. Returns an error if the command is unknown, or
+                /// `data` doesn't hold exactly its arguments.
+                #[cfg(test)]
+                pub fn invoke_command(name: &str, mut data: &[u8], context: &mut Context<'_>) -> Result<(), ::anchor::encoding::ReadError> {
+                    let cmd = match name {
+                        #(#command_names => #command_name_ids,)*
+                        _ => return Err(::anchor::encoding::ReadError::UnknownCommand),
+                    };
+                    <Config as ::anchor::transport::Config>::dispatch(cmd, &mut data, context)?;
+                    if !data.is_empty() {
+                        return Err(::anchor::encoding::ReadError::Invalid);
+                    }
+                    Ok(())
+                }
+
+                #aux_transport
+
                 #data_dictionary
+
+                /// The serial set with `ConfigBuilder::set_serial`
+                pub const SERIAL: Option<&str> = #serial;
+
+                /// The constants of the (base) dictionary as name and value pairs
+                ///
+                /// Strings are given as is, other values as JSON.
+                pub const CONSTANTS: &[(&str, &str)] = &[#((#constant_names, #constant_values)),*];
+
+                /// The number of commands in the dictionary, including the built-in ones
+                pub const NUM_COMMANDS: usize = #num_commands;
+
+                /// The number of responses in the dictionary, including the built-in ones
+                pub const NUM_RESPONSES: usize = #num_responses;
+
+                /// One more than the highest command id, for tables indexed by command id
+                ///
+                /// Commands, responses and output messages share the ids, so this is usually
+                /// larger than `NUM_COMMANDS`.
+                pub const COMMAND_ID_LIMIT: usize = #command_id_limit;
             }
         )?;
         Ok(())
     }
 
+    fn has_unit_context(&self) -> bool {
+        self.generate_cfg
+            .as_ref()
+            .is_none_or(GenerateConfig::has_unit_context)
+    }
+
     fn write_message_dispatcher(&self) -> TokenStream {
         let mut handlers = vec![None; 16384];
+        let ctx_arg = (!self.has_unit_context()).then(|| quote! { , context });
 
         for m in self.messages.values() {
             let id = m.id().unwrap();
@@ -672,50 +1713,179 @@ impl Processor {
             if let Message::Command(c) = m {
                 let handler = c.handler_fn_name();
                 handlers[id as usize] = Some(quote! {
-                    #id => message_handlers::#handler(frame, context),
+                    #id => message_handlers::#handler(frame #ctx_arg),
                 });
             }
         }
 
         let handlers: Vec<_> = handlers.into_iter().flatten().collect();
+        let config_only: Vec<_> = self
+            .messages
+            .values()
+            .filter_map(|m| match m {
+                Message::Command(c) if c.config_only => c.id,
+                _ => None,
+            })
+            .collect();
+        let rejected_in_shutdown: Vec<_> = self
+            .messages
+            .values()
+            .filter_map(|m| match m {
+                Message::Command(c) if c.reject_in_shutdown => c.id,
+                _ => None,
+            })
+            .collect();
+
+        let unknown = if self.report_unknown_commands {
+            let sender = Self::unknown_command_output().sender_fn_name();
+            quote! {
+                unknown_cmd => {
+                    message_handlers::#sender(unknown_cmd as u32);
+                    Err(::anchor::encoding::ReadError::UnknownCommand)
+                }
+            }
+        } else {
+            quote! {
+                _unknown_cmd => Err(::anchor::encoding::ReadError::UnknownCommand),
+            }
+        };
 
         quote! {
+            fn is_config_only(cmd: u16) -> bool {
+                match cmd {
+                    #(#config_only => true,)*
+                    _ => false,
+                }
+            }
+
+            fn is_rejected_in_shutdown(cmd: u16) -> bool {
+                match cmd {
+                    #(#rejected_in_shutdown => true,)*
+                    _ => false,
+                }
+            }
+
+            // `Config::dispatch` always takes the context, so the parameter stays with a unit
+            // context too. It is just not passed on to the handlers then.
             fn dispatch(cmd: u16, frame: &mut &[u8], context: &mut Context) -> Result<(), ::anchor::encoding::ReadError> {
                 match cmd {
                     #(#handlers)*
-                    _unknown_cmd => Err(::anchor::encoding::ReadError),
+                    #unknown
                 }
             }
         }
     }
 
     fn write_message_handlers(&self) -> Vec<TokenStream> {
+        let unit_context = self.has_unit_context();
+        let inline = self.inline_handlers.then(|| quote! { #[inline(always)] });
         self.messages
             .values()
             .map(|m| match m {
                 Message::Command(c) => {
                     let handler_name = c.handler_fn_name();
 
+                    let payload = format_ident!("payload");
                     let mut args = Vec::new();
                     let mut call_args = Vec::new();
+                    if c.raw {
+                        // The payload runs to the end of the frame, which leaves nothing for
+                        // further commands
+                        args.push(quote! {
+                            let #payload: &[u8] = ::core::mem::take(data);
+                        });
+                        call_args.push(&payload);
+                    }
                     for arg in &c.args {
                         let name = &arg.name;
-                        let ty = &arg.type_;
-                        args.push(quote! {
-                            let #name = <#ty as ::anchor::encoding::Readable>::read(data)?;
+                        let optional = msg_desc::optional_inner_type(&arg.type_);
+                        let ty = optional.unwrap_or(&arg.type_);
+                        // Non-protocol types, e.g. `KlipperTransparent` newtypes, may not be
+                        // nameable from here. Let the handler signature determine the type.
+                        if let Some(elem) = msg_desc::slice_element_type(ty) {
+                            // Typed slices are decoded into a buffer on the stack. A frame holds
+                            // at most 64 bytes, which bounds the number of values.
+                            let buf = format_ident!("{}_buf", name);
+                            args.push(quote! {
+                                let mut #buf = [<#elem as Default>::default(); 64 / ::core::mem::size_of::<#elem>()];
+                                let len = ::anchor::encoding::read_slice_into(data, &mut #buf)?;
+                                let #name = &#buf[..len];
+                            });
+                            call_args.push(name);
+                            continue;
+                        }
+                        let read = if msg_desc::is_protocol_type(ty) {
+                            quote! {
+                                let #name = <#ty as ::anchor::encoding::Readable>::read(data)?;
+                            }
+                        } else if let Some(wire) = msg_desc::wire_type(ty, &self.transparent_types) {
+                            // Commands are not length delimited, so a `Readable` consuming more
+                            // or less than its wire encoding would desync the rest of the block.
+                            // Decode the wire type on a copy first to detect this.
+                            quote! {
+                                let #name = {
+                                    let mut expected = *data;
+                                    <#wire as ::anchor::encoding::Readable>::read(&mut expected)?;
+                                    let value = ::anchor::encoding::Readable::read(data)?;
+                                    if data.len() != expected.len() {
+                                        return Err(::anchor::encoding::ReadError::Invalid);
+                                    }
+                                    value
+                                };
+                            }
+                        } else {
+                            quote! {
+                                let #name = ::anchor::encoding::Readable::read(data)?;
+                            }
+                        };
+                        // Optional arguments are trailing, and only read if the frame has more data
+                        args.push(if optional.is_some() {
+                            quote! {
+                                let #name = if data.is_empty() {
+                                    None
+                                } else {
+                                    #read
+                                    Some(#name)
+                                };
+                            }
+                        } else {
+                            read
                         });
                         call_args.push(name);
                     }
 
                     let target = c.target();
-                    let ctx_arg = c.has_context.then(|| quote! {
-                        context,
-                    });
+                    let ctx_arg = match (c.has_context, unit_context) {
+                        (false, _) => None,
+                        (true, false) => Some(quote! { context, }),
+                        (true, true) => Some(quote! { &mut (), }),
+                    };
+                    // With a unit context there is nothing to pass along, so the
+                    // handler is generated without the context parameter.
+                    let ctx_param = (!unit_context).then(|| quote! { , context: &mut Context });
+                    let call = if c.fallible {
+                        quote! {
+                            let result: Result<(), _> = #target(#ctx_arg #(#call_args),*);
+                            let result = result.map_err(::anchor::CommandError::from);
+                            match result {
+                                Ok(()) => {}
+                                Err(::anchor::CommandError::Shutdown(s)) => {
+                                    let _ = send_reply_shutdown(s.clock, s.static_string_id);
+                                }
+                                Err(::anchor::CommandError::Read(e)) => return Err(e),
+                            }
+                        }
+                    } else {
+                        quote! {
+                            #target(#ctx_arg #(#call_args),*);
+                        }
+                    };
                     quote! {
                         #[allow(unused_variables)]
-                        pub fn #handler_name(data: &mut &[u8], context: &mut Context) -> Result<(), ::anchor::encoding::ReadError> {
+                        #inline
+                        pub fn #handler_name(data: &mut &[u8] #ctx_param) -> Result<(), ::anchor::encoding::ReadError> {
                             #(#args)*
-                            #target(#ctx_arg #(#call_args),*);
+                            #call
                             Ok(())
                         }
                     }
@@ -730,8 +1900,16 @@ impl Processor {
                         .map(|a| {
                             let name = &a.name;
                             let type_ = &a.type_;
-                            quote! {
-                                #name: #type_
+                            // Non-protocol types are type checked by `klipper_reply!` at the call
+                            // site, where they can be named
+                            if msg_desc::is_protocol_type(type_) {
+                                quote! {
+                                    #name: #type_
+                                }
+                            } else {
+                                quote! {
+                                    #name: impl ::anchor::encoding::Writable
+                                }
                             }
                         })
                         .collect();
@@ -742,20 +1920,28 @@ impl Processor {
                         .map(|a| {
                             let name = &a.name;
                             let type_ = &a.type_;
-                            quote! {
-                                <#type_ as ::anchor::encoding::Writable>::write(&#name, output);
+                            if msg_desc::is_protocol_type(type_) {
+                                quote! {
+                                    <#type_ as ::anchor::encoding::Writable>::write(&#name, output);
+                                }
+                            } else {
+                                quote! {
+                                    ::anchor::encoding::Writable::write(&#name, output);
+                                }
                             }
                         })
                         .collect();
 
+                    // The message id is VLQ encoded like any other integer, so ids of 128 and up
+                    // take more than one byte
                     quote! {
-                        pub fn #name ( #(#args),* ) {
+                        pub fn #name ( #(#args),* ) -> Result<(), ::anchor::SendError> {
                             TRANSPORT.encode_frame(|output: &mut <Output as TransportOutput>::Output| {
                                 #[allow(unused_imports)]
                                 use ::anchor::encoding::*;
                                 <u16 as ::anchor::encoding::Writable>::write(&#id, output);
                                 #(#writers)*
-                            });
+                            })
                         }
                     }
                 }
@@ -789,9 +1975,15 @@ impl Processor {
                         })
                         .collect();
 
+                    let (transport, output) = if o.aux {
+                        (quote! { AUX_TRANSPORT }, quote! { AuxOutput })
+                    } else {
+                        (quote! { TRANSPORT }, quote! { Output })
+                    };
+
                     quote! {
                         pub fn #name ( #(#args),* ) {
-                            TRANSPORT.encode_frame(|output: &mut <Output as TransportOutput>::Output| {
+                            let _ = #transport.encode_frame(|output: &mut <#output as TransportOutput>::Output| {
                                 #[allow(unused_imports)]
                                 use ::anchor::encoding::*;
                                 <u16 as ::anchor::encoding::Writable>::write(&#id, output);
@@ -810,28 +2002,424 @@ impl Processor {
             .iter()
             .map(|(ss, idx)| {
                 let compile_name = ss.compile_name();
+                match &self.static_string_prefix {
+                    Some(prefix) => {
+                        let name = format_ident!("{}{}", prefix, idx);
+                        let doc = format!(" `{}`", ss.0);
+                        quote! {
+                            #[doc = #doc]
+                            pub const #name: u16 = #idx;
+                            #[doc(hidden)]
+                            pub const #compile_name: u16 = #name;
+                        }
+                    }
+                    None => quote! {
+                        pub const #compile_name: u16 = #idx;
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn write_message_decoders(&self) -> Vec<TokenStream> {
+        self.messages
+            .values()
+            .filter_map(|m| match m {
+                Message::Command(c) => Some(c),
+                _ => None,
+            })
+            // The returned types must be nameable from here, which rules out non-protocol
+            // types. Typed slices are decoded into a temporary buffer, and can't be returned.
+            // Raw commands have nothing to decode.
+            .filter(|c| {
+                !c.raw
+                    && c.args.iter().all(|a| {
+                    msg_desc::is_protocol_type(&a.type_)
+                        && msg_desc::slice_element_type(&a.type_).is_none()
+                })
+            })
+            .map(|c| {
+                let fn_name = format_ident!("decode_{}", c.name.unraw());
+                let names: Vec<_> = c.args.iter().map(|a| &a.name).collect();
+                let types: Vec<_> = c.args.iter().map(|a| &a.type_).collect();
                 quote! {
-                    pub const #compile_name: u16 = #idx;
+                    pub fn #fn_name(mut data: &[u8]) -> Result<(#(#types,)*), ::anchor::encoding::ReadError> {
+                        let data = &mut data;
+                        #(
+                            let #names = <#types as ::anchor::encoding::Readable>::read(data)?;
+                        )*
+                        if !data.is_empty() {
+                            return Err(::anchor::encoding::ReadError::Invalid);
+                        }
+                        Ok((#(#names,)*))
+                    }
                 }
             })
             .collect()
     }
 
+    fn write_arg_names(&self) -> Vec<TokenStream> {
+        self.messages
+            .values()
+            .filter_map(|m| {
+                // Names match the ones in the dictionary message descriptors
+                let (const_name, names): (_, Vec<_>) = match m {
+                    Message::Command(c) => (
+                        format_ident!("COMMAND_{}", c.name.unraw().to_string().to_uppercase()),
+                        c.args.iter().map(|a| a.name.to_string()).collect(),
+                    ),
+                    Message::Reply(r) => (
+                        format_ident!("REPLY_{}", r.name.unraw().to_string().to_uppercase()),
+                        r.args.iter().map(|a| a.name.to_string()).collect(),
+                    ),
+                    // Output arguments are positional, and have no names
+                    Message::Output(_) => return None,
+                };
+                Some(quote! {
+                    pub const #const_name: &[&str] = &[#(#names),*];
+                })
+            })
+            .collect()
+    }
+
     fn write_data_dictionary(&self) -> TokenStream {
-        let data = self.dictionary.to_compressed();
+        let data = self.dictionary.to_compressed(self.dictionary_compression);
         let len = data.len();
+        let json = self.emit_json_dictionary.then(|| {
+            let json = self.dictionary.to_json();
+            quote! {
+                pub const DICT_JSON: &str = #json;
+            }
+        });
+        let dump = self.dump_dictionary_command.then(|| {
+            quote! {
+                fn handle_dump_dict() {
+                    // Fill whole frames, leaving room for the message id, and the offset and data
+                    // length at their largest encoding
+                    const CHUNK: usize = ::anchor::transport::MESSAGE_PAYLOAD_MAX - 7;
+                    let data = dictionary();
+                    let mut pos = 0;
+                    loop {
+                        let end = (pos + CHUNK).min(data.len());
+                        let _ = message_handlers::send_reply_identify_response(pos as u32, &data[pos..end]);
+                        // Like for `identify`, a short chunk, possibly empty, marks the end
+                        if end - pos < CHUNK {
+                            break;
+                        }
+                        pos = end;
+                    }
+                }
+            }
+        });
+        let selector = if self.variants.is_empty() {
+            quote! {
+                fn dictionary() -> &'static [u8] {
+                    DATA
+                }
+            }
+        } else {
+            let (names, data): (Vec<_>, Vec<_>) = self
+                .variants
+                .iter()
+                .map(|(name, constants)| {
+                    let data = self
+                        .dictionary
+                        .to_compressed_variant(constants, self.dictionary_compression);
+                    let len = data.len();
+                    (name, quote! { &[#(#data),*] as &[u8; #len] })
+                })
+                .unzip();
+            let idx = 1..=names.len() as u8;
+            let idx2 = idx.clone();
+            quote! {
+                static DICTIONARY_VARIANT: ::core::sync::atomic::AtomicU8 = ::core::sync::atomic::AtomicU8::new(0);
+
+                /// Selects the dictionary variant served by `identify`
+                ///
+                /// Returns false, leaving the selection unchanged, if there is no such variant.
+                pub(crate) fn select_dictionary(name: &str) -> bool {
+                    let idx = match name {
+                        #(#names => #idx,)*
+                        _ => return false,
+                    };
+                    DICTIONARY_VARIANT.store(idx, ::core::sync::atomic::Ordering::Relaxed);
+                    true
+                }
+
+                fn dictionary() -> &'static [u8] {
+                    match DICTIONARY_VARIANT.load(::core::sync::atomic::Ordering::Relaxed) {
+                        #(#idx2 => #data,)*
+                        _ => DATA,
+                    }
+                }
+            }
+        };
         quote! {
             const DATA: &[u8; #len] = &[#(#data),*];
+            #json
+            #dump
+            #selector
 
             fn handle_identify(offset: u32, count: u32) {
-                let end = (offset + count).min(DATA.len() as u32);
-                let offset = offset.min(DATA.len() as u32);
-                message_handlers::send_reply_identify_response(offset, &DATA[(offset as usize)..(end as usize)]);
+                // Like Klipper, reply with the requested offset and no data past the end
+                let data = dictionary();
+                let start = (offset as usize).min(data.len());
+                let end = start.saturating_add(count as usize).min(data.len());
+                let _ = message_handlers::send_reply_identify_response(offset, &data[start..end]);
             }
         }
     }
 }
 
+// Joins the lines of the `///` doc comment in `attrs`, if any
+fn doc_comment(attrs: &[syn::Attribute]) -> Option<String> {
+    let lines = attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(s),
+                ..
+            })) => Some(s.value()),
+            _ => None,
+        })
+        .map(|line| line.strip_prefix(' ').map(str::to_string).unwrap_or(line))
+        .collect::<Vec<_>>();
+    let doc = lines.join("\n").trim().to_string();
+    (!doc.is_empty()).then_some(doc)
+}
+
+// Converts an integer or string literal, or an array of these, to JSON
+fn constant_value(expr: &syn::Expr) -> Result<Option<serde_json::Value>> {
+    use syn::Expr;
+    let array = match expr {
+        Expr::Array(a) => a,
+        Expr::Reference(r) => match r.expr.as_ref() {
+            Expr::Array(a) => a,
+            _ => return Ok(None),
+        },
+        _ => {
+            return Ok(if let Ok(v) = parse2::<LitInt>(expr.to_token_stream()) {
+                Some(v.base10_parse::<u32>()?.into())
+            } else if let Ok(v) = parse2::<LitStr>(expr.to_token_stream()) {
+                Some(v.value().into())
+            } else {
+                None
+            })
+        }
+    };
+    let mut values = Vec::new();
+    for elem in &array.elems {
+        // Nested arrays are not supported
+        if matches!(elem, Expr::Array(_) | Expr::Reference(_)) {
+            return Ok(None);
+        }
+        match constant_value(elem)? {
+            Some(v) => values.push(v),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(values.into()))
+}
+
 fn path_last_name(path: &syn::Path) -> Option<&Ident> {
     path.get_ident()
 }
+
+fn has_derive(attrs: &[syn::Attribute], name: &str) -> bool {
+    attrs
+        .iter()
+        .filter(|a| a.path.is_ident("derive"))
+        .filter_map(|a| match a.parse_meta() {
+            Ok(Meta::List(list)) => Some(list.nested),
+            _ => None,
+        })
+        .flatten()
+        .any(|m| match m {
+            NestedMeta::Meta(Meta::Path(p)) => p.segments.last().is_some_and(|s| s.ident == name),
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serializes the builds below, which all write to `OUT_DIR`
+    static OUT_DIR_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Returns the directory the sources of the test `name` are built in
+    fn build_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("anchor_codegen_{}_{}", name, std::process::id()))
+    }
+
+    /// Builds `files`, the first one being the entry, with the options set by `configure`
+    ///
+    /// Returns the generated source, or the message the build panicked with.
+    fn build_files(
+        name: &str,
+        files: &[(&str, &str)],
+        configure: impl FnOnce(ConfigBuilder) -> ConfigBuilder + std::panic::UnwindSafe,
+    ) -> std::result::Result<String, String> {
+        let _lock = OUT_DIR_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = build_dir(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, source) in files {
+            std::fs::write(dir.join(file), source).unwrap();
+        }
+        std::env::set_var("OUT_DIR", &dir);
+
+        let entry = dir.join(files[0].0);
+        let result =
+            std::panic::catch_unwind(|| configure(ConfigBuilder::new().entry(&entry)).build());
+        let generated = std::fs::read_to_string(dir.join("_anchor_config.rs"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Ok(()) => Ok(generated.unwrap()),
+            Err(e) => match e.downcast::<String>() {
+                Ok(msg) => Err(*msg),
+                Err(e) => Err(e.downcast_ref::<&str>().unwrap().to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn duplicate_command_names_both_files() {
+        let files = [
+            ("main.rs", "mod motor;\nmod heater;\n"),
+            ("motor.rs", "#[klipper_command]\nfn enable(oid: u8) {}\n"),
+            (
+                "heater.rs",
+                "#[klipper_command]\nfn enable(oid: u8, on: bool) {}\n",
+            ),
+        ];
+        let msg = match build_files("dup", &files, |b| b) {
+            Ok(_) => panic!("duplicate command was accepted"),
+            Err(msg) => msg,
+        };
+        let dir = build_dir("dup");
+        assert!(
+            msg.starts_with("A command named enable already exists"),
+            "{}",
+            msg
+        );
+        for (file, module) in [("motor.rs", "crate::motor"), ("heater.rs", "crate::heater")] {
+            let origin = format!("{} (module {})", dir.join(file).display(), module);
+            assert!(msg.contains(&origin), "{} does not name {}", msg, origin);
+        }
+    }
+
+    #[test]
+    fn chunked_reply_rejects_non_scalar_arguments() {
+        let parse = |s: &str| syn::parse_str::<Reply>(s);
+        assert!(parse("dump[chunked], oid: u8, offset: u32, data: &[u8]").is_ok());
+        for args in ["name: &str", "values: &[u16]", "other: &[u8]"] {
+            let reply = format!("dump[chunked], {}, offset: u32, data: &[u8]", args);
+            assert!(parse(&reply).is_err(), "{} was accepted", reply);
+        }
+    }
+
+    #[test]
+    fn empty_version_is_rejected() {
+        for version in ["", "  "] {
+            let result = std::panic::catch_unwind(|| check_version(version));
+            assert!(result.is_err(), "{:?} was accepted", version);
+        }
+        let result = build_files("empty_version", &[("main.rs", "")], |b| {
+            b.set_version_components(" ", "")
+        });
+        assert_eq!(result.unwrap_err(), "The version string must not be empty");
+    }
+
+    #[test]
+    fn version_components_are_joined_in_dictionary() {
+        let generated = build_files("version_components", &[("main.rs", "")], |b| {
+            b.set_version_components(" jig ", "v1.2-3-gabcdef\n")
+                .emit_json_dictionary(true)
+        })
+        .unwrap();
+        assert!(
+            generated.contains(r#"\"version\":\"jig v1.2-3-gabcdef\""#),
+            "{}",
+            generated
+        );
+    }
+
+    #[test]
+    fn reference_dictionary_mismatch_is_reported() {
+        let main = "fn report() { klipper_reply!(status, oid: u8 = 1, value: u32 = 2); }";
+        let check = |name: &str, reference: &str| {
+            let path = build_dir(name).join("reference.json");
+            build_files(
+                name,
+                &[("main.rs", main), ("reference.json", reference)],
+                |b| b.check_against(path),
+            )
+        };
+
+        let same = r#"{"commands": {}, "responses": {"status oid=%c value=%u": 3}}"#;
+        assert!(check("reference_same", same).is_ok());
+
+        let reordered = r#"{"commands": {}, "responses": {"status value=%u oid=%c": 3}}"#;
+        let msg = check("reference_reordered", reordered).unwrap_err();
+        assert!(
+            msg.starts_with("Dictionary does not match reference"),
+            "{}",
+            msg
+        );
+        assert!(
+            msg.contains(
+                "responses: 'status oid=%c value=%u' does not match reference 'status value=%u oid=%c'"
+            ),
+            "{}",
+            msg
+        );
+    }
+
+    /// A command taking the context, and one without, for the context fixtures below
+    const CONTEXT_COMMANDS: &str = "
+        #[klipper_command]
+        fn set_pin(context: &mut State, pin: u8) {}
+
+        #[klipper_command]
+        fn ping(value: u8) {}
+    ";
+
+    #[test]
+    fn unit_context_is_not_passed_to_handlers() {
+        let main = format!(
+            "klipper_config_generate!(transport = crate::OUT: crate::Out);\n{}",
+            CONTEXT_COMMANDS
+        );
+        let generated = build_files("unit_context", &[("main.rs", &main)], |b| b).unwrap();
+        assert!(generated.contains("type Context < 'ctx > = () ;"));
+        // `dispatch` keeps the parameter `Config::dispatch` requires
+        assert!(generated
+            .contains("fn dispatch (cmd : u16 , frame : & mut & [u8] , context : & mut Context)"));
+        assert!(generated.contains("message_handlers :: _anchor_set_pin_handler (frame) ,"));
+        assert!(generated.contains("set_pin (& mut () , pin) ;"));
+        assert!(generated.contains("pub fn _anchor_set_pin_handler (data : & mut & [u8]) ->"));
+    }
+
+    #[test]
+    fn real_context_is_passed_to_handlers() {
+        let main = format!(
+            "klipper_config_generate!(transport = crate::OUT: crate::Out, context = &'ctx mut crate::State);\n{}",
+            CONTEXT_COMMANDS
+        );
+        let generated = build_files("real_context", &[("main.rs", &main)], |b| b).unwrap();
+        assert!(generated.contains("type Context < 'ctx > = & 'ctx mut crate :: State ;"));
+        assert!(generated
+            .contains("fn dispatch (cmd : u16 , frame : & mut & [u8] , context : & mut Context)"));
+        assert!(
+            generated.contains("message_handlers :: _anchor_set_pin_handler (frame , context) ,")
+        );
+        assert!(generated.contains(
+            "pub fn _anchor_set_pin_handler (data : & mut & [u8] , context : & mut Context) ->"
+        ));
+        assert!(generated.contains("set_pin (context , pin) ;"));
+        // Handlers not taking the context still get it passed through
+        assert!(generated.contains("message_handlers :: _anchor_ping_handler (frame , context) ,"));
+        assert!(generated.contains("ping (value) ;"));
+    }
+}
@@ -12,24 +12,150 @@ lazy_static::lazy_static! {
         ("u32", "%u"),
         ("i32", "%i"),
         ("& [u8]", "%*s"),
+        // Klipper has no boolean type, its own firmware declares flags like `is_config` as
+        // `%c` too, and Klippy parses them as integers. Don't give `bool` a token of its own,
+        // the host would reject the dictionary.
         ("bool", "%c"),
         ("u8", "%c"),
+        // Sent as an ASCII byte, see `Writable for char`
+        ("char", "%c"),
         ("u16", "%hu"),
         ("i16", "%hi"),
+        // Typed slices are transferred as byte arrays, see `anchor::encoding::SliceElement`
+        ("& [u32]", "%*s"),
+        ("& [i32]", "%*s"),
+        ("& [u16]", "%*s"),
+        ("& [i16]", "%*s"),
     ]);
 }
 
+/// Returns true if the type maps directly to a Klipper data type
+pub fn is_protocol_type(type_: &Type) -> bool {
+    TYPE_MAP.contains_key(type_.to_token_stream().to_string().as_str())
+}
+
+/// Returns the element type if the type is a typed slice like `&[u16]`
+///
+/// Byte arrays (`&[u8]`) are not considered typed slices.
+pub fn slice_element_type(type_: &Type) -> Option<&Type> {
+    match type_ {
+        Type::Reference(r) => match r.elem.as_ref() {
+            Type::Slice(s) if is_protocol_type(type_) && !is_byte_type(&s.elem) => Some(&s.elem),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns the inner type if the type is an `Option<T>`, used for optional command arguments
+pub fn optional_inner_type(type_: &Type) -> Option<&Type> {
+    let segment = match type_ {
+        Type::Path(p) if p.qself.is_none() => p.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+            match args.args.first() {
+                Some(syn::GenericArgument::Type(inner)) => Some(inner),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_byte_type(type_: &Type) -> bool {
+    matches!(type_, Type::Path(p) if p.path.is_ident("u8"))
+}
+
+/// Returns true for the fixed width `Le32` and `Be32` types, which are transferred as byte arrays
+fn is_fixed_width_type(type_: &Type) -> bool {
+    match type_ {
+        Type::Path(p) if p.qself.is_none() => p
+            .path
+            .segments
+            .last()
+            .is_some_and(|s| s.ident == "Le32" || s.ident == "Be32"),
+        _ => false,
+    }
+}
+
+/// Maps the names of `KlipperTransparent` types to their inner type
+pub type TransparentTypes = BTreeMap<String, Type>;
+
+fn resolve_transparent<'a>(mut type_: &'a Type, transparent: &'a TransparentTypes) -> &'a Type {
+    // Bounded to avoid looping forever on a (nonsensical) cycle of newtypes
+    for _ in 0..=transparent.len() {
+        let name = match type_ {
+            Type::Path(p) if p.qself.is_none() => p.path.segments.last().map(|s| &s.ident),
+            _ => None,
+        };
+        match name.and_then(|n| transparent.get(&n.to_string())) {
+            Some(inner) => type_ = inner,
+            None => break,
+        }
+    }
+    type_
+}
+
+/// Returns the maximum number of bytes a scalar argument encodes to
+///
+/// Non-protocol types are assumed to wrap a 32-bit integer, the largest scalar encoding.
+pub fn max_encoded_size(type_: &Type) -> usize {
+    match type_.to_token_stream().to_string().as_str() {
+        "bool" | "u8" | "char" => 2,
+        "u16" | "i16" => 3,
+        _ => 5,
+    }
+}
+
+/// Returns the maximum number of bytes an argument encodes to, not counting byte array contents
+///
+/// Byte arrays, including strings and typed slices, only count their length prefix, as their
+/// length is only known at runtime. Fixed width types count their full encoding.
+pub fn max_fixed_size(type_: &Type, transparent: &TransparentTypes) -> usize {
+    let resolved = resolve_transparent(type_, transparent);
+    if is_fixed_width_type(resolved) {
+        return 5;
+    }
+    match resolved {
+        Type::Reference(_) => 1,
+        _ => max_encoded_size(resolved),
+    }
+}
+
+/// Returns the protocol type a non-protocol argument type is carried as on the wire
+///
+/// Used to check that custom `Readable` implementations consume exactly the bytes of their
+/// encoding. Returns `None` if the type can't be mapped.
+pub fn wire_type(type_: &Type, transparent: &TransparentTypes) -> Option<Type> {
+    let resolved = resolve_transparent(type_, transparent);
+    if is_protocol_type(resolved) {
+        Some(resolved.clone())
+    } else if is_fixed_width_type(resolved) {
+        Some(syn::parse_quote!(&[u8]))
+    } else {
+        None
+    }
+}
+
 pub fn build_message_descriptor<'a>(
     name: &Ident,
     args: impl Iterator<Item = DescArg<'a>>,
+    transparent: &TransparentTypes,
 ) -> String {
     use std::fmt::Write;
     let mut s = name.to_string();
 
     for a in args {
-        let ty = a.type_.to_token_stream().to_string();
+        let resolved = resolve_transparent(a.type_, transparent);
+        let ty = resolved.to_token_stream().to_string();
         let mapped = match TYPE_MAP.get(ty.as_str()) {
             Some(m) => m,
+            None if is_fixed_width_type(resolved) => "%*s",
             None => panic!("Can't map type '{}' to a klipper data type", ty),
         };
         write!(s, " {}={}", a.name, mapped).unwrap();
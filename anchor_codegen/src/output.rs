@@ -2,13 +2,21 @@ use std::collections::BTreeMap;
 
 use crate::static_string::HexName;
 use quote::format_ident;
-use syn::{parse::Parse, token::Comma, Expr, Ident, LitStr, Type};
+use syn::{
+    bracketed,
+    parse::{Error, Parse},
+    token::{Bracket, Comma, Eq},
+    Expr, Ident, LitStr, Type,
+};
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Output {
     pub id: Option<u16>,
     pub format: String,
+    pub aux: bool,
     pub args: Vec<Arg>,
+    /// The format string split into literal text and specifiers, see [`Output::render`]
+    pub template: Vec<FormatSegment>,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -17,11 +25,64 @@ pub struct Arg {
     pub value: Option<Expr>,
 }
 
+/// A piece of an output format string
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FormatSegment {
+    /// Literal text, rendered as is
+    Literal(String),
+    /// A format specifier like `%u` or `%*s`, standing in for the argument at index `arg`
+    Spec { kind: String, arg: usize },
+}
+
+/// A decoded argument value, as passed to [`Output::render`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ArgValue<'a> {
+    /// Any integer argument, including `%c`
+    Int(i64),
+    /// A `%*s` or `%.*s` argument
+    Bytes(&'a [u8]),
+}
+
 impl Output {
     pub fn sender_fn_name(&self) -> Ident {
         format_ident!("send_output_{}", HexName(&self.format, false))
     }
 
+    /// Creates an output message from its format string, without argument values
+    pub fn from_format(format: &str, aux: bool) -> syn::Result<Self> {
+        let template = parse_template(format);
+        Ok(Output {
+            id: None,
+            format: format.into(),
+            aux,
+            args: parse_args(&template),
+            template,
+        })
+    }
+
+    /// Renders the message with decoded argument values, like Klippy's terminal does
+    ///
+    /// Integers are printed in decimal, `%c` included, and byte arrays as (lossy) UTF-8 text.
+    /// Returns `None` if the number of values doesn't match the number of specifiers.
+    ///
+    /// Meant for host side tooling that decodes `klipper_output!` messages.
+    pub fn render(&self, values: &[ArgValue]) -> Option<String> {
+        if values.len() != self.args.len() {
+            return None;
+        }
+        let mut s = String::new();
+        for segment in &self.template {
+            match segment {
+                FormatSegment::Literal(text) => s.push_str(text),
+                FormatSegment::Spec { arg, .. } => match values[*arg] {
+                    ArgValue::Int(v) => s.push_str(&v.to_string()),
+                    ArgValue::Bytes(b) => s.push_str(&String::from_utf8_lossy(b)),
+                },
+            }
+        }
+        Some(s)
+    }
+
     pub fn clear_arg_values(&mut self) {
         for arg in self.args.iter_mut() {
             arg.value = None;
@@ -41,25 +102,92 @@ lazy_static::lazy_static! {
     ]);
 }
 
-fn parse_args(mut fmt: &str) -> syn::Result<Vec<Arg>> {
-    let mut args = vec![];
+/// Splits a format string into literal text and specifiers
+///
+/// A `%` that doesn't start a known specifier is kept as literal text.
+fn parse_template(mut fmt: &str) -> Vec<FormatSegment> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut arg = 0;
     while let Some(pos) = fmt.find('%') {
+        literal.push_str(&fmt[..pos]);
         fmt = &fmt[pos + 1..];
-        for (kind, type_) in TYPE_MAP.iter() {
-            if fmt.starts_with(kind) {
-                let type_ = syn::parse_str(type_).unwrap();
-                args.push(Arg { type_, value: None });
-                break;
+        match TYPE_MAP.keys().find(|kind| fmt.starts_with(*kind)) {
+            Some(kind) => {
+                if !literal.is_empty() {
+                    segments.push(FormatSegment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(FormatSegment::Spec {
+                    kind: format!("%{}", kind),
+                    arg,
+                });
+                arg += 1;
+                fmt = &fmt[kind.len()..];
             }
+            None => literal.push('%'),
         }
     }
-    Ok(args)
+    literal.push_str(fmt);
+    if !literal.is_empty() {
+        segments.push(FormatSegment::Literal(literal));
+    }
+    segments
+}
+
+fn parse_args(template: &[FormatSegment]) -> Vec<Arg> {
+    template
+        .iter()
+        .filter_map(|segment| match segment {
+            FormatSegment::Spec { kind, .. } => Some(Arg {
+                type_: syn::parse_str(TYPE_MAP[&kind[1..]]).unwrap(),
+                value: None,
+            }),
+            FormatSegment::Literal(_) => None,
+        })
+        .collect()
 }
 
 impl Parse for Output {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let mut aux = false;
+
+        // Check for attributes
+        if input.peek(Bracket) {
+            let content;
+            bracketed!(content in input);
+            while !content.is_empty() {
+                let attrib_name: Ident = content.parse()?;
+                content.parse::<Eq>()?;
+                match attrib_name.to_string().as_str() {
+                    "channel" => {
+                        let channel: Ident = content.parse()?;
+                        aux = match channel.to_string().as_str() {
+                            "main" => false,
+                            "aux" => true,
+                            _ => {
+                                return Err(Error::new(
+                                    channel.span(),
+                                    format!("Unknown channel '{}'", channel),
+                                ))
+                            }
+                        };
+                    }
+                    _ => {
+                        return Err(Error::new(
+                            attrib_name.span(),
+                            format!("Unknown attribute '{}'", attrib_name),
+                        ))
+                    }
+                }
+                if !content.is_empty() {
+                    content.parse::<Comma>()?;
+                }
+            }
+        }
+
         let format = input.parse::<LitStr>()?.value();
-        let mut args = parse_args(&format)?;
+        let template = parse_template(&format);
+        let mut args = parse_args(&template);
 
         for arg in args.iter_mut() {
             input.parse::<Comma>()?;
@@ -72,7 +200,9 @@ impl Parse for Output {
             Ok(Output {
                 id: None,
                 format,
+                aux,
                 args,
+                template,
             })
         }
     }
@@ -1,5 +1,5 @@
-use crate::msg_desc::{build_message_descriptor, DescArg};
-use quote::format_ident;
+use crate::msg_desc::{build_message_descriptor, slice_element_type, DescArg, TransparentTypes};
+use quote::{format_ident, ToTokens};
 use syn::{
     bracketed,
     parse::{Error, Parse, ParseStream, Result},
@@ -11,6 +11,7 @@ use syn::{
 pub struct Reply {
     pub name: Ident,
     pub id: Option<u16>,
+    pub chunked: bool,
     pub args: Vec<Arg>,
 }
 
@@ -26,16 +27,60 @@ impl Reply {
         format_ident!("send_reply_{}", self.name)
     }
 
-    pub fn get_desc_string(&self) -> String {
+    pub fn get_desc_string(&self, transparent: &TransparentTypes) -> String {
         build_message_descriptor(
             &self.name,
             self.args.iter().map(|a| DescArg {
                 name: &a.name,
                 type_: &a.type_,
             }),
+            transparent,
         )
     }
 
+    /// Returns the `offset` and data arguments of a chunked reply
+    pub fn chunk_args(&self) -> Option<(&Arg, &Arg)> {
+        let offset = self.args.iter().find(|a| a.name == "offset")?;
+        let data = self.args.iter().find(|a| is_byte_array(&a.type_))?;
+        Some((offset, data))
+    }
+
+    fn validate_chunked(&self) -> Result<()> {
+        let err = |msg: &str| Err(Error::new(self.name.span(), msg));
+        match self.chunk_args() {
+            None => err("A chunked reply needs an `offset: u32` argument and a `&[u8]` argument"),
+            Some((offset, _)) if offset.type_.to_token_stream().to_string() != "u32" => {
+                err("The `offset` argument of a chunked reply must be `u32`")
+            }
+            Some(_) if self.args.iter().filter(|a| is_byte_array(&a.type_)).count() > 1 => {
+                err("A chunked reply can only have one `&[u8]` argument")
+            }
+            Some(_)
+                if self
+                    .args
+                    .iter()
+                    .any(|a| slice_element_type(&a.type_).is_some()) =>
+            {
+                err("A chunked reply can't have typed slice arguments")
+            }
+            // The chunk size is derived from the largest encoding of the other arguments, which
+            // is only bounded for scalars
+            Some((_, data))
+                if self
+                    .args
+                    .iter()
+                    .any(|a| a.name != data.name && matches!(a.type_, Type::Reference(_))) =>
+            {
+                err("Besides the `&[u8]` data, a chunked reply can only have scalar arguments")
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Drops the value expressions, keeping only the signature
+    ///
+    /// Used by the build step, which only needs the signature. The value types are checked
+    /// against the declared types where the reply is sent, see `klipper_reply!`.
     pub fn clear_arg_values(&mut self) {
         for arg in self.args.iter_mut() {
             arg.value = None;
@@ -47,6 +92,7 @@ impl Parse for Reply {
     fn parse(input: ParseStream) -> Result<Self> {
         let name = input.parse()?;
         let mut id = None;
+        let mut chunked = false;
 
         // Check for attributes
         if input.peek(Bracket) {
@@ -55,15 +101,18 @@ impl Parse for Reply {
             let mut first = true;
             while !content.is_empty() {
                 if !first {
-                    input.parse::<Comma>()?;
+                    content.parse::<Comma>()?;
                 }
                 first = false;
                 let attrib_name: Ident = content.parse()?;
-                content.parse::<Eq>()?;
                 match attrib_name.to_string().as_str() {
                     "id" => {
+                        content.parse::<Eq>()?;
                         id = Some(content.parse::<LitInt>()?.base10_parse()?);
                     }
+                    "chunked" => {
+                        chunked = true;
+                    }
                     _ => {
                         return Err(Error::new(
                             attrib_name.span(),
@@ -90,6 +139,19 @@ impl Parse for Reply {
 
             args.push(Arg { name, type_, value });
         }
-        Ok(Reply { name, id, args })
+        let reply = Reply {
+            name,
+            id,
+            chunked,
+            args,
+        };
+        if reply.chunked {
+            reply.validate_chunked()?;
+        }
+        Ok(reply)
     }
 }
+
+fn is_byte_array(type_: &Type) -> bool {
+    type_.to_token_stream().to_string() == "& [u8]"
+}
@@ -0,0 +1,63 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse::Parse, spanned::Spanned, Data, DeriveInput, Error, Fields, Ident, Type};
+
+/// A single field tuple struct encoded as its inner type
+#[derive(Debug)]
+pub struct Transparent {
+    pub ident: Ident,
+    pub inner: Type,
+}
+
+impl Transparent {
+    pub fn to_token_stream(&self) -> TokenStream {
+        let ident = &self.ident;
+        let inner = &self.inner;
+        quote! {
+            impl<'de> ::anchor::encoding::Readable<'de> for #ident {
+                fn read(data: &mut &'de [u8]) -> Result<Self, ::anchor::encoding::ReadError> {
+                    <#inner as ::anchor::encoding::Readable<'de>>::read(data).map(#ident)
+                }
+            }
+
+            impl ::anchor::encoding::Writable for #ident {
+                fn write(&self, output: &mut impl ::anchor::output_buffer::OutputBuffer) {
+                    <#inner as ::anchor::encoding::Writable>::write(&self.0, output)
+                }
+            }
+        }
+    }
+}
+
+impl Parse for Transparent {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let input: DeriveInput = input.parse()?;
+        if !input.generics.params.is_empty() {
+            return Err(Error::new(
+                input.generics.span(),
+                "KlipperTransparent does not support generic types",
+            ));
+        }
+        let field = match &input.data {
+            Data::Struct(s) => match &s.fields {
+                Fields::Unnamed(fields) if fields.unnamed.len() == 1 => &fields.unnamed[0],
+                _ => {
+                    return Err(Error::new(
+                        input.ident.span(),
+                        "KlipperTransparent requires a tuple struct with a single field",
+                    ))
+                }
+            },
+            _ => {
+                return Err(Error::new(
+                    input.ident.span(),
+                    "KlipperTransparent can only be derived for structs",
+                ))
+            }
+        };
+        Ok(Transparent {
+            ident: input.ident,
+            inner: field.ty.clone(),
+        })
+    }
+}
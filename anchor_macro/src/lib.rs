@@ -1,14 +1,19 @@
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 use proc_macro_error::{abort, proc_macro_error};
-use quote::quote;
-use syn::{parse_macro_input, ItemConst};
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, ItemConst, ItemFn};
 
 use anchor_codegen::{
+    command::{Command, CommandAttrs},
     enumeration::Enumeration,
     generate::GenerateConfig,
+    msg_desc::max_encoded_size,
     output::Output,
     reply::Reply,
     static_string::{Shutdown, StaticString},
+    transparent::Transparent,
 };
 
 /// Sends a message to the remote end
@@ -28,26 +33,121 @@ use anchor_codegen::{
 /// ```
 /// klipper_reply!(data, clock: u32 = clock.into(), data: u32);
 /// ```
+///
+/// Each value is checked against its declared type where the reply is sent, so a mismatch like
+/// `clock: u32 = some_u64` is reported at the value, with the declared type as the expected one.
+///
+/// Byte arrays too large for a single message can be sent by marking the reply `[chunked]`. The
+/// reply must have an `offset: u32` and a `&[u8]` argument. The data is split into as many
+/// messages as needed, each a complete reply carrying a piece of the data, with `offset` set to
+/// the given value plus the position of the piece within the data. The other arguments are
+/// repeated in every message:
+/// ```
+/// klipper_reply!(sensor_dump[chunked], oid: u8, offset: u32 = 0, data: &[u8] = &samples);
+/// ```
+/// The host reassembles the data by placing each piece at its offset, in the same way Klippy
+/// reads the data dictionary with `identify`. The last message always carries a shorter piece
+/// than the others, possibly an empty one, which marks the end of the data.
 #[proc_macro_error]
 #[proc_macro]
 pub fn klipper_reply(item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(item as Reply);
+    let send = reply_sender(&input);
+    TokenStream::from(quote! {
+        {
+            let _ = #send;
+        }
+    })
+}
+
+/// Sends a message to the remote end, reporting failure
+///
+/// This works like `klipper_reply!`, but evaluates to a `Result<(), SendError>`. A message that
+/// doesn't fit in a single frame is dropped, and `SendError` is returned, allowing critical
+/// replies to be logged or handled otherwise:
+/// ```
+/// if try_klipper_reply!(sensor_state, oid: u8, data: &[u8] = &buf).is_err() {
+///     // Reply was too large and was not sent
+/// }
+/// ```
+/// For a `[chunked]` reply, sending stops at the first chunk that fails.
+#[proc_macro_error]
+#[proc_macro]
+pub fn try_klipper_reply(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as Reply);
+    TokenStream::from(reply_sender(&input))
+}
+
+// Builds an expression sending the reply, evaluating to a `Result<(), SendError>`
+fn reply_sender(input: &Reply) -> TokenStream2 {
     let sender = input.sender_fn_name();
     let args = input
         .args
         .iter()
-        .map(|arg| match &arg.value {
-            Some(value) => quote! { #value },
-            None => {
-                let name = &arg.name;
-                quote! { #name }
-            }
+        .map(|arg| {
+            let (value, span) = match &arg.value {
+                Some(value) => (quote! { #value }, value.span()),
+                None => {
+                    let name = &arg.name;
+                    (quote! { #name }, name.span())
+                }
+            };
+            // Check the value against the declared type here, rather than at the generated
+            // sender, so a mismatch is reported at the value with the declared type as the
+            // expected type. The generated sender can't name non-protocol types anyway. A match
+            // keeps any temporaries in the value alive for the whole send.
+            let type_ = &arg.type_;
+            quote_spanned! {span=> match #value { v => { let v: #type_ = v; v } } }
         })
         .collect::<Vec<_>>();
 
-    TokenStream::from(quote! {
-        crate::_anchor_config::message_handlers::#sender(#(#args),*)
-    })
+    if !input.chunked {
+        return quote! {
+            crate::_anchor_config::message_handlers::#sender(#(#args),*)
+        };
+    }
+
+    // Evaluate every argument once, in order, then send the data in chunks
+    let (offset, data) = input.chunk_args().unwrap();
+    let locals = (0..args.len())
+        .map(|idx| format_ident!("arg_{}", idx))
+        .collect::<Vec<_>>();
+    let data_local = &locals[input.args.iter().position(|a| a.name == data.name).unwrap()];
+    let send_args = input.args.iter().zip(&locals).map(|(arg, local)| {
+        if arg.name == offset.name {
+            quote! { #local + pos as u32 }
+        } else if arg.name == data.name {
+            quote! { &#local[pos..end] }
+        } else {
+            quote! { #local }
+        }
+    });
+    // Message id, data length prefix, and the other arguments at their largest encoding
+    let overhead = 3
+        + 1
+        + input
+            .args
+            .iter()
+            .filter(|a| a.name != data.name)
+            .map(|a| max_encoded_size(&a.type_))
+            .sum::<usize>();
+    quote! {
+        {
+            #(let #locals = #args;)*
+            const CHUNK: usize = ::anchor::transport::MESSAGE_PAYLOAD_MAX - #overhead;
+            let mut pos = 0usize;
+            loop {
+                let end = (pos + CHUNK).min(#data_local.len());
+                if let Err(e) = crate::_anchor_config::message_handlers::#sender(#(#send_args),*) {
+                    break Err(e);
+                }
+                if end - pos < CHUNK {
+                    break Ok(());
+                }
+                pos = end;
+            }
+        }
+    }
 }
 
 /// Sends a `printf`-style message to the remote end
@@ -73,6 +173,12 @@ pub fn klipper_reply(item: TokenStream) -> TokenStream {
 /// | `%c`          | `u8`      |
 /// | `%.*s`        | `&[u8]`   |
 /// | `%*s`         | `&str`    |
+///
+/// If an `aux_transport` is given to `klipper_config_generate!`, the message can be sent through
+/// it instead of the main transport by prefixing the format string with `[channel = aux]`:
+/// ```
+/// klipper_output!([channel = aux] "Sensor reading %u", value);
+/// ```
 #[proc_macro_error]
 #[proc_macro]
 pub fn klipper_output(item: TokenStream) -> TokenStream {
@@ -109,16 +215,26 @@ pub fn klipper_output(item: TokenStream) -> TokenStream {
 ///
 ///   * `transport = path: type`  
 ///     This is the `TransportOutput` that will be used when sending messages to the remote end.
-///     The user crate must provide this, and it must be a global `const`. Any `klipper_reply!`
-///     calls will call the `output` method. Both a path and type must be provided, both must be
-///     fully expanded. E.g.:  
+///     The user crate must provide this, and it must be a global `const` or `static`. Any
+///     `klipper_reply!` calls will call the `output` method. Both a path and type must be
+///     provided, both must be fully expanded. E.g.:  
 ///     `transport = crate::usb::TRANSPORT_OUTPUT: crate::usb::BufferTransportOutput`  
 ///
+///   * `aux_transport = path: type`  
+///     An optional second `TransportOutput`, e.g. a separate debug UART. Only `klipper_output!`
+///     messages using `[channel = aux]` are sent through it, all commands and replies use the
+///     main `transport`. Unlike `transport`, any path to a global `static` or `const` may be used.
+///
 ///   * `context = type`  
 ///     An optional context can be passed to all `klipper_command` functions. The lifetime `'ctx`
 ///     is available, and allows the context to capture the lifetime when the generated dispatcher
 ///     is called, and pass this along to the handler functions. If no context type is given, the
-///     default is the empty tuple `()`.
+///     default is the empty tuple `()`. With the empty tuple, the generated dispatcher does not
+///     pass a context along at all.
+///
+///   * `name = IDENT`  
+///     The name the generated `Transport` static is exported as. Defaults to
+///     `KLIPPER_TRANSPORT`, set this if the crate already uses that identifier.
 ///
 /// An example invocation could be:
 /// ```
@@ -128,8 +244,27 @@ pub fn klipper_output(item: TokenStream) -> TokenStream {
 /// );
 /// ```
 ///
-/// This generates a module called `_anchor_config`, and exports a `KLIPPER_TRANSPORT` symbol from
-/// it.
+/// This generates a module called `_anchor_config`, and exports a `KLIPPER_TRANSPORT` symbol (or
+/// the `name` given) from it.
+///
+/// When building tests, `_anchor_config::message_decoders` additionally holds a
+/// `decode_<command>` function for each command, returning its decoded arguments as a tuple
+/// without calling the handler:
+/// ```
+/// let args = _anchor_config::message_decoders::decode_finalize_config(&data);
+/// assert_eq!(args.ok(), Some((0x1234,)));
+/// ```
+/// The data must hold exactly the encoded arguments. Commands taking typed slices or
+/// non-protocol types have no decoder.
+///
+/// Tests can also run a command's handler through `_anchor_config::invoke_command`, passing the
+/// command name, its encoded arguments and the context. To inspect the replies, wrap the
+/// `transport` output in an `anchor::CaptureOutput`, held in a `static`, and invoke the command
+/// inside its `capture`, which returns the sent frames:
+/// ```
+/// let (result, frames) =
+///     TRANSPORT_OUTPUT.capture(|| _anchor_config::invoke_command("get_clock", &[], &mut ()));
+/// ```
 #[proc_macro_error]
 #[proc_macro]
 pub fn klipper_config_generate(item: TokenStream) -> TokenStream {
@@ -138,10 +273,13 @@ pub fn klipper_config_generate(item: TokenStream) -> TokenStream {
         abort!("Invalid klipper config: {}", e);
     }
     let target = std::env::var("OUT_DIR").unwrap() + "/_anchor_config.rs";
+    let name = cfg
+        .name
+        .unwrap_or_else(|| format_ident!("KLIPPER_TRANSPORT"));
     TokenStream::from(quote! {
         #[path = #target]
         mod _anchor_config;
-        pub(crate) use _anchor_config::TRANSPORT as KLIPPER_TRANSPORT;
+        pub(crate) use _anchor_config::TRANSPORT as #name;
     })
 }
 
@@ -175,7 +313,7 @@ pub fn klipper_shutdown(item: TokenStream) -> TokenStream {
     let compile_name = info.msg.compile_name();
     let clock = info.clock;
     TokenStream::from(quote! {
-        crate::_anchor_config::message_handlers::send_reply_shutdown(
+        let _ = crate::_anchor_config::message_handlers::send_reply_shutdown(
             #clock,
             crate::_anchor_config::static_strings::#compile_name
         );
@@ -204,7 +342,10 @@ pub fn klipper_shutdown(item: TokenStream) -> TokenStream {
 /// ```
 /// Range(Prefix, start, count)
 /// ```
-/// This will generate `count` items named `Prefix{start+i}`.
+/// This will generate `count` items named `Prefix{start+i}`, numbered consecutively on the wire.
+/// A range must have at least one item.
+/// In the dictionary, the range appears as Klipper expects, keyed by its first name, e.g.
+/// `"PA0": [0, 16]`.
 ///
 /// Variants can be enabled or disabled using standard `#[cfg(feature...)]` feature flags.
 ///
@@ -224,6 +365,24 @@ pub fn klipper_shutdown(item: TokenStream) -> TokenStream {
 /// size>` are generated automatically, along with  implementations of `From<Self> for u{8, 16, 32,
 /// 64, usize}`. The number of bits in these generated functions is determining by the number of
 /// variants. E.g. if the enum has more than 255 variants, the `u8` functions are not generated.
+///
+/// The enum can be used directly as a `klipper_command` argument, and is transferred as `%u`. A
+/// value outside the variants is rejected with `ReadError::InvalidEnumValue`, naming the
+/// enumeration, and the handler is not called:
+/// ```
+/// #[klipper_command]
+/// fn config_button(context: &mut State, oid: u8, pin: Pins) {
+///     ...
+/// }
+/// ```
+/// As the enum is always transferred as `%u`, it fits any command, regardless of the `u8` and
+/// `u16` conversions. Its variants must however be numbered within a `u32`, larger enums are
+/// rejected.
+///
+/// With the `enumeration_tests` feature of `anchor_codegen` enabled, e.g. on the build
+/// dependency, a `#[test]` is generated next to each enum. It converts every variant to each
+/// integer type and back, and checks that the values of variants compiled out by `#[cfg]`, and
+/// the value past the last variant, are rejected.
 #[proc_macro_error]
 #[proc_macro]
 pub fn klipper_enumeration(item: TokenStream) -> TokenStream {
@@ -251,21 +410,139 @@ pub fn klipper_enumeration(item: TokenStream) -> TokenStream {
 /// `context` or `ctx` and **must** be the first argument. It must have a type matching the one
 /// given as the `context` parameter to the `klipper_config_generate` macro.
 ///
-/// The following types are supported: `u8`, `i16`, `u16`, `i32`, `u32`, `bool`, `&[u8]`.
+/// The following types are supported: `u8`, `i16`, `u16`, `i32`, `u32`, `bool`, `char`, `&[u8]`.
+/// A `char` is transferred as a single ASCII byte, commands carrying a larger value are rejected.
+///
+/// Batches of values can be received as typed slices: `&[u16]`, `&[i16]`, `&[u32]` and `&[i32]`.
+/// These are transferred as byte arrays holding the values in little endian byte order, see
+/// `anchor::encoding::SliceElement`. Fixed width 32 bit values, e.g. for bridging binary
+/// sub-protocols, can be received as `Le32` or `Be32`.
+///
+/// Byte arrays and typed slices borrow the received data, which is reused for the next frame as
+/// soon as the handler returns. They can't outlive the call, and thus can't be stored in the
+/// context, even one holding a `'ctx` lifetime. Slice arguments are therefore declared without a
+/// named lifetime. To retain the data, copy it into storage owned by the context:
+/// ```
+/// #[klipper_command]
+/// fn set_name(context: &mut State, name: &[u8]) -> Result<(), Shutdown> {
+///     let target = context.name.get_mut(..name.len()).ok_or_else(|| name_too_long())?;
+///     target.copy_from_slice(name);
+///     context.name_len = name.len();
+///     Ok(())
+/// }
+/// ```
+/// Custom `Readable` types can likewise use `anchor::read_into` to copy a byte array into a
+/// buffer they own.
+///
+/// Commands that are only valid during the configuration phase can be marked with
+/// `#[klipper_command(phase = "config")]`. Once the firmware has called `set_configured(true)` on
+/// `KLIPPER_TRANSPORT`, typically from its `finalize_config` handler, such commands are rejected
+/// without calling the handler. Calling `set_configured(false)`, e.g. from `config_reset`, allows
+/// them again.
+///
+/// Similarly, commands that must not run after a shutdown, e.g. motion commands, can be marked
+/// with `#[klipper_command(shutdown = "reject")]`. These are rejected while the firmware has
+/// called `set_shutdown(true)` on `KLIPPER_TRANSPORT`, typically from `emergency_stop`, until
+/// `set_shutdown(false)` is called from `config_reset`. Both options may be combined, e.g.
+/// `#[klipper_command(phase = "config", shutdown = "reject")]`.
+///
+/// Commands can be grouped into optional feature sets with `#[klipper_command(group = "name")]`.
+/// Such commands are only included if the group is enabled with `ConfigBuilder::enable_group`
+/// in the build script. Otherwise the handler is compiled out, as if it had a
+/// `#[cfg(anchor_group = "name")]` attribute.
+///
+/// Handlers may also be associated functions in an inherent `impl` block, and are then called as
+/// `Type::handler(...)`. A method taking `self` receives the context as `self`, which requires
+/// the context type given to `klipper_config_generate!` to be `&'ctx mut Type`:
+/// ```
+/// impl State {
+///     #[klipper_command]
+///     fn set_fan(&mut self, speed: u8) {
+///         self.fan = speed;
+///     }
+/// }
+/// ```
+/// The type is named relative to the module containing the `impl` block, unless it is written as
+/// a `crate::` path, and must be nameable from the crate root.
+///
+/// The command is named after the handler, unless a name is given with
+/// `#[klipper_command(name = "...")]`. This allows keeping an old variant of a command under a
+/// separate name while hosts migrate to a changed argument list, with both dispatched and listed
+/// in the dictionary:
+/// ```
+/// #[klipper_command]
+/// fn set_heater(context: &mut State, oid: u8, pwm: u16, max_time: u32) {
+///     ...
+/// }
+///
+/// #[klipper_command(name = "set_heater_v1")]
+/// fn set_heater_compat(context: &mut State, oid: u8, pwm: u16) {
+///     set_heater(context, oid, pwm, 0)
+/// }
+/// ```
+///
+/// To extend a command without breaking hosts sending the old arguments, new arguments can be
+/// appended as `Option<T>`. An optional argument is only decoded if the frame has data left, and
+/// is `None` otherwise:
+/// ```
+/// #[klipper_command]
+/// fn set_pwm(context: &mut State, oid: u8, value: u16, ramp: Option<u16>) {
+///     ...
+/// }
+/// ```
+/// Optional arguments must follow all required ones, and can't be typed slices. The dictionary
+/// describes them like required ones, `set_pwm oid=%c value=%hu ramp=%hu`, as it has no notion
+/// of optional arguments, so Klippy always sends them. Only hosts with a hard coded, older
+/// format may leave them out, and must then send the command last in its frame, as commands
+/// following it would be decoded as the missing arguments.
+///
+/// For bridging sub-devices, `#[klipper_command(raw)]` declares a command whose payload is passed
+/// to the handler undecoded. The handler takes a single `&[u8]` argument, after the optional
+/// context, which receives everything following the command id up to the end of the frame:
+/// ```
+/// #[klipper_command(raw)]
+/// fn i2c_bridge(context: &mut State, payload: &[u8]) {
+///     context.bridge.forward(payload);
+/// }
+/// ```
+/// The command is listed in the dictionary without arguments, so Klippy can't send it with a
+/// payload. A cooperating host encodes the command id followed by the payload bytes, and must
+/// send the command last in its frame, as any commands following it are passed to the handler
+/// as part of the payload.
+///
+/// A handler may return `Result<(), Shutdown>` instead of `()`. If it returns an error, a
+/// `shutdown` message is sent to the remote end, just like with `klipper_shutdown!`.
 ///
 /// While Anchor places no restrictions on the number of arguments, be aware that individual
 /// messages in the protocol are limited to 64 bytes of length. For larger sized data, one must
 /// split the data across multiple messages.
 #[proc_macro_error]
 #[proc_macro_attribute]
-pub fn klipper_command(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    item
+pub fn klipper_command(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let opts = parse_macro_input!(attr as CommandAttrs);
+    // The build step leaves errors in the sources to the compiler, so the signature is checked
+    // here, e.g. for raw handlers and the order of optional arguments
+    let func = item.clone();
+    let func = parse_macro_input!(func as ItemFn);
+    if let Err(e) = Command::from_fn(func, &opts) {
+        return e.to_compile_error().into();
+    }
+    match opts.group {
+        Some(group) => {
+            let item = parse_macro_input!(item as ItemFn);
+            TokenStream::from(quote! {
+                #[cfg(anchor_group = #group)]
+                #item
+            })
+        }
+        None => item,
+    }
 }
 
 /// Expose a constant
 ///
 /// Rust constants can be exposed to the remote end by marking them as `#[klipper_constant]`. The
-/// exposed constant must be either a string or an integer number.
+/// exposed constant must be either a string, an integer number, or an array of these.
 ///
 /// ```
 /// #[klipper_constant]
@@ -273,6 +550,9 @@ pub fn klipper_command(_attr: TokenStream, item: TokenStream) -> TokenStream {
 ///
 /// #[klipper_constant]
 /// const MCU: &str = "beacon";
+///
+/// #[klipper_constant]
+/// const BAUD_RATES: [u32; 3] = [9600, 115200, 250000];
 /// ```
 #[proc_macro_error]
 #[proc_macro_attribute]
@@ -283,3 +563,25 @@ pub fn klipper_constant(_attr: TokenStream, item: TokenStream) -> TokenStream {
         #input
     })
 }
+
+/// Encode a newtype as its inner type
+///
+/// Single field tuple structs can derive `KlipperTransparent` to be used directly as
+/// `klipper_command` arguments and `klipper_reply!` fields. The type is read and written exactly
+/// like the wrapped type, and shows up as the wrapped type in the data dictionary.
+///
+/// ```
+/// #[derive(KlipperTransparent)]
+/// struct Oid(u8);
+///
+/// #[klipper_command]
+/// fn config_thing(oid: Oid, pin: u32) {
+///   ...
+/// }
+/// ```
+#[proc_macro_error]
+#[proc_macro_derive(KlipperTransparent)]
+pub fn klipper_transparent(item: TokenStream) -> TokenStream {
+    let transparent = parse_macro_input!(item as Transparent);
+    TokenStream::from(transparent.to_token_stream())
+}
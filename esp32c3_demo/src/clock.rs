@@ -31,44 +31,6 @@ impl Clock {
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct InstantShort(u32);
-
-impl InstantShort {
-    pub fn new(t: u32) -> InstantShort {
-        InstantShort(t)
-    }
-
-    pub fn after(&self, other: impl AsRef<Self>) -> bool {
-        other.as_ref().0.wrapping_sub(self.0) & 0x8000_0000 != 0
-    }
-}
-
-impl core::ops::AddAssign<u32> for InstantShort {
-    fn add_assign(&mut self, rhs: u32) {
-        self.0 = self.0.wrapping_add(rhs);
-    }
-}
-
-impl core::ops::Add<u32> for InstantShort {
-    type Output = Self;
-    fn add(self, rhs: u32) -> Self::Output {
-        InstantShort(self.0.wrapping_add(rhs))
-    }
-}
-
-impl core::convert::AsRef<InstantShort> for InstantShort {
-    fn as_ref(&self) -> &InstantShort {
-        self
-    }
-}
-
-impl From<InstantShort> for u32 {
-    fn from(t: InstantShort) -> Self {
-        t.0
-    }
-}
-
 #[derive(Copy, Clone)]
 pub struct InstantFull(u64);
 
@@ -83,12 +45,8 @@ const CLOCK_FREQ: u32 = 40_000_000;
 
 #[klipper_command]
 pub fn get_uptime(context: &mut crate::State) {
-    let c = context.clock.full().0;
-    klipper_reply!(
-        uptime,
-        high: u32 = (c >> 32) as u32,
-        clock: u32 = (c & 0xFFFF_FFFF) as u32
-    );
+    let c = Clock64::from(u64::from(context.clock.full()));
+    klipper_reply!(uptime, high: u32 = c.high(), clock: u32 = c.low());
 }
 
 #[klipper_command]
@@ -2,7 +2,9 @@ use crate::State;
 use anchor::*;
 
 #[klipper_command]
-pub fn emergency_stop() {}
+pub fn emergency_stop() {
+    crate::KLIPPER_TRANSPORT.set_shutdown(true);
+}
 
 #[klipper_command]
 pub fn get_config(context: &State) {
@@ -11,7 +13,7 @@ pub fn get_config(context: &State) {
         config,
         is_config: bool = crc.is_some(),
         crc: u32 = crc.unwrap_or(0),
-        is_shutdown: bool = false,
+        is_shutdown: bool = crate::KLIPPER_TRANSPORT.is_shutdown(),
         move_count: u16 = 0
     );
 }
@@ -19,12 +21,15 @@ pub fn get_config(context: &State) {
 #[klipper_command]
 pub fn config_reset(context: &mut State) {
     context.config_crc = None;
+    crate::KLIPPER_TRANSPORT.set_configured(false);
+    crate::KLIPPER_TRANSPORT.set_shutdown(false);
 }
 
 #[klipper_command]
 pub fn finalize_config(context: &mut State, crc: u32) {
     context.config_crc = Some(crc);
+    crate::KLIPPER_TRANSPORT.set_configured(true);
 }
 
-#[klipper_command]
+#[klipper_command(phase = "config", shutdown = "reject")]
 pub fn allocate_oids(_count: u8) {}
@@ -63,20 +63,9 @@ impl Esp32c3Device {
             self.state.poll();
 
             self.usb.read_into(&mut self.receive_buffer);
-            let recv_data = self.receive_buffer.data();
-            if !recv_data.is_empty() {
-                let mut wrap = SliceInputBuffer::new(recv_data);
-                KLIPPER_TRANSPORT.receive(&mut wrap, &mut self.state);
-                let consumed = recv_data.len() - wrap.available();
-                if consumed > 0 {
-                    self.receive_buffer.pop(consumed);
-                }
-            }
+            KLIPPER_TRANSPORT.pump_once(&mut self.receive_buffer, &mut self.state);
 
-            critical_section::with(|cs| {
-                let mut txbuf = usb::USB_TX_BUFFER.borrow(cs).borrow_mut();
-                self.usb.write_from(&mut txbuf);
-            });
+            usb::TRANSPORT_OUTPUT.with_buffer(|txbuf| self.usb.write_from(txbuf));
         }
     }
 }
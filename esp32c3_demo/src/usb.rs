@@ -61,18 +61,10 @@ impl Esp32c3UsbDevice {
     }
 }
 
-pub static USB_TX_BUFFER: Mutex<RefCell<FifoBuffer<{ USB_MAX_PACKET_SIZE * 2 }>>> =
-    Mutex::new(RefCell::new(FifoBuffer::new()));
-pub(crate) struct BufferTransportOutput;
+const USB_TX_BUFFER_SIZE: usize = USB_MAX_PACKET_SIZE * 2;
 
-impl TransportOutput for BufferTransportOutput {
-    type Output = ScratchOutput;
-    fn output(&self, f: impl FnOnce(&mut Self::Output)) {
-        let mut scratch = ScratchOutput::new();
-        f(&mut scratch);
-        let output = scratch.result();
-        critical_section::with(|cs| USB_TX_BUFFER.borrow(cs).borrow_mut().extend(output));
-    }
-}
+pub(crate) type BufferTransportOutput =
+    BufferedTransportOutput<Mutex<RefCell<FifoBuffer<USB_TX_BUFFER_SIZE>>>, USB_TX_BUFFER_SIZE>;
 
-pub(crate) const TRANSPORT_OUTPUT: BufferTransportOutput = BufferTransportOutput;
+pub(crate) static TRANSPORT_OUTPUT: BufferTransportOutput =
+    BufferedTransportOutput::new(Mutex::new(RefCell::new(FifoBuffer::new())));
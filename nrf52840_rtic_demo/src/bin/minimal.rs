@@ -171,13 +171,9 @@ mod app {
                     Ok(n) => {
                         defmt::trace!("Klipper protocol RECEIVE, {} bytes", n);
                         rcv_buf.advance(n);
-                        if !rcv_buf.is_empty() {
-                            let mut wrap = SliceInputBuffer::new(rcv_buf.data());
-                            cx.shared.app_state.lock(|app_state| {
-                                crate::KLIPPER_TRANSPORT.receive(&mut wrap, app_state);
-                            });
-                            rcv_buf.pop(rcv_buf.len() - wrap.available());
-                        }
+                        cx.shared.app_state.lock(|app_state| {
+                            crate::KLIPPER_TRANSPORT.pump_once(&mut rcv_buf, app_state);
+                        });
                     }
                     Err(_) => {
                         defmt::error!("Lost USB connection");
@@ -5,7 +5,9 @@ use anchor::*;
 pub fn debug_nop() {}
 
 #[klipper_command]
-pub fn emergency_stop() {}
+pub fn emergency_stop() {
+    crate::KLIPPER_TRANSPORT.set_shutdown(true);
+}
 
 #[klipper_command]
 pub fn get_config(context: &State) {
@@ -14,7 +16,7 @@ pub fn get_config(context: &State) {
         config,
         is_config: bool = crc.is_some(),
         crc: u32 = crc.unwrap_or(0),
-        is_shutdown: bool = false,
+        is_shutdown: bool = crate::KLIPPER_TRANSPORT.is_shutdown(),
         move_count: u16 = 0
     );
 }
@@ -22,12 +24,15 @@ pub fn get_config(context: &State) {
 #[klipper_command]
 pub fn config_reset(context: &mut State) {
     context.config_crc = None;
+    crate::KLIPPER_TRANSPORT.set_configured(false);
+    crate::KLIPPER_TRANSPORT.set_shutdown(false);
 }
 
 #[klipper_command]
 pub fn finalize_config(context: &mut State, crc: u32) {
     context.config_crc = Some(crc);
+    crate::KLIPPER_TRANSPORT.set_configured(true);
 }
 
-#[klipper_command]
+#[klipper_command(phase = "config", shutdown = "reject")]
 pub fn allocate_oids(_count: u8) {}
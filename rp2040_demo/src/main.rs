@@ -13,7 +13,6 @@ use bsp::{
     entry,
     hal::{clocks::init_clocks_and_plls, pac, usb::UsbBus, watchdog::Watchdog},
 };
-use cortex_m::interrupt::free;
 use usb_device::{class_prelude::UsbBusAllocator, prelude::*};
 use usbd_serial::{CdcAcmClass, USB_CLASS_CDC};
 
@@ -81,17 +80,10 @@ fn main() -> ! {
         while let Ok(n) = serial.read_packet(read_buffer.receive_buffer()) {
             read_buffer.advance(n);
         }
-        if !read_buffer.is_empty() {
-            let mut wrap = SliceInputBuffer::new(read_buffer.data());
-            KLIPPER_TRANSPORT.receive(&mut wrap, &mut state);
-            read_buffer.pop(read_buffer.len() - wrap.available());
-        }
+        KLIPPER_TRANSPORT.pump_once(&mut read_buffer, &mut state);
 
         // Write side
-        free(|cs| {
-            let mut txbuf = USB_TX_BUFFER.borrow(cs).borrow_mut();
-            packet_writer.write_packets(&mut serial, &mut txbuf);
-        });
+        TRANSPORT_OUTPUT.with_buffer(|txbuf| packet_writer.write_packets(&mut serial, txbuf));
         bus.poll(&mut [&mut serial]);
     }
 }
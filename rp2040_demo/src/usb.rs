@@ -1,24 +1,14 @@
 use anchor::*;
 use core::cell::RefCell;
-use cortex_m::interrupt::{free, Mutex};
+use critical_section::Mutex;
 use usb_device::UsbError;
 use usbd_serial::CdcAcmClass;
 
-pub static USB_TX_BUFFER: Mutex<RefCell<FifoBuffer<128>>> =
-    Mutex::new(RefCell::new(FifoBuffer::new()));
-pub(crate) struct BufferTransportOutput;
+pub(crate) type BufferTransportOutput =
+    BufferedTransportOutput<Mutex<RefCell<FifoBuffer<128>>>, 128>;
 
-impl TransportOutput for BufferTransportOutput {
-    type Output = ScratchOutput;
-    fn output(&self, f: impl FnOnce(&mut Self::Output)) {
-        let mut scratch = ScratchOutput::new();
-        f(&mut scratch);
-        let output = scratch.result();
-        free(|cs| USB_TX_BUFFER.borrow(cs).borrow_mut().extend(output));
-    }
-}
-
-pub(crate) const TRANSPORT_OUTPUT: BufferTransportOutput = BufferTransportOutput;
+pub(crate) static TRANSPORT_OUTPUT: BufferTransportOutput =
+    BufferedTransportOutput::new(Mutex::new(RefCell::new(FifoBuffer::new())));
 
 #[derive(Default)]
 pub struct UsbPacketWriter {
@@ -3,5 +3,8 @@ fn main() {
         .entry("src/main.rs")
         .set_version("jig")
         .set_build_versions("rust: someversion")
+        .static_string_prefix("MSG_")
+        .inline_handlers(std::env::var_os("CARGO_FEATURE_INLINE_HANDLERS").is_some())
+        .write_id_map(std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("ids.json"))
         .build()
 }
@@ -10,7 +10,10 @@ use std::{
 };
 use tempfile::TempDir;
 
-klipper_config_generate!(transport = crate::TRANSPORT_OUTPUT: crate::BufferTransportOutput);
+klipper_config_generate!(
+    transport = crate::TRANSPORT_OUTPUT: ::anchor::CaptureOutput<crate::BufferTransportOutput>,
+    aux_transport = crate::AUX_OUTPUT: ::anchor::CaptureOutput<crate::DiscardOutput>
+);
 
 struct KlipperInstance {
     _temp_dir: TempDir,
@@ -79,6 +82,10 @@ impl SerialEmulator {
     fn master(&self) -> RawFd {
         self.master
     }
+
+    fn slave(&self) -> RawFd {
+        self.slave
+    }
 }
 
 impl Drop for SerialEmulator {
@@ -110,7 +117,19 @@ impl TransportOutput for BufferTransportOutput {
     }
 }
 
-pub(crate) const TRANSPORT_OUTPUT: BufferTransportOutput = BufferTransportOutput;
+pub(crate) static TRANSPORT_OUTPUT: CaptureOutput<BufferTransportOutput> =
+    CaptureOutput::new(BufferTransportOutput);
+
+/// Stands in for a second UART, the jig has nothing attached to it
+#[derive(Debug, Default)]
+struct DiscardOutput;
+
+impl TransportOutput for DiscardOutput {
+    type Output = ScratchOutput;
+    fn output(&self, _f: impl FnOnce(&mut Self::Output)) {}
+}
+
+pub(crate) static AUX_OUTPUT: CaptureOutput<DiscardOutput> = CaptureOutput::new(DiscardOutput);
 
 fn main() {
     let serial = SerialEmulator::new();
@@ -121,15 +140,32 @@ fn main() {
         match p {
             Err(_) => panic!("Can't map pin {i}"),
             Ok(p) => {
-                if i != p.into() {
+                if i != u8::from(p) {
                     panic!("Can't reverse map pin {i}")
                 }
             }
         }
     }
 
-    let _instance = KlipperInstance::new(format!(
-        r#"
+    if !_anchor_config::CONSTANTS.contains(&("CLOCK_FREQ", "100000000")) {
+        panic!("CLOCK_FREQ missing from CONSTANTS");
+    }
+
+    // The 17 handlers compiled in, plus the built-in `identify`
+    if _anchor_config::NUM_COMMANDS != 18 {
+        panic!("NUM_COMMANDS is {}", _anchor_config::NUM_COMMANDS);
+    }
+    if _anchor_config::COMMAND_ID_LIMIT < _anchor_config::NUM_COMMANDS {
+        panic!("COMMAND_ID_LIMIT is {}", _anchor_config::COMMAND_ID_LIMIT);
+    }
+
+    // With `--mock`, run the handshake against the built in mock instead of a real Klippy
+    let _instance = if env::args().any(|a| a == "--mock") {
+        start_mock(serial.slave());
+        None
+    } else {
+        Some(KlipperInstance::new(format!(
+            r#"
             [mcu]
             serial: {}
 
@@ -138,19 +174,16 @@ fn main() {
             max_velocity: 100
             max_accel: 100
         "#,
-        serial.ttyname().display()
-    ));
+            serial.ttyname().display()
+        )))
+    };
 
     let mut recv = [0u8; 128];
-    let mut rcvbuf: Vec<u8> = Vec::new();
     loop {
         match nix::unistd::read(serial.master(), &mut recv) {
             Err(nix::errno::Errno::EWOULDBLOCK) => {}
             Err(e) => panic!("read failed: {e})"),
-            Ok(n) => {
-                rcvbuf.extend(&recv[..n]);
-                KLIPPER_TRANSPORT.receive(&mut rcvbuf, ());
-            }
+            Ok(n) => KLIPPER_TRANSPORT.feed(&recv[..n], ()),
         };
         if cur_clock() > 10 * CLOCK_FREQ {
             klipper_output!("This the %uth test! %*s?", Pins::PB8.into(), "You alright?");
@@ -159,6 +192,28 @@ fn main() {
     }
 }
 
+/// Runs the mock Klippy in the background, exiting the process with its result
+fn start_mock(fd: RawFd) {
+    std::thread::spawn(move || {
+        let code = match mock_klippy::MockKlippy::new(fd).run() {
+            Ok(()) => {
+                println!("Mock handshake passed");
+                0
+            }
+            Err(e) => {
+                eprintln!("Mock handshake failed: {e}");
+                1
+            }
+        };
+        process::exit(code);
+    });
+    std::thread::spawn(|| {
+        std::thread::sleep(std::time::Duration::from_secs(5));
+        eprintln!("Mock handshake timed out");
+        process::exit(1);
+    });
+}
+
 fn cur_clock() -> u32 {
     use std::time::Instant;
     lazy_static! {
@@ -178,44 +233,487 @@ fn get_clock() {
     klipper_reply!(clock, clock: u32 = cur_clock());
 }
 
+/// Returns the id of the command `name`, from the id map written by the build script
+#[cfg(test)]
+fn command_id(name: &str) -> i64 {
+    let ids: serde_json::Value =
+        serde_json::from_str(include_str!(concat!(env!("OUT_DIR"), "/ids.json"))).unwrap();
+    ids["commands"][name].as_i64().unwrap()
+}
+
+/// Returns the id of the reply `name`, from the id map written by the build script
+#[cfg(test)]
+fn response_id(name: &str) -> i64 {
+    let ids: serde_json::Value =
+        serde_json::from_str(include_str!(concat!(env!("OUT_DIR"), "/ids.json"))).unwrap();
+    ids["responses"][name].as_i64().unwrap()
+}
+
+/// Reads the compressed data dictionary through `identify`, like Klippy does on connect
+#[cfg(test)]
+fn dictionary_data() -> Vec<u8> {
+    use anchor::encoding::{Readable, Writable};
+
+    let mut compressed = Vec::new();
+    loop {
+        let mut args = Vec::new();
+        (compressed.len() as u32).write(&mut args);
+        40u32.write(&mut args);
+        let (result, frames) =
+            TRANSPORT_OUTPUT.capture(|| _anchor_config::invoke_command("identify", &args, &mut ()));
+        assert_eq!(result, Ok(()));
+        let mut payload = &frames[0][2..frames[0].len() - 3];
+        assert_eq!(
+            u32::read(&mut payload).unwrap() as i64,
+            response_id("identify_response")
+        );
+        assert_eq!(u32::read(&mut payload), Ok(compressed.len() as u32));
+        let chunk = <&[u8]>::read(&mut payload).unwrap();
+        compressed.extend_from_slice(chunk);
+        if chunk.len() < 40 {
+            break;
+        }
+    }
+    compressed
+}
+
+/// Reads and decodes the data dictionary
+#[cfg(test)]
+fn dictionary_json() -> serde_json::Value {
+    let compressed = dictionary_data();
+    let mut json = String::new();
+    let mut decoder = flate2::read::ZlibDecoder::new(compressed.as_slice());
+    std::io::Read::read_to_string(&mut decoder, &mut json).unwrap();
+    serde_json::from_str(&json).unwrap()
+}
+
+/// Receives `payload` as a single frame on a fresh transport, returning the frames sent meanwhile
+#[cfg(test)]
+fn receive_frame(payload: &[u8]) -> Vec<Vec<u8>> {
+    let transport = Transport::<_anchor_config::Config>::with_output(&TRANSPORT_OUTPUT);
+    let frame = mock_klippy::encode_frame(0, payload);
+    let ((), frames) = TRANSPORT_OUTPUT.capture(|| {
+        transport.receive(&mut SliceInputBuffer::new(&frame), ());
+    });
+    frames
+}
+
+#[test]
+fn get_clock_replies() {
+    use anchor::encoding::Readable;
+
+    let before = cur_clock();
+    let (result, frames) =
+        TRANSPORT_OUTPUT.capture(|| _anchor_config::invoke_command("get_clock", &[], &mut ()));
+    let after = cur_clock();
+    assert_eq!(result, Ok(()));
+    assert_eq!(frames.len(), 1);
+
+    let frame = &frames[0];
+    assert_eq!(frame[0] as usize, frame.len());
+    assert_eq!(frame[1] & 0xf0, 0x10);
+    assert_eq!(frame[frame.len() - 1], 0x7e);
+    let ids: serde_json::Value =
+        serde_json::from_str(include_str!(concat!(env!("OUT_DIR"), "/ids.json"))).unwrap();
+    let mut payload = &frame[2..frame.len() - 3];
+    let id = u32::read(&mut payload).unwrap();
+    assert_eq!(ids["responses"]["clock"], id);
+    let clock = u32::read(&mut payload).unwrap();
+    assert!((before..=after).contains(&clock));
+    assert!(payload.is_empty());
+}
+
+#[test]
+fn identify_past_end_replies_empty() {
+    use anchor::encoding::{Readable, Writable};
+
+    let len = dictionary_data().len() as u32;
+    for offset in [len, len + 1, 10_000, u32::MAX] {
+        let mut args = Vec::new();
+        offset.write(&mut args);
+        40u32.write(&mut args);
+        let (result, frames) =
+            TRANSPORT_OUTPUT.capture(|| _anchor_config::invoke_command("identify", &args, &mut ()));
+        assert_eq!(result, Ok(()));
+        assert_eq!(frames.len(), 1);
+        let mut payload = &frames[0][2..frames[0].len() - 3];
+        assert_eq!(
+            u32::read(&mut payload).unwrap() as i64,
+            response_id("identify_response")
+        );
+        assert_eq!(u32::read(&mut payload), Ok(offset));
+        assert_eq!(<&[u8]>::read(&mut payload), Ok(&[][..]));
+        assert!(payload.is_empty());
+    }
+}
+
 #[klipper_command]
-fn emergency_stop() {}
+fn drain_tank() {
+    klipper_shutdown!("Tank drained", 77);
+}
+
+#[test]
+fn shutdown_uses_prefixed_static_string() {
+    use anchor::encoding::Readable;
+
+    let (result, frames) =
+        TRANSPORT_OUTPUT.capture(|| _anchor_config::invoke_command("drain_tank", &[], &mut ()));
+    assert_eq!(result, Ok(()));
+    assert_eq!(frames.len(), 1);
+
+    let frame = &frames[0];
+    let ids: serde_json::Value =
+        serde_json::from_str(include_str!(concat!(env!("OUT_DIR"), "/ids.json"))).unwrap();
+    let mut payload = &frame[2..frame.len() - 3];
+    assert_eq!(
+        ids["responses"]["shutdown"],
+        u32::read(&mut payload).unwrap()
+    );
+    assert_eq!(u32::read(&mut payload), Ok(77));
+    let id = u16::read(&mut payload).unwrap();
+    assert!(payload.is_empty());
+
+    // The shutdown refers to the constant named after its id, which documents the string
+    assert_eq!(id, klipper_static_string!("Tank drained"));
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/_anchor_config.rs"));
+    let constant = format!("# [doc = \" `Tank drained`\"] pub const MSG_{id} : u16 = {id}u16 ;");
+    assert!(generated.contains(&constant), "{constant} not generated");
+}
+
+#[klipper_command]
+fn emergency_stop() {
+    CONFIG.lock().unwrap().shutdown();
+    KLIPPER_TRANSPORT.set_shutdown(true);
+}
 
 lazy_static! {
-    static ref CONFIG_CRC: Mutex<Option<u32>> = Mutex::new(None);
+    static ref CONFIG: Mutex<ConfigState> = Mutex::new(ConfigState::new());
+}
+
+#[test]
+fn emergency_stop_shuts_down() {
+    use anchor::encoding::{ReadError, Readable};
+
+    assert_eq!(
+        _anchor_config::invoke_command("emergency_stop", &[], &mut ()),
+        Ok(())
+    );
+    // Commands declared with `shutdown = "reject"` are now refused by the transport
+    let allocate_oids = command_id("allocate_oids") as u16;
+    assert_eq!(
+        KLIPPER_TRANSPORT.check_command(allocate_oids),
+        Err(ReadError::Rejected)
+    );
+    let get_config = command_id("get_config") as u16;
+    assert_eq!(KLIPPER_TRANSPORT.check_command(get_config), Ok(()));
+
+    let (result, frames) =
+        TRANSPORT_OUTPUT.capture(|| _anchor_config::invoke_command("get_config", &[], &mut ()));
+    assert_eq!(result, Ok(()));
+    assert_eq!(frames.len(), 1);
+    let frame = &frames[0];
+    let mut payload = &frame[2..frame.len() - 3];
+    let id = u32::read(&mut payload).unwrap();
+    assert_eq!(id as i64, response_id("config"));
+    let _is_config = bool::read(&mut payload).unwrap();
+    let _crc = u32::read(&mut payload).unwrap();
+    assert_eq!(bool::read(&mut payload), Ok(true), "is_shutdown");
+    assert_eq!(u16::read(&mut payload), Ok(0));
+    assert!(payload.is_empty());
+
+    assert_eq!(
+        _anchor_config::invoke_command("config_reset", &[], &mut ()),
+        Ok(())
+    );
+    assert_eq!(KLIPPER_TRANSPORT.check_command(allocate_oids), Ok(()));
 }
 
 #[klipper_command]
 fn get_config() {
-    let crc = CONFIG_CRC.lock().unwrap();
+    let config = CONFIG.lock().unwrap();
     klipper_reply!(
         config,
-        is_config: bool = crc.is_some(),
-        crc: u32 = crc.unwrap_or(0),
-        is_shutdown: bool = false,
+        is_config: bool = config.is_configured(),
+        crc: u32 = config.crc().unwrap_or(0),
+        is_shutdown: bool = config.is_shutdown(),
         move_count: u16 = 0
     );
 }
 
 #[klipper_command]
-fn config_reset() {
-    *CONFIG_CRC.lock().unwrap() = None;
+fn config_reset() -> Result<(), Shutdown> {
+    CONFIG.lock().unwrap().config_reset().map_err(|_| {
+        Shutdown::new(
+            klipper_static_string!("config_reset only available when shutdown"),
+            cur_clock(),
+        )
+    })?;
+    KLIPPER_TRANSPORT.set_configured(false);
+    KLIPPER_TRANSPORT.set_shutdown(false);
+    Ok(())
 }
 
 #[klipper_command]
-fn finalize_config(crc: u32) {
-    *CONFIG_CRC.lock().unwrap() = Some(crc);
+fn finalize_config(crc: u32) -> Result<(), Shutdown> {
+    CONFIG.lock().unwrap().finalize_config(crc).map_err(|_| {
+        Shutdown::new(
+            klipper_static_string!("Unexpected finalize_config"),
+            cur_clock(),
+        )
+    })?;
+    KLIPPER_TRANSPORT.set_configured(true);
+    Ok(())
 }
 
-#[klipper_command]
-fn allocate_oids(count: u8) {
+#[klipper_command(phase = "config", shutdown = "reject")]
+fn allocate_oids(count: u8) -> Result<(), Shutdown> {
     let _ = count;
+    CONFIG.lock().unwrap().allocate_oids().map_err(|_| {
+        Shutdown::new(
+            klipper_static_string!("oids already allocated"),
+            cur_clock(),
+        )
+    })
 }
 
+/// Data received by `test_array`, copied as the received slice can't be retained
+static TEST_ARRAY: Mutex<[u8; 64]> = Mutex::new([0; 64]);
+
 #[klipper_command]
-fn test_array(buf: &[u8], offset: u16) {
-    let _ = buf;
-    let _ = offset;
+fn test_array(buf: &[u8], offset: u16) -> Result<(), Shutdown> {
+    let mut array = TEST_ARRAY.lock().unwrap();
+    let target = (offset as usize)
+        .checked_add(buf.len())
+        .and_then(|end| array.get_mut(offset as usize..end))
+        .ok_or_else(|| {
+            Shutdown::new(
+                klipper_static_string!("test_array out of bounds"),
+                cur_clock(),
+            )
+        })?;
+    target.copy_from_slice(buf);
+    Ok(())
+}
+
+#[test]
+fn test_array_retains_copy() {
+    use anchor::encoding::Writable;
+
+    let mut data = Vec::new();
+    b"anchor".as_slice().write(&mut data);
+    4u16.write(&mut data);
+    assert_eq!(
+        _anchor_config::invoke_command("test_array", &data, &mut ()),
+        Ok(())
+    );
+    // The received data is gone, the handler kept its own copy
+    data.fill(0);
+    assert_eq!(&TEST_ARRAY.lock().unwrap()[4..10], b"anchor");
+}
+
+/// Payload received by `raw_bridge`
+static RAW_PAYLOAD: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+#[klipper_command(raw)]
+fn raw_bridge(payload: &[u8]) {
+    *RAW_PAYLOAD.lock().unwrap() = payload.to_vec();
+}
+
+#[test]
+fn raw_command_gets_exact_payload() {
+    let mut payload = Vec::new();
+    mock_klippy::encode_vlq(&mut payload, command_id("raw_bridge"));
+    payload.extend_from_slice(&[0x00, 0x7e, 0xff, 0x10]);
+    receive_frame(&payload);
+    assert_eq!(*RAW_PAYLOAD.lock().unwrap(), [0x00, 0x7e, 0xff, 0x10]);
+}
+
+#[test]
+fn raw_command_swallows_following_commands() {
+    let mut payload = Vec::new();
+    mock_klippy::encode_vlq(&mut payload, command_id("raw_bridge"));
+    payload.extend_from_slice(&[1, 2]);
+    let mut get_clock = Vec::new();
+    mock_klippy::encode_vlq(&mut get_clock, command_id("get_clock"));
+    payload.extend_from_slice(&get_clock);
+
+    let frames = receive_frame(&payload);
+    // `get_clock` ends up in the payload, and is not dispatched
+    let mut expected = vec![1, 2];
+    expected.extend_from_slice(&get_clock);
+    assert_eq!(*RAW_PAYLOAD.lock().unwrap(), expected);
+    assert!(frames.iter().all(|f| f.len() == 5), "{:02x?}", frames);
+}
+
+#[klipper_command]
+fn report_value(value: u32) {
+    klipper_output!("Reported %u", value);
+    klipper_output!([channel = aux] "Aux reported %u", value);
+}
+
+#[test]
+fn aux_output_goes_to_aux_transport() {
+    use anchor::encoding::{Readable, Writable};
+
+    let mut args = Vec::new();
+    1234u32.write(&mut args);
+    let ((result, aux), main) = TRANSPORT_OUTPUT.capture(|| {
+        AUX_OUTPUT.capture(|| _anchor_config::invoke_command("report_value", &args, &mut ()))
+    });
+    assert_eq!(result, Ok(()));
+    assert_eq!((main.len(), aux.len()), (1, 1));
+
+    let ids: serde_json::Value =
+        serde_json::from_str(include_str!(concat!(env!("OUT_DIR"), "/ids.json"))).unwrap();
+    for (frame, format) in [(&main[0], "Reported %u"), (&aux[0], "Aux reported %u")] {
+        let mut payload = &frame[2..frame.len() - 3];
+        let id = u16::read(&mut payload).unwrap();
+        assert_eq!(id as i64, ids["output"][format].as_i64().unwrap());
+        assert_eq!(u32::read(&mut payload), Ok(1234));
+        assert!(payload.is_empty());
+    }
+}
+
+/// Fill level set through `Tank::set_level`
+static TANK_LEVEL: Mutex<Option<u16>> = Mutex::new(None);
+
+struct Tank;
+
+impl Tank {
+    #[klipper_command]
+    fn set_level(level: u16) {
+        *TANK_LEVEL.lock().unwrap() = Some(level);
+    }
+}
+
+#[test]
+fn associated_function_command() {
+    let mut payload = Vec::new();
+    mock_klippy::encode_vlq(&mut payload, command_id("set_level"));
+    mock_klippy::encode_vlq(&mut payload, 1500);
+    receive_frame(&payload);
+    assert_eq!(TANK_LEVEL.lock().unwrap().take(), Some(1500));
+}
+
+/// Arguments received by `set_ramp`
+static RAMP: Mutex<Option<(u8, Option<u16>)>> = Mutex::new(None);
+
+#[klipper_command]
+fn set_ramp(oid: u8, ramp: Option<u16>) {
+    *RAMP.lock().unwrap() = Some((oid, ramp));
+}
+
+#[test]
+fn optional_trailing_argument() {
+    let set_ramp = |args: &[i64], next: Option<&str>| {
+        let mut payload = Vec::new();
+        mock_klippy::encode_vlq(&mut payload, command_id("set_ramp"));
+        for &arg in args {
+            mock_klippy::encode_vlq(&mut payload, arg);
+        }
+        if let Some(next) = next {
+            mock_klippy::encode_vlq(&mut payload, command_id(next));
+        }
+        let frames = receive_frame(&payload);
+        (RAMP.lock().unwrap().take(), frames.len())
+    };
+
+    // Present and absent, each sent last in its frame
+    assert_eq!(set_ramp(&[3, 500], None), (Some((3, Some(500))), 1));
+    assert_eq!(set_ramp(&[3], None), (Some((3, None)), 1));
+    // A present argument leaves the next command intact, which replies
+    assert_eq!(
+        set_ramp(&[3, 500], Some("get_clock")),
+        (Some((3, Some(500))), 2)
+    );
+    // An absent one takes the id of the next command as its value, which is not dispatched
+    let get_clock = command_id("get_clock") as u16;
+    assert_eq!(
+        set_ramp(&[3], Some("get_clock")),
+        (Some((3, Some(get_clock))), 1)
+    );
+}
+
+#[test]
+fn chunked_reply_spans_frames() {
+    use anchor::encoding::Readable;
+
+    let samples: Vec<u8> = (0..150).map(|i| i as u8).collect();
+    let ((), frames) = TRANSPORT_OUTPUT.capture(|| {
+        klipper_reply!(samples[chunked], oid: u8 = 7, offset: u32 = 1000, data: &[u8] = &samples)
+    });
+    assert!(frames.len() >= 3, "{} frames", frames.len());
+
+    let mut data = Vec::new();
+    for frame in &frames {
+        let payload = &frame[2..frame.len() - 3];
+        assert_eq!(*frame, mock_klippy::encode_frame(frame[1] & 0xf, payload));
+        let mut payload = payload;
+        assert_eq!(
+            u32::read(&mut payload).unwrap() as i64,
+            response_id("samples")
+        );
+        assert_eq!(u8::read(&mut payload), Ok(7));
+        assert_eq!(u32::read(&mut payload), Ok(1000 + data.len() as u32));
+        data.extend_from_slice(<&[u8]>::read(&mut payload).unwrap());
+        assert!(payload.is_empty());
+    }
+    assert_eq!(data, samples);
+}
+
+#[test]
+fn try_reply_reports_overflow() {
+    use anchor::transport::SendError;
+
+    let (result, frames) =
+        TRANSPORT_OUTPUT.capture(|| try_klipper_reply!(oversized, data: &[u8] = &[0u8; 64]));
+    assert_eq!(result, Err(SendError));
+    assert!(frames.is_empty());
+
+    let (result, frames) =
+        TRANSPORT_OUTPUT.capture(|| try_klipper_reply!(oversized, data: &[u8] = &[0u8; 8]));
+    assert_eq!(result, Ok(()));
+    assert_eq!(frames.len(), 1);
+}
+
+/// An object id, carried exactly like a bare `u8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, KlipperTransparent)]
+struct Oid(u8);
+
+/// Object id and brightness received by `set_brightness`
+static BRIGHTNESS: Mutex<Option<(Oid, u8)>> = Mutex::new(None);
+
+#[klipper_command]
+fn set_brightness(oid: Oid, brightness: u8) {
+    *BRIGHTNESS.lock().unwrap() = Some((oid, brightness));
+    klipper_reply!(brightness_state, oid: Oid = oid, brightness: u8);
+}
+
+#[test]
+fn transparent_argument_matches_inner_type() {
+    use anchor::encoding::{Readable, Writable};
+
+    // Described as the wrapped `u8` in the dictionary
+    let dict = dictionary_json();
+    let id = command_id("set_brightness");
+    assert_eq!(dict["commands"]["set_brightness oid=%c brightness=%c"], id);
+    let id = response_id("brightness_state");
+    assert_eq!(
+        dict["responses"]["brightness_state oid=%c brightness=%c"],
+        id
+    );
+
+    // Read and written as the wrapped `u8`
+    let mut args = Vec::new();
+    200u8.write(&mut args);
+    5u8.write(&mut args);
+    let (result, frames) = TRANSPORT_OUTPUT
+        .capture(|| _anchor_config::invoke_command("set_brightness", &args, &mut ()));
+    assert_eq!(result, Ok(()));
+    assert_eq!(BRIGHTNESS.lock().unwrap().take(), Some((Oid(200), 5)));
+    let mut payload = &frames[0][2..frames[0].len() - 3];
+    assert_eq!(u32::read(&mut payload).unwrap() as i64, id);
+    assert_eq!(payload, args);
 }
 
 #[klipper_command]
@@ -266,12 +764,22 @@ klipper_enumeration! {
     }
 }
 
+#[test]
+fn pin_ranges_use_klipper_dictionary_shape() {
+    let dict = dictionary_json();
+    assert_eq!(
+        dict["enumerations"]["pin"],
+        serde_json::json!({"PA0": [0, 16], "PB0": [16, 16], "ADCTEMPERATURE": 32})
+    );
+}
+
 mod test_embed {
     use anchor::*;
     #[klipper_command]
     pub fn woot() {}
 }
 
+mod mock_klippy;
 mod test;
 
 #[cfg(feature = "skipped_command")]
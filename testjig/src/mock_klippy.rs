@@ -0,0 +1,465 @@
+//! A minimal stand-in for Klippy
+//!
+//! Speaks just enough of the host side of the protocol to run the connection handshake: it
+//! fetches and decodes the data dictionary through `identify`, then uses the dictionary to query
+//! `get_config` and `get_clock`, the latter often enough for the 4-bit frame sequence number to
+//! wrap around a few times. The framing, VLQ and CRC code here is written from the host's
+//! point of view and deliberately shares nothing with Anchor, so it checks Anchor rather than
+//! mirroring it.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Read,
+    os::unix::io::RawFd,
+    time::Duration,
+};
+
+/// Command and reply ids of the identify handshake, fixed by the protocol
+const IDENTIFY_ID: u32 = 1;
+const IDENTIFY_RESPONSE_ID: u32 = 0;
+/// Number of dictionary bytes requested per `identify`, as Klippy does
+const IDENTIFY_CHUNK: usize = 40;
+
+const MESSAGE_MIN: usize = 5;
+const MESSAGE_MAX: usize = 64;
+const MESSAGE_DEST: u8 = 0x10;
+const MESSAGE_SEQ_MASK: u8 = 0x0f;
+const MESSAGE_SYNC: u8 = 0x7e;
+
+type Result<T> = std::result::Result<T, String>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    fn int(&self) -> Result<i64> {
+        match self {
+            Value::Int(v) => Ok(*v),
+            Value::Bytes(_) => Err("expected an integer parameter".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ParamType {
+    Unsigned,
+    Signed,
+    Bytes,
+}
+
+impl ParamType {
+    fn from_spec(spec: &str) -> Result<Self> {
+        match spec {
+            "%u" | "%hu" | "%c" => Ok(ParamType::Unsigned),
+            "%i" | "%hi" => Ok(ParamType::Signed),
+            "%*s" | "%.*s" => Ok(ParamType::Bytes),
+            _ => Err(format!("unknown parameter type '{}'", spec)),
+        }
+    }
+}
+
+/// A message format from the dictionary, e.g. `clock clock=%u`
+#[derive(Debug, Clone)]
+struct MessageFormat {
+    name: String,
+    params: Vec<(String, ParamType)>,
+}
+
+impl MessageFormat {
+    fn parse(format: &str) -> Result<Self> {
+        let mut parts = format.split(' ');
+        let name = parts.next().unwrap_or_default().to_string();
+        let params = parts
+            .map(|p| {
+                let (name, spec) = p
+                    .split_once('=')
+                    .ok_or_else(|| format!("malformed parameter '{}' in '{}'", p, format))?;
+                Ok((name.to_string(), ParamType::from_spec(spec)?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(MessageFormat { name, params })
+    }
+
+    fn decode(&self, data: &mut &[u8]) -> Result<BTreeMap<String, Value>> {
+        let mut values = BTreeMap::new();
+        for (name, type_) in &self.params {
+            let value = match type_ {
+                ParamType::Unsigned => Value::Int(decode_vlq(data)? as u32 as i64),
+                ParamType::Signed => Value::Int(decode_vlq(data)? as i64),
+                ParamType::Bytes => {
+                    let len = decode_vlq(data)? as usize;
+                    if data.len() < len {
+                        return Err(format!("truncated '{}' parameter", name));
+                    }
+                    let (bytes, rest) = data.split_at(len);
+                    *data = rest;
+                    Value::Bytes(bytes.to_vec())
+                }
+            };
+            values.insert(name.clone(), value);
+        }
+        Ok(values)
+    }
+}
+
+/// The parts of a decoded data dictionary the mock uses
+struct Dictionary {
+    json: serde_json::Value,
+    commands: BTreeMap<String, u32>,
+    responses: BTreeMap<u32, MessageFormat>,
+}
+
+impl Dictionary {
+    fn decode(compressed: &[u8]) -> Result<Self> {
+        let mut data = String::new();
+        flate2::read::ZlibDecoder::new(compressed)
+            .read_to_string(&mut data)
+            .map_err(|e| format!("could not decompress dictionary: {}", e))?;
+        let json: serde_json::Value =
+            serde_json::from_str(&data).map_err(|e| format!("invalid dictionary JSON: {}", e))?;
+
+        let ids = |key: &str| -> Result<Vec<(String, u32)>> {
+            json[key]
+                .as_object()
+                .ok_or_else(|| format!("dictionary has no '{}'", key))?
+                .iter()
+                .map(|(format, id)| {
+                    let id = id
+                        .as_u64()
+                        .ok_or_else(|| format!("invalid id for '{}'", format))?;
+                    Ok((format.clone(), id as u32))
+                })
+                .collect()
+        };
+
+        let commands = ids("commands")?.into_iter().collect();
+        let mut responses = BTreeMap::new();
+        for (format, id) in ids("responses")? {
+            responses.insert(id, MessageFormat::parse(&format)?);
+        }
+
+        Ok(Dictionary {
+            json,
+            commands,
+            responses,
+        })
+    }
+
+    /// Looks up a command id by its full format, e.g. `get_clock`
+    fn command_id(&self, format: &str) -> Result<u32> {
+        self.commands
+            .get(format)
+            .copied()
+            .ok_or_else(|| format!("dictionary has no command '{}'", format))
+    }
+
+    /// Checks that the id map written by the build matches the ids the MCU reports
+    fn check_id_map(&self, id_map: &str) -> Result<()> {
+        let map: serde_json::Value =
+            serde_json::from_str(id_map).map_err(|e| format!("invalid id map: {}", e))?;
+        check_eq(&map["version"], &self.json["version"], "id map version")?;
+        for kind in ["commands", "responses"] {
+            let ids = self.json[kind].as_object().unwrap();
+            let mapped = map[kind]
+                .as_object()
+                .ok_or_else(|| format!("id map has no '{}'", kind))?;
+            if ids.len() != mapped.len() {
+                return Err(format!(
+                    "id map has {} {}, expected {}",
+                    mapped.len(),
+                    kind,
+                    ids.len()
+                ));
+            }
+            for (format, id) in ids {
+                let name = format.split(' ').next().unwrap();
+                check_eq(&mapped[name], id, name)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn constant(&self, name: &str) -> &serde_json::Value {
+        &self.json["config"][name]
+    }
+}
+
+pub struct MockKlippy {
+    fd: RawFd,
+    seq: u8,
+    /// Sequence byte of the last frame received from the MCU
+    acked: u8,
+    /// Number of frames sent to the MCU
+    sent: usize,
+    rx: Vec<u8>,
+    messages: VecDeque<(u32, Vec<u8>)>,
+    dictionary: Option<Dictionary>,
+}
+
+impl MockKlippy {
+    /// Creates a mock talking to the MCU through the serial port `fd`
+    pub fn new(fd: RawFd) -> Self {
+        MockKlippy {
+            fd,
+            seq: 0,
+            acked: MESSAGE_DEST,
+            sent: 0,
+            rx: Vec::new(),
+            messages: VecDeque::new(),
+            dictionary: None,
+        }
+    }
+
+    /// Runs the handshake, returning an error describing the first failed check
+    pub fn run(&mut self) -> Result<()> {
+        self.identify()?;
+        let dict = self.dictionary.as_ref().unwrap();
+        check_eq(dict.constant("MCU"), &"anchor_jig".into(), "MCU")?;
+        let clock_freq = dict.constant("CLOCK_FREQ").clone();
+        check_eq(&clock_freq, &crate::CLOCK_FREQ.into(), "CLOCK_FREQ")?;
+        dict.check_id_map(include_str!(concat!(env!("OUT_DIR"), "/ids.json")))?;
+
+        let config = self.query("get_config", &[], "config")?;
+        check_eq(param(&config, "is_config")?, &Value::Int(0), "is_config")?;
+        check_eq(
+            param(&config, "is_shutdown")?,
+            &Value::Int(0),
+            "is_shutdown",
+        )?;
+        check_eq(param(&config, "crc")?, &Value::Int(0), "crc")?;
+
+        let clock = self.query("get_clock", &[], "clock")?;
+        let first = param(&clock, "clock")?.int()?;
+        std::thread::sleep(Duration::from_millis(10));
+        let clock = self.query("get_clock", &[], "clock")?;
+        let second = param(&clock, "clock")?.int()?;
+        if second <= first {
+            return Err(format!("clock did not advance: {} then {}", first, second));
+        }
+
+        // Keep the session going past a few sequence wrap-arounds, every reply checks the ack
+        while self.sent < 3 * (MESSAGE_SEQ_MASK as usize + 1) {
+            self.query("get_clock", &[], "clock")?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the data dictionary in chunks, like Klippy does on connect
+    fn identify(&mut self) -> Result<()> {
+        let mut compressed = Vec::new();
+        loop {
+            let offset = compressed.len() as u32;
+            self.send(IDENTIFY_ID, &[offset, IDENTIFY_CHUNK as u32])?;
+            let msg = self.receive(IDENTIFY_RESPONSE_ID)?;
+            let mut data = msg.as_slice();
+            let reply_offset = decode_vlq(&mut data)? as u32;
+            if reply_offset != offset {
+                return Err(format!(
+                    "identify_response for offset {} while requesting {}",
+                    reply_offset, offset
+                ));
+            }
+            let len = decode_vlq(&mut data)? as usize;
+            let chunk = data.get(..len).ok_or("truncated identify_response")?;
+            compressed.extend_from_slice(chunk);
+            if len < IDENTIFY_CHUNK {
+                break;
+            }
+        }
+        self.dictionary = Some(Dictionary::decode(&compressed)?);
+        Ok(())
+    }
+
+    /// Sends a command and decodes the reply with the given name
+    fn query(
+        &mut self,
+        command: &str,
+        args: &[u32],
+        reply: &str,
+    ) -> Result<BTreeMap<String, Value>> {
+        let dict = self.dictionary.as_ref().ok_or("no dictionary")?;
+        let id = dict.command_id(command)?;
+        let (reply_id, format) = dict
+            .responses
+            .iter()
+            .find(|(_, f)| f.name == reply)
+            .map(|(id, f)| (*id, f.clone()))
+            .ok_or_else(|| format!("dictionary has no reply '{}'", reply))?;
+        self.send(id, args)?;
+        let msg = self.receive(reply_id)?;
+        format.decode(&mut msg.as_slice())
+    }
+
+    /// Sends a single message, with only integer arguments, in its own frame
+    fn send(&mut self, id: u32, args: &[u32]) -> Result<()> {
+        let mut payload = Vec::new();
+        encode_vlq(&mut payload, id as i64);
+        for &arg in args {
+            encode_vlq(&mut payload, arg as i64);
+        }
+
+        let frame = encode_frame(self.seq, &payload);
+        self.seq = (self.seq + 1) & MESSAGE_SEQ_MASK;
+        self.sent += 1;
+
+        let n = nix::unistd::write(self.fd, &frame).map_err(|e| e.to_string())?;
+        if n != frame.len() {
+            return Err("short write".into());
+        }
+        Ok(())
+    }
+
+    /// Waits for a message with id `id`, returning its arguments
+    ///
+    /// Other messages, e.g. output from the MCU, are skipped. As the reply is only sent after the
+    /// MCU received every frame sent so far, it must acknowledge all of them.
+    fn receive(&mut self, id: u32) -> Result<Vec<u8>> {
+        loop {
+            while let Some((msg_id, data)) = self.messages.pop_front() {
+                if msg_id == id {
+                    check_eq(
+                        &self.acked,
+                        &(MESSAGE_DEST | self.seq),
+                        "acknowledged sequence",
+                    )?;
+                    return Ok(data);
+                }
+            }
+            self.read_frames()?;
+        }
+    }
+
+    fn read_frames(&mut self) -> Result<()> {
+        let mut buf = [0u8; 128];
+        match nix::unistd::read(self.fd, &mut buf) {
+            Ok(0) | Err(nix::errno::Errno::EWOULDBLOCK) => {
+                std::thread::sleep(Duration::from_millis(1));
+                return Ok(());
+            }
+            Ok(n) => self.rx.extend_from_slice(&buf[..n]),
+            Err(e) => return Err(format!("read failed: {}", e)),
+        }
+
+        while let Some(&len) = self.rx.first() {
+            let len = len as usize;
+            if !(MESSAGE_MIN..=MESSAGE_MAX).contains(&len) {
+                return Err(format!("invalid frame length {}", len));
+            }
+            if self.rx.len() < len {
+                break;
+            }
+            let frame: Vec<u8> = self.rx.drain(..len).collect();
+            let crc = crc16_ccitt(&frame[..len - 3]).to_be_bytes();
+            if frame[len - 3..len - 1] != crc || frame[len - 1] != MESSAGE_SYNC {
+                return Err(format!("corrupt frame {:02x?}", frame));
+            }
+            if frame[1] & !MESSAGE_SEQ_MASK != MESSAGE_DEST {
+                return Err(format!("invalid sequence byte {:#04x}", frame[1]));
+            }
+            self.acked = frame[1];
+            self.split_messages(&frame[2..len - 3])?;
+        }
+        Ok(())
+    }
+
+    /// Splits a frame payload into its messages, using the dictionary to find their lengths
+    fn split_messages(&mut self, mut payload: &[u8]) -> Result<()> {
+        while !payload.is_empty() {
+            let id = decode_vlq(&mut payload)? as u32;
+            let start = payload;
+            match (&self.dictionary, id) {
+                (_, IDENTIFY_RESPONSE_ID) => {
+                    decode_vlq(&mut payload)?;
+                    let len = decode_vlq(&mut payload)? as usize;
+                    payload = payload.get(len..).ok_or("truncated identify_response")?;
+                }
+                (Some(dict), _) => {
+                    let format = dict
+                        .responses
+                        .get(&id)
+                        .ok_or_else(|| format!("unknown message id {}", id))?;
+                    format.decode(&mut payload)?;
+                }
+                (None, _) => return Err(format!("message {} before the dictionary", id)),
+            }
+            let used = start.len() - payload.len();
+            self.messages.push_back((id, start[..used].to_vec()));
+        }
+        Ok(())
+    }
+}
+
+fn param<'a>(params: &'a BTreeMap<String, Value>, name: &str) -> Result<&'a Value> {
+    params
+        .get(name)
+        .ok_or_else(|| format!("reply has no '{}' parameter", name))
+}
+
+fn check_eq<T: PartialEq + std::fmt::Debug>(actual: &T, expected: &T, what: &str) -> Result<()> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("{} is {:?}, expected {:?}", what, actual, expected))
+    }
+}
+
+/// Wraps `payload` in a frame with sequence number `seq` (0-15)
+pub(crate) fn encode_frame(seq: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![(payload.len() + MESSAGE_MIN) as u8, MESSAGE_DEST | seq];
+    frame.extend_from_slice(payload);
+    let crc = crc16_ccitt(&frame);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    frame.push(MESSAGE_SYNC);
+    frame
+}
+
+/// Encodes an integer the way Klippy's `msgproto` does
+pub(crate) fn encode_vlq(out: &mut Vec<u8>, v: i64) {
+    if !(-0x4000000..0xc000000).contains(&v) {
+        out.push(((v >> 28) & 0x7f) as u8 | 0x80);
+    }
+    if !(-0x80000..0x180000).contains(&v) {
+        out.push(((v >> 21) & 0x7f) as u8 | 0x80);
+    }
+    if !(-0x1000..0x3000).contains(&v) {
+        out.push(((v >> 14) & 0x7f) as u8 | 0x80);
+    }
+    if !(-0x20..0x60).contains(&v) {
+        out.push(((v >> 7) & 0x7f) as u8 | 0x80);
+    }
+    out.push((v & 0x7f) as u8);
+}
+
+/// Decodes an integer the way Klippy's `msgproto` does, returning it sign extended
+fn decode_vlq(data: &mut &[u8]) -> Result<i32> {
+    let mut next = || -> Result<u8> {
+        let (&c, rest) = data.split_first().ok_or("truncated integer")?;
+        *data = rest;
+        Ok(c)
+    };
+    let mut c = next()?;
+    let mut v = (c & 0x7f) as i32;
+    if c & 0x60 == 0x60 {
+        v |= -0x20;
+    }
+    while c & 0x80 != 0 {
+        c = next()?;
+        v = (v << 7) | (c & 0x7f) as i32;
+    }
+    Ok(v)
+}
+
+fn crc16_ccitt(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for &b in buf {
+        let mut data = b ^ (crc & 0xff) as u8;
+        data ^= (data & 0x0f) << 4;
+        let data = data as u16;
+        crc = ((data << 8) | (crc >> 8)) ^ (data >> 4) ^ (data << 3);
+    }
+    crc
+}